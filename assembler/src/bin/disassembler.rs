@@ -0,0 +1,45 @@
+use assembler::disassemble;
+use common::architecture::PROGRAM_MEMORY_SIZE;
+use std::fs;
+use std::process::ExitCode;
+
+const USAGE: &str = "Usage: disassembler input [output]";
+
+/// Disassembles a raw image, like the ones the assembler writes by default, to the output file or stdout.
+fn main() -> ExitCode {
+    let args: Vec<_> = std::env::args().skip(1).collect();
+    let (input, output) = match args.as_slice() {
+        [input] => (input, None),
+        [input, output] => (input, Some(output)),
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    let bytes = match fs::read(input) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            eprintln!("Could not read input file {input}. Cause: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    // Images that are too short are padded with NOPs, like program memory is when nothing is placed there.
+    let Some(mut image) = (bytes.len() <= PROGRAM_MEMORY_SIZE).then_some([0; PROGRAM_MEMORY_SIZE]) else {
+        eprintln!("Input file {input} is {} bytes, but program memory only holds {PROGRAM_MEMORY_SIZE}", bytes.len());
+        return ExitCode::FAILURE;
+    };
+    image[..bytes.len()].copy_from_slice(&bytes);
+
+    let text = disassemble(&image);
+    match output {
+        Some(output) => {
+            if let Err(err) = fs::write(output, text) {
+                eprintln!("Could not write output file {output}. Cause: {err}");
+                return ExitCode::FAILURE;
+            }
+        }
+        None => print!("{text}"),
+    }
+    ExitCode::SUCCESS
+}
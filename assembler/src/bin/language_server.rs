@@ -0,0 +1,14 @@
+use assembler::serve;
+use std::io;
+use std::process::ExitCode;
+
+/// Runs the language server over stdin and stdout, for editors to start.
+fn main() -> ExitCode {
+    match serve(io::stdin().lock(), io::stdout().lock()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Language server stopped. Cause: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
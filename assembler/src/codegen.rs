@@ -0,0 +1,415 @@
+use crate::error::{AssemblyError, AssemblyErrorKind};
+use crate::expression;
+use crate::flow::{self, KnownState};
+use crate::layout::{jump_target, JumpForm, Layout};
+use crate::lexer::{DirectiveKind, InstructionKind, PseudoInstructionKind, Register};
+use crate::location::Location;
+use crate::operands::{self, OperandKind};
+use crate::parser::Node;
+use crate::symbols::{Address, SymbolTable};
+use common::architecture::PROGRAM_MEMORY_SIZE;
+use common::instruction::{encode_instruction, Instruction};
+
+/// Turns a parsed program into a ROM image that can be loaded directly into program memory. Any unused memory is
+/// filled with `NOP`s.
+///
+/// # Arguments
+///
+/// * `program`: The nodes produced by the parser.
+/// * `layout`: Where each node of `program` is placed in program memory.
+/// * `symbols`: The symbol table built from `program`, used to resolve label references.
+pub fn generate<'a>(
+    program: &[Node<'a>],
+    layout: &Layout,
+    symbols: &SymbolTable<'a>,
+) -> Result<[u8; PROGRAM_MEMORY_SIZE], Vec<AssemblyError<'a>>> {
+    let mut image = [encode_instruction(Instruction::NOP); PROGRAM_MEMORY_SIZE];
+    let mut used = [false; PROGRAM_MEMORY_SIZE];
+    let mut errors = vec![];
+    let states = flow::known_states(&flow::node_flows(program, layout, symbols), layout);
+
+    for (i, node) in program.iter().enumerate() {
+        if let Some(guard) = layout.guard(i) {
+            // Running off the last page can't be fixed with a jump.
+            if guard.target >= PROGRAM_MEMORY_SIZE {
+                errors.push(too_large(node.location()));
+                break;
+            }
+
+            let instructions = expand(PseudoInstructionKind::Jmp, guard.form, Address::from_index(guard.target));
+            if let Err(err) = place(&mut image, &mut used, guard.start, &encode(instructions), node.location()) {
+                errors.push(err);
+                break;
+            }
+        }
+
+        let page = layout.page(i);
+        let bytes = match node {
+            Node::Instruction { kind, arguments, location } => {
+                check_branch(*kind, arguments, *location, symbols, page, states[i])
+                    .and_then(|()| to_instruction(*kind, arguments, *location, symbols, page))
+                    .map(|instruction| encode(vec![instruction]))
+            }
+            Node::PseudoInstruction { kind, arguments, location } => match jump_target(arguments, symbols, page) {
+                Some(target) => Ok(encode(expand(*kind, layout.form(i), target))),
+                None => Err(AssemblyError {
+                    location: *location,
+                    kind: AssemblyErrorKind::InvalidPseudoArguments { instruction: *kind },
+                    help: Some(format!("{} takes a single label", kind.name())),
+                }),
+            },
+            // The arguments were checked when laying out the program.
+            Node::Directive { kind: DirectiveKind::Byte, arguments, .. } => {
+                arguments.iter().map(|argument| byte(argument, symbols, page)).collect()
+            }
+            Node::Directive { kind: DirectiveKind::Fill, arguments, .. } => {
+                byte(&arguments[1], symbols, page).map(|value| vec![value; layout.size(i)])
+            }
+            _ => continue,
+        };
+
+        let bytes = match bytes {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                errors.push(err);
+                continue;
+            }
+        };
+
+        match place(&mut image, &mut used, layout.start(i), &bytes, node.location()) {
+            Ok(()) => {}
+            Err(err @ AssemblyError { kind: AssemblyErrorKind::ProgramTooLarge, .. }) => {
+                errors.push(err);
+                break;
+            }
+            Err(err) => errors.push(err),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(image)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Writes encoded instructions or data into the image starting at `start`, making sure they neither run out of program
+/// memory nor overwrite anything placed before.
+fn place<'a>(
+    image: &mut [u8; PROGRAM_MEMORY_SIZE],
+    used: &mut [bool; PROGRAM_MEMORY_SIZE],
+    start: usize,
+    bytes: &[u8],
+    location: Location,
+) -> Result<(), AssemblyError<'a>> {
+    if start + bytes.len() > PROGRAM_MEMORY_SIZE {
+        return Err(too_large(location));
+    }
+
+    if let Some(index) = (start..start + bytes.len()).find(|&index| used[index]) {
+        return Err(AssemblyError {
+            location,
+            kind: AssemblyErrorKind::Overlap { index },
+            help: Some("Check the .page and .org directives placing this code".to_owned()),
+        });
+    }
+
+    image[start..start + bytes.len()].copy_from_slice(bytes);
+    used[start..start + bytes.len()].fill(true);
+    Ok(())
+}
+
+fn encode(instructions: Vec<Instruction>) -> Vec<u8> {
+    instructions.into_iter().map(encode_instruction).collect()
+}
+
+/// Evaluates a byte of data placed with `.byte` or `.fill`.
+fn byte<'a>(node: &Node<'a>, symbols: &SymbolTable<'a>, page: usize) -> Result<u8, AssemblyError<'a>> {
+    let value = expression::evaluate(node, symbols, page)?;
+    Ok(expression::fit(value, 8, node.location())? as u8)
+}
+
+fn too_large<'a>(location: Location) -> AssemblyError<'a> {
+    AssemblyError {
+        location,
+        kind: AssemblyErrorKind::ProgramTooLarge,
+        help: Some(format!("Program memory only holds {PROGRAM_MEMORY_SIZE} instructions")),
+    }
+}
+
+/// Turns a single instruction node into the instruction it stands for.
+///
+/// # Arguments
+///
+/// * `kind`: The instruction.
+/// * `arguments`: The arguments given to the instruction.
+/// * `location`: Where the instruction is in the source, used when reporting errors.
+/// * `symbols`: The symbol table used to resolve label references.
+/// * `page`: The page the instruction is placed on, used to pick between copies of a subroutine.
+pub fn to_instruction<'a>(
+    kind: InstructionKind,
+    arguments: &[Node<'a>],
+    location: Location,
+    symbols: &SymbolTable<'a>,
+    page: usize,
+) -> Result<Instruction, AssemblyError<'a>> {
+    let schema = operands::schema(kind);
+    schema.check(kind, arguments, location)?;
+
+    let register = |i: usize| match &arguments[i] {
+        Node::RegisterLiteral { register, .. } => register_id(*register),
+        _ => unreachable!("Operands are checked against the schema first"),
+    };
+    // Expressions are range-checked against the width of the operand they end up in.
+    let value = |i: usize| -> Result<u8, AssemblyError<'a>> {
+        let operand = schema.operands[i];
+        let value = match &arguments[i] {
+            // A branch can only hold the offset within a page. Getting to the right page is up to the page buffer.
+            Node::LabelReference { name, .. } if operand.kind == OperandKind::Label => {
+                match symbols.resolve(*name, page) {
+                    Some(symbol) => symbol.address.offset as i64,
+                    None => expression::evaluate(&arguments[i], symbols, page)?,
+                }
+            }
+            node => expression::evaluate(node, symbols, page)?,
+        };
+        operand.fit(kind, value, arguments[i].location())
+    };
+
+    // Operands are written in a different order than the fields they go into, like the destination of a `mov`.
+    let mut fields = vec![0; schema.operands.len()];
+    for (i, operand) in schema.operands.iter().enumerate() {
+        fields[operand.field] = match operand.kind {
+            OperandKind::Register => register(i),
+            _ => value(i)?,
+        };
+    }
+
+    Ok(kind.instruction(&fields))
+}
+
+/// Makes sure a `BRN` to a label on another page gets there. The `BRN` only holds the offset, so the page buffer has to
+/// be known to hold the label's page already, or the branch would land on the same offset of some other page.
+fn check_branch<'a>(
+    kind: InstructionKind,
+    arguments: &[Node<'a>],
+    location: Location,
+    symbols: &SymbolTable<'a>,
+    page: usize,
+    state: KnownState,
+) -> Result<(), AssemblyError<'a>> {
+    let (InstructionKind::BRN, [Node::LabelReference { name, .. }]) = (kind, arguments) else {
+        return Ok(());
+    };
+    let Some(symbol) = symbols.resolve(*name, page) else {
+        return Ok(());
+    };
+
+    let target = symbol.address.page;
+    // A subroutine jump ignores the page buffer and stays on the current page.
+    let reaches = state.page_buffer == Some(target) && state.subroutine_jump != Some(true);
+    if target as usize == page || reaches {
+        return Ok(());
+    }
+    Err(AssemblyError {
+        location,
+        kind: AssemblyErrorKind::BranchToOtherPage { name: *name, page: target.into() },
+        help: Some(format!("Use jmp or jmp_if instead, or load the page with lpb {target} first")),
+    })
+}
+
+/// Expands a far jump into the real instructions that make it up.
+///
+/// # Arguments
+///
+/// * `kind`: Which far jump to expand.
+/// * `form`: Which of the optional instructions are needed, as decided during layout.
+/// * `target`: The address to jump to.
+pub fn expand(kind: PseudoInstructionKind, form: JumpForm, target: Address) -> Vec<Instruction> {
+    let mut instructions = Vec::with_capacity(form.len());
+    if form.load_page {
+        instructions.push(Instruction::LPB { immediate: target.page.into() });
+    }
+    if form.set_flag && kind == PseudoInstructionKind::Jmp {
+        instructions.push(Instruction::SSF);
+    }
+    instructions.push(Instruction::BRN { immediate: target.offset.into() });
+    instructions
+}
+
+/// Maps a register to the id the computer uses to select it.
+fn register_id(register: Register) -> u8 {
+    match register {
+        Register::A => 0,
+        Register::X => 1,
+        Register::Y => 2,
+        Register::Z => 3,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::lay_out;
+    use crate::lexer::Lexer;
+    use crate::operands::Operand;
+    use crate::parser::{scope_labels, Parser};
+
+    fn assemble(program: &str) -> Result<[u8; PROGRAM_MEMORY_SIZE], usize> {
+        let mut lexer = Lexer::new(program, 0);
+        let mut parser = Parser::new(lexer.iter());
+        let mut nodes = parser.parse().map_err(|errors| errors.len())?;
+        scope_labels(&mut nodes);
+        let (layout, symbols) = lay_out(&nodes).map_err(|errors| errors.len())?;
+        generate(&nodes, &layout, &symbols).map_err(|errors| errors.len())
+    }
+
+    #[test]
+    fn encodes_instructions_in_order() {
+        let image = assemble("ldi x 0x4\nmov z x\nout 1\nssf\nbrn 5\n").ok().unwrap();
+        assert_eq!(image[0..5], [0b11010100, 0b01000111, 0b01110101, 0b00000011, 0b10000101]);
+    }
+
+    #[test]
+    fn encodes_every_instruction_in_the_definitions() {
+        let image = assemble("not\nshr\nshl\nlpb 3\n").ok().unwrap();
+        assert_eq!(image[0..4], [0b00000100, 0b00000101, 0b00000110, 0b00010011]);
+    }
+
+    #[test]
+    fn pads_with_nops() {
+        let image = assemble("start:\n    ssj\n").ok().unwrap();
+        assert_eq!(image.len(), PROGRAM_MEMORY_SIZE);
+        assert_eq!(image[0], 0b00000001);
+        assert!(image[1..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn branches_to_label_offset() {
+        let image = assemble(".page 1\nnop\nnop\ntarget:\nnop\nbrn target\n").ok().unwrap();
+        assert_eq!(image[67], 0b10000010);
+    }
+
+    #[test]
+    fn scopes_local_labels_to_the_global_label_before_them() {
+        let image = assemble("first:\n.loop: nop\nbrn .loop\nsecond:\nnop\n.loop: brn .loop\n").ok().unwrap();
+        assert_eq!(image[1], 0b10000000);
+        assert_eq!(image[3], 0b10000011);
+        assert_eq!(assemble("a:\n.x: nop\n.x: nop\n").err(), Some(1));
+        assert_eq!(assemble("a:\n.x: nop\nb:\nbrn .x\n").err(), Some(1));
+    }
+
+    #[test]
+    fn resolves_anonymous_labels_in_either_direction() {
+        let image = assemble("1: nop\nbrn 1f\n1: brn 1b\nbrn 1b\n").ok().unwrap();
+        assert_eq!(image[1..4], [0b10000010, 0b10000010, 0b10000010]);
+        assert_eq!(assemble("brn 1b\n").err(), Some(1));
+        assert_eq!(assemble("1: nop\nbrn 1f\n").err(), Some(1));
+    }
+
+    #[test]
+    fn expands_far_jumps() {
+        let image = assemble("jmp far\n.page 1\nnop\nnop\nnop\nfar:\ncmp x\njmp_if far\n").ok().unwrap();
+        // LPB 1, SSF, BRN 3
+        assert_eq!(image[0..3], [0b00010001, 0b00000011, 0b10000011]);
+        // Nothing is known about the code after .page, so the page has to be loaded again.
+        assert_eq!(image[68..70], [0b00010001, 0b10000011]);
+    }
+
+    #[test]
+    fn emits_guards() {
+        let image = assemble(&"inc x\n".repeat(70)).ok().unwrap();
+        // LPB 1, SSF, BRN 0
+        assert_eq!(image[60..65], [0b00101001, 0b00010001, 0b00000011, 0b10000000, 0b00101001]);
+    }
+
+    #[test]
+    fn evaluates_expressions() {
+        let program = ".equ CHAR 0x48\nldi x hi(CHAR)\nldi y lo(CHAR)\nldi z -1\nout PORT - 1\n.equ PORT 2\n";
+        let image = assemble(program).ok().unwrap();
+        assert_eq!(image[0..4], [0b11010100, 0b11101000, 0b11111111, 0b01110101]);
+    }
+
+    #[test]
+    fn evaluates_character_literals() {
+        let image = assemble("ldi x hi('H')
+ldi y lo('i')
+ldi z '\\n'
+ldi a '\\''-0x20
+").ok().unwrap();
+        assert_eq!(image[0..4], [0b11010100, 0b11101001, 0b11111010, 0b11000111]);
+        assert_eq!(assemble("ldi x 'ab'
+"), Err(1));
+    }
+
+    #[test]
+    fn rejects_values_that_do_not_fit() {
+        assert_eq!(assemble("ldi x 17\nout 4\nsep -3\n").err(), Some(3));
+    }
+
+    #[test]
+    fn rejects_overlapping_code() {
+        assert_eq!(assemble("nop\nnop\n.org 1\nnop\n").err(), Some(1));
+        assert_eq!(assemble(".fill 3, 0xFF\n.org 2\n.byte 1\n").err(), Some(1));
+    }
+
+    #[test]
+    fn checks_branches_to_other_pages() {
+        // Nothing is known about the page buffer after `.page`, and the second `brn` loads the wrong page.
+        let program = "brn far\n.page 1\nfar:\nnop\nlpb 0\nbrn near\nlpb 1\nbrn far\n.page 2\nnear:\nnop\n";
+        assert_eq!(assemble(program).err(), Some(2));
+        // A subroutine jump ignores the page buffer.
+        assert_eq!(assemble("lpb 1\nssj\nbrn far\n.page 1\nfar:\nret\n").err(), Some(1));
+
+        let image = assemble("lpb 1\nbrn far\n.page 1\nfar:\nbrn far\n").ok().unwrap();
+        assert_eq!(image[1], 0b10000000);
+    }
+
+    #[test]
+    fn places_data() {
+        let image = assemble("table: .byte 0x12, 'A', -1\n.fill 2, table + 1\nldi x 4\n").ok().unwrap();
+        assert_eq!(image[0..6], [0x12, 0x41, 0xFF, 0x01, 0x01, 0b11010100]);
+        assert_eq!(assemble(".byte 256\n.fill 1\n.byte\n").err(), Some(2));
+    }
+
+    #[test]
+    fn aligns_to_multiples() {
+        let image = assemble("ldi x 4\n.align 4\nfour: .byte four\n.align 64\nlpb 0\nbrn four\n").ok().unwrap();
+        assert_eq!(image[0..5], [0b11010100, 0, 0, 0, 4]);
+        assert_eq!(image[65], 0b10000100);
+        assert_eq!(assemble(".align 3\n").err(), Some(1));
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        assert_eq!(assemble("ldi x\nmov 1 x\nret a\n").err(), Some(3));
+    }
+
+    #[test]
+    fn reports_which_operand_is_wrong() {
+        let mut lexer = Lexer::new("ldi 17\nmov x\nbrn 200\nout x\n", 0);
+        let mut parser = Parser::new(lexer.iter());
+        let nodes = parser.parse().ok().unwrap();
+        let (layout, symbols) = lay_out(&nodes).ok().unwrap();
+        let errors = generate(&nodes, &layout, &symbols).err().unwrap();
+
+        assert!(matches!(
+            errors.iter().map(|error| &error.kind).collect::<Vec<_>>().as_slice(),
+            [
+                AssemblyErrorKind::OperandCount { instruction: InstructionKind::LDI, expected: 2, found: 1 },
+                AssemblyErrorKind::OperandCount { instruction: InstructionKind::MOV, expected: 2, found: 1 },
+                AssemblyErrorKind::OperandOutOfRange { instruction: InstructionKind::BRN, bits: 6, value: 200, .. },
+                AssemblyErrorKind::OperandKind {
+                    instruction: InstructionKind::OUT,
+                    operand: Operand { name: "port", .. },
+                },
+            ]
+        ));
+    }
+
+    #[test]
+    fn rejects_oversized_program() {
+        let program = "nop\n".repeat(PROGRAM_MEMORY_SIZE + 1);
+        assert_eq!(assemble(&program).err(), Some(1));
+    }
+}
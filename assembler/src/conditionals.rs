@@ -0,0 +1,205 @@
+use crate::error::{AssemblyError, AssemblyErrorKind};
+use crate::expression::{self, Constants};
+use crate::lexer::DirectiveKind;
+use crate::location::Location;
+use crate::parser::Node;
+
+/// A `.if` or `.ifdef` that hasn't been closed with `.endif` yet.
+struct Conditional {
+    /// Where the `.if` or `.ifdef` is.
+    location: Location,
+    /// Whether the branch being read is assembled.
+    active: bool,
+    /// Whether one of the branches has been assembled already, so later ones can't be.
+    taken: bool,
+    /// Whether `.else` has been seen, after which only `.endif` can follow.
+    in_else: bool,
+}
+
+/// Keeps only the branches of `.if`, `.ifdef`, `.elif` and `.else` blocks whose conditions hold, and takes the
+/// directives themselves out.
+///
+/// Conditions are worked out before macros are expanded, so they can't use macro parameters. They can only use
+/// constants defined before them, including those given on the command line, since which constants exist depends on
+/// the conditions before. A condition holds if its value isn't zero.
+///
+/// # Arguments
+///
+/// * `program`: The nodes produced by the parser.
+pub fn resolve<'a>(program: &[Node<'a>]) -> Result<Vec<Node<'a>>, Vec<AssemblyError<'a>>> {
+    let mut resolver = Resolver::default();
+    for node in program {
+        resolver.push(node);
+    }
+    resolver.finish()
+}
+
+/// Resolves conditionals one node at a time, so whether what comes next is assembled is known while reading a program,
+/// before the rest of it has been read.
+#[derive(Default)]
+pub struct Resolver<'a> {
+    resolved: Vec<Node<'a>>,
+    errors: Vec<AssemblyError<'a>>,
+    open: Vec<Conditional>,
+}
+
+impl<'a> Resolver<'a> {
+    /// Whether the next node is assembled, since every conditional it is in holds.
+    pub fn active(&self) -> bool {
+        self.open.last().is_none_or(|conditional| conditional.active)
+    }
+
+    /// Takes in the next node of the program, keeping it if it is assembled.
+    pub fn push(&mut self, node: &Node<'a>) {
+        let active = self.active();
+        let (kind, arguments, location) = match node {
+            Node::Directive { kind, arguments, location } if kind.is_conditional() => (*kind, arguments, *location),
+            _ => {
+                if active {
+                    self.resolved.push(node.clone());
+                }
+                return;
+            }
+        };
+        let Resolver { resolved, errors, open } = self;
+        // Whether the branches of the innermost conditional can be assembled at all.
+        let outer = open.len() < 2 || open[open.len() - 2].active;
+
+        // Nothing in a branch that isn't assembled is checked, including its conditions.
+        let condition = |errors: &mut Vec<AssemblyError<'a>>| -> bool {
+            match evaluate(kind, arguments, location, resolved) {
+                Ok(value) => value,
+                Err(mut err) => {
+                    errors.append(&mut err);
+                    false
+                }
+            }
+        };
+
+        match (kind, open.last_mut()) {
+            (DirectiveKind::If | DirectiveKind::IfDef, _) => {
+                let value = active && condition(errors);
+                open.push(Conditional { location, active: value, taken: value, in_else: false });
+            }
+            (DirectiveKind::Elif, Some(conditional)) if !conditional.in_else => {
+                conditional.active = outer && !conditional.taken && condition(errors);
+                conditional.taken |= conditional.active;
+            }
+            (DirectiveKind::Else, Some(conditional)) if !conditional.in_else => {
+                conditional.active = outer && !conditional.taken;
+                conditional.taken = true;
+                conditional.in_else = true;
+            }
+            (DirectiveKind::EndIf, Some(_)) => {
+                open.pop();
+            }
+            (DirectiveKind::Elif | DirectiveKind::Else, Some(_)) => errors.push(AssemblyError {
+                location,
+                kind: AssemblyErrorKind::UnmatchedDirective { directive: kind },
+                help: Some(format!("{} can't come after {}", kind.name(), DirectiveKind::Else.name())),
+            }),
+            _ => errors.push(AssemblyError {
+                location,
+                kind: AssemblyErrorKind::UnmatchedDirective { directive: kind },
+                help: None,
+            }),
+        }
+
+        if let (DirectiveKind::Else | DirectiveKind::EndIf, [argument, ..]) = (kind, arguments.as_slice()) {
+            errors.push(AssemblyError {
+                location: argument.location(),
+                kind: AssemblyErrorKind::InvalidDirectiveArguments { directive: kind },
+                help: Some(format!("{} takes no arguments", kind.name())),
+            });
+        }
+    }
+
+    /// Returns the nodes that are assembled, once the whole program has been taken in.
+    pub fn finish(mut self) -> Result<Vec<Node<'a>>, Vec<AssemblyError<'a>>> {
+        for conditional in self.open {
+            self.errors.push(AssemblyError {
+                location: conditional.location,
+                kind: AssemblyErrorKind::UnmatchedDirective { directive: DirectiveKind::If },
+                help: None,
+            });
+        }
+
+        if self.errors.is_empty() {
+            Ok(self.resolved)
+        } else {
+            Err(self.errors)
+        }
+    }
+}
+
+/// Works out whether the condition of a `.if`, `.ifdef` or `.elif` holds, using the constants defined in `before`.
+fn evaluate<'a>(
+    kind: DirectiveKind,
+    arguments: &[Node<'a>],
+    location: Location,
+    before: &[Node<'a>],
+) -> Result<bool, Vec<AssemblyError<'a>>> {
+    let invalid = |help: String| {
+        vec![AssemblyError {
+            location,
+            kind: AssemblyErrorKind::InvalidDirectiveArguments { directive: kind },
+            help: Some(help),
+        }]
+    };
+
+    let constants = Constants::collect(before)?;
+    match (kind, arguments) {
+        (DirectiveKind::IfDef, [Node::LabelReference { name, .. }]) => Ok(constants.get(*name).is_some()),
+        (DirectiveKind::IfDef, _) => Err(invalid(format!("{} takes the name of a constant", kind.name()))),
+        (_, [condition]) if condition.is_expression() => {
+            expression::evaluate_constant(condition, &constants).map(|value| value != 0).map_err(|mut err| {
+                if let AssemblyErrorKind::NotAConstant { .. } = err.kind {
+                    err.help = Some(format!(
+                        "Conditions can only use constants defined before them, {} checks whether one is",
+                        DirectiveKind::IfDef.name()
+                    ));
+                }
+                vec![err]
+            })
+        }
+        _ => Err(invalid(format!("{} takes a single expression", kind.name()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    /// Returns the lines of the nodes kept, or the codes of the errors.
+    fn resolve_program(program: &str) -> Result<Vec<u32>, Vec<&'static str>> {
+        let mut lexer = Lexer::new(program, 0);
+        let mut parser = Parser::new(lexer.iter());
+        let nodes = parser.parse().ok().unwrap();
+        match resolve(&nodes) {
+            Ok(nodes) => Ok(nodes.iter().map(|node| node.location().line).collect()),
+            Err(errors) => Err(errors.iter().map(|error| error.kind.code()).collect()),
+        }
+    }
+
+    #[test]
+    fn keeps_the_first_branch_that_holds() {
+        let program = ".equ MODE 2\n.if MODE & 1\nnop\n.elif MODE - 2\nnop\n.elif MODE\nnop\n.else\nnop\n.endif\n";
+        assert_eq!(resolve_program(program), Ok(vec![1, 7]));
+        assert_eq!(resolve_program(".if 0\nnop\n.else\nssf\n.endif\n"), Ok(vec![4]));
+    }
+
+    #[test]
+    fn nests_and_checks_definitions() {
+        let program =
+            ".equ SIM 1\n.ifdef SIM\n.ifdef HW\nnop\n.else\nssf\n.endif\n.else\n.if UNDEFINED\n.endif\n.endif\n";
+        assert_eq!(resolve_program(program), Ok(vec![1, 6]));
+    }
+
+    #[test]
+    fn rejects_unmatched_directives() {
+        assert_eq!(resolve_program(".else\n.if 1\n.else\n.elif 1\n"), Err(vec!["E407", "E407", "E407"]));
+        assert_eq!(resolve_program(".if\n.endif 1\n"), Err(vec!["E205", "E205"]));
+    }
+}
@@ -0,0 +1,79 @@
+use crate::expression;
+use crate::layout::Layout;
+use crate::parser::Node;
+use crate::sources::Sources;
+use crate::symbols::SymbolTable;
+use common::architecture::PAGE_SIZE;
+use common::debug_info::{DebugInfo, SourcePosition};
+
+/// Collects the debug information for an assembled program: the source position of every instruction, and every
+/// label and constant.
+///
+/// Code that the assembler adds by itself, like the jump carrying execution over to the next page, has no position.
+///
+/// # Arguments
+///
+/// * `sources`: The source files of the program.
+/// * `program`: The nodes the image was generated from.
+/// * `layout`: Where each node of `program` is placed in program memory.
+/// * `symbols`: The symbol table built from `program`.
+pub fn collect(sources: &Sources, program: &[Node], layout: &Layout, symbols: &SymbolTable) -> DebugInfo {
+    let mut info = DebugInfo {
+        files: sources.files().iter().map(|file| file.path.display().to_string()).collect(),
+        ..DebugInfo::default()
+    };
+
+    for (i, node) in program.iter().enumerate() {
+        let location = node.location();
+        let start = layout.start(i);
+        for index in start..start + layout.size(i) {
+            info.positions[index] =
+                Some(SourcePosition { file: location.file as usize, line: location.line, col: location.col });
+        }
+    }
+
+    info.labels = symbols
+        .labels()
+        .map(|(name, symbol)| {
+            let address = symbol.address;
+            (name.text.to_owned(), address.page as usize * PAGE_SIZE + address.offset as usize)
+        })
+        .collect();
+    info.labels.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+
+    // A constant that can't be evaluated has already been reported if it is used, and is of no use here either way.
+    info.constants = symbols
+        .constants()
+        .iter()
+        .filter_map(|(name, constant)| {
+            let value = expression::evaluate(&constant.value, symbols, 0).ok()?;
+            Some((name.text.to_owned(), value))
+        })
+        .collect();
+    info.constants.sort();
+
+    info
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::lay_out;
+    use std::path::PathBuf;
+
+    #[test]
+    fn maps_addresses_to_their_source() {
+        let program = ".equ FIVE 2 + 3\n.page 1\nstart:\n    ldi x FIVE\n    jmp start\n";
+        let sources = Sources::new(PathBuf::from("test.asm"), program.to_owned());
+        let nodes = sources.parse().ok().unwrap();
+        let (layout, symbols) = lay_out(&nodes).ok().unwrap();
+        let info = collect(&sources, &nodes, &layout, &symbols);
+
+        assert_eq!(info.files, ["test.asm"]);
+        assert_eq!(info.labels, [("start".to_owned(), 64)]);
+        assert_eq!(info.constants, [("FIVE".to_owned(), 5)]);
+        assert_eq!(info.positions[64], Some(SourcePosition { file: 0, line: 4, col: 5 }));
+        assert_eq!(info.describe(66), "start+2 (test.asm:5)");
+        assert_eq!(info.positions[68], None);
+    }
+}
@@ -0,0 +1,358 @@
+use crate::error::{AssemblyError, AssemblyErrorKind};
+use crate::lexer::{DirectiveKind, TokenKind};
+use crate::location::Location;
+use crate::macros::Expansion;
+use crate::operands::OperandKind;
+use crate::parser::{ErrorTokenKind, Name, ParseError, ParseErrorKind};
+use crate::sources::Sources;
+use std::fmt::{Display, Formatter, Write};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem with a program, ready to be shown to the user.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Identifies the kind of problem, like `E301`. Codes never change meaning, so they can be looked up in the README
+    /// or matched on by scripts.
+    pub code: &'static str,
+    pub message: String,
+    /// Where the problem is, if it is anywhere in particular.
+    pub location: Option<Location>,
+    /// How many characters to underline at `location`.
+    pub length: usize,
+    /// The line `location` is on, so the diagnostic can be shown after the sources are gone.
+    pub snippet: Option<Snippet>,
+    pub help: Option<String>,
+    pub notes: Vec<String>,
+}
+
+/// A line of a source file, as shown under a diagnostic.
+#[derive(Clone, Debug)]
+pub struct Snippet {
+    /// The path of the file, as given to the assembler.
+    pub path: String,
+    /// The text of the line, without its line break.
+    pub text: String,
+}
+
+impl Snippet {
+    fn new(sources: &Sources, location: Location) -> Snippet {
+        Snippet { path: sources.file(location.file).path.display().to_string(), text: line(sources, location) }
+    }
+}
+
+impl Diagnostic {
+    /// Creates an error that isn't about any particular place in the program, like a file that can't be read.
+    pub fn error(code: &'static str, message: String) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            code,
+            message,
+            location: None,
+            length: 0,
+            snippet: None,
+            help: None,
+            notes: vec![],
+        }
+    }
+
+    pub fn from_parse_error(error: ParseError, sources: &Sources) -> Diagnostic {
+        let (code, message) = match &error.kind {
+            ParseErrorKind::UnexpectedToken { expected_types } => {
+                let expected = expected_types.iter().map(|e| e.to_string()).collect::<Vec<_>>().join(", ");
+                // Punctuation is already shown quoted, so only words get their text added.
+                let found = match &error.token {
+                    Some(token) if token.kind.to_string().starts_with('\'') => token.kind.to_string(),
+                    Some(token) => format!("{} '{}'", token.kind, token.text),
+                    None => "end of file".to_owned(),
+                };
+                ("E101", format!("Expected one of {expected} but found {found}"))
+            }
+            ParseErrorKind::InvalidNumberLiteral { cause } => {
+                let text = error.token.as_ref().map_or("", |token| token.text);
+                ("E102", format!("Invalid number literal '{text}'. Cause: {cause}"))
+            }
+            ParseErrorKind::UnknownFunction => {
+                let text = error.token.as_ref().map_or("", |token| token.text);
+                ("E103", format!("Unknown function '{text}'"))
+            }
+            ParseErrorKind::InvalidLiteral => {
+                let text = error.token.as_ref().map_or("", |token| token.text);
+                ("E104", format!("Invalid literal {text}"))
+            }
+            ParseErrorKind::ReservedLabelName => {
+                let text = error.token.as_ref().map_or("", |token| token.text);
+                ("E106", format!("Label '{text}' can't be defined, since it refers to an anonymous label"))
+            }
+        };
+
+        Diagnostic {
+            severity: Severity::Error,
+            code,
+            message,
+            location: error.token.as_ref().map(|token| token.location),
+            length: error.token.as_ref().map_or(1, |token| token.text.chars().count().max(1)),
+            snippet: error.token.as_ref().map(|token| Snippet::new(sources, token.location)),
+            help: error.help,
+            notes: vec![],
+        }
+    }
+
+    /// Turns an error found while assembling into a diagnostic, noting every macro invocation it came out of.
+    ///
+    /// # Arguments
+    ///
+    /// * `error`: The error.
+    /// * `sources`: The source files of the program, used to show other locations the error refers to.
+    /// * `expansions`: The macro expansions `Location::expansion` indexes into.
+    pub fn from_assembly_error(error: AssemblyError, sources: &Sources, expansions: &[Expansion]) -> Diagnostic {
+        let severity = if error.kind.is_warning() { Severity::Warning } else { Severity::Error };
+        let code = error.kind.code();
+        let message = match error.kind {
+            AssemblyErrorKind::NonAscii { character } => format!("Character '{character}' is not ASCII"),
+            AssemblyErrorKind::OperandCount { instruction, expected, found } => {
+                format!("{instruction:?} takes {expected} operands but was given {found}")
+            }
+            AssemblyErrorKind::OperandKind { instruction, operand } => match operand.kind {
+                OperandKind::Register => format!("{instruction:?} {} must be a register", operand.name),
+                _ => format!("{instruction:?} {} must be a number or label", operand.name),
+            },
+            AssemblyErrorKind::OperandOutOfRange { instruction, operand, bits, value } => {
+                format!("{instruction:?} {operand} is {bits} bits; {value} does not fit")
+            }
+            AssemblyErrorKind::InvalidPseudoArguments { instruction } => {
+                format!("Invalid arguments for pseudo-instruction {}", instruction.name())
+            }
+            AssemblyErrorKind::InvalidDirectiveArguments { directive } => {
+                format!("Invalid arguments for directive {}", directive.name())
+            }
+            AssemblyErrorKind::JumpInSubroutineMode { instruction } => format!(
+                "{} cannot jump to another page while the subroutine jump flag is set",
+                instruction.name()
+            ),
+            AssemblyErrorKind::BranchToOtherPage { name, page } => {
+                format!("BRN to label '{name}' on page {page}, which the page buffer may not hold")
+            }
+            AssemblyErrorKind::UndefinedLabel { name } => format!("Undefined label '{name}'"),
+            AssemblyErrorKind::DuplicateLabel { name, previous } => {
+                format!("Label '{name}' is already defined at {}", sources.position(previous))
+            }
+            AssemblyErrorKind::LabelOutOfRange { name } => format!("Label '{name}' lies outside of program memory"),
+            AssemblyErrorKind::ProgramTooLarge => "Program does not fit into program memory".to_owned(),
+            AssemblyErrorKind::PageOverflow { page } => {
+                format!("Code runs past the end of page {page} and cannot be moved to the next one")
+            }
+            AssemblyErrorKind::Overlap { index } => {
+                format!("Code overlaps with other code already placed at index {index}")
+            }
+            AssemblyErrorKind::UnmatchedDirective { directive } => {
+                let matching = match directive {
+                    DirectiveKind::EndSub => DirectiveKind::Sub,
+                    DirectiveKind::Macro => DirectiveKind::EndMacro,
+                    DirectiveKind::EndMacro => DirectiveKind::Macro,
+                    DirectiveKind::If | DirectiveKind::IfDef => DirectiveKind::EndIf,
+                    DirectiveKind::Elif | DirectiveKind::Else | DirectiveKind::EndIf => DirectiveKind::If,
+                    _ => DirectiveKind::EndSub,
+                };
+                format!("{} has no matching {}", directive.name(), matching.name())
+            }
+            AssemblyErrorKind::SubroutineDoesNotFit { name, page, chain } => format!(
+                "Subroutine '{name}' does not fit on page {page}, where it is called through {}",
+                join(&chain)
+            ),
+            AssemblyErrorKind::OutOfWorkingMemory { name, size, free } => {
+                format!("Variable '{name}' takes up {size} nibbles, but only {free} are left in working memory")
+            }
+            AssemblyErrorKind::NestedCall { chain } => {
+                format!("Subroutine called while another one is running: {}", join(&chain))
+            }
+            // Only an expression overflowing while it is worked out is too big for 64 bits.
+            AssemblyErrorKind::ValueOutOfRange { bits: 64, .. } => {
+                "Value of the expression does not fit into 64 bits".to_owned()
+            }
+            AssemblyErrorKind::ValueOutOfRange { value, bits } => format!("Value {value} does not fit into {bits} bits"),
+            AssemblyErrorKind::RecursiveConstant { name } => format!("Constant '{name}' depends on itself"),
+            AssemblyErrorKind::NotAConstant { name } => format!("'{name}' is not a constant"),
+            AssemblyErrorKind::UndefinedMacro { name } => format!("Unknown instruction or macro '{name}'"),
+            AssemblyErrorKind::DuplicateMacro { name, previous } => {
+                format!("Macro '{name}' is already defined at {}", sources.position(previous))
+            }
+            AssemblyErrorKind::MacroArguments { name, expected, found } => {
+                format!("Macro '{name}' takes {expected} arguments but was given {found}")
+            }
+            AssemblyErrorKind::RecursiveMacro { chain } => format!("Macro invokes itself: {}", chain.join(" -> ")),
+            AssemblyErrorKind::IncludeNotFound { path, searched } => {
+                format!("Could not find included file '{path}' in any of {}", searched.join(", "))
+            }
+            AssemblyErrorKind::UnreadableInclude { path, cause } => {
+                format!("Could not read included file {path}. Cause: {cause}")
+            }
+            AssemblyErrorKind::IncludeCycle { chain } => format!("File includes itself: {}", chain.join(" -> ")),
+            AssemblyErrorKind::UnusedMacro { name } => format!("Macro '{name}' is never used"),
+            AssemblyErrorKind::UnusedConstant { name } => format!("Constant '{name}' is never used"),
+        };
+
+        let mut notes = vec![];
+        let mut expansion = error.location.expansion;
+        while let Some(Expansion { name, definition, call }) = expansion.map(|i| expansions[i as usize]) {
+            notes.push(format!(
+                "in macro '{name}' defined at {}, invoked at {}",
+                sources.position(definition),
+                sources.position(call)
+            ));
+            expansion = call.expansion;
+        }
+
+        Diagnostic {
+            severity,
+            code,
+            message,
+            location: Some(error.location),
+            length: error.location.length.max(1) as usize,
+            snippet: Some(Snippet::new(sources, error.location)),
+            help: error.help,
+            notes,
+        }
+    }
+
+    /// Renders the diagnostic the way it is shown to the user, with the line it points at and the part it is about
+    /// underlined.
+    ///
+    /// # Arguments
+    ///
+    /// * `color`: Whether to color the output with ANSI escape codes.
+    pub fn render(&self, color: bool) -> String {
+        let paint = |style: &str, text: &str| if color { format!("\x1b[{style}m{text}\x1b[0m") } else { text.to_owned() };
+        let (severity, style) = match self.severity {
+            Severity::Error => ("error", "1;31"),
+            Severity::Warning => ("warning", "1;33"),
+        };
+
+        let mut output = String::new();
+        write!(output, "{}{}", paint(style, &format!("{severity}[{}]", self.code)), paint("1", &format!(": {}", self.message)))
+            .unwrap();
+        writeln!(output).unwrap();
+
+        let snippet = self.location.zip(self.snippet.as_ref());
+        let gutter = snippet.map_or(0, |(location, _)| location.line.to_string().len());
+        let bar = paint("1;34", &format!("{:gutter$} |", ""));
+        if let Some((location, Snippet { path, text })) = snippet {
+            let position = format!("{path}:{}:{}", location.line, location.col);
+            writeln!(output, "{}{position}", paint("1;34", &format!("{:gutter$}--> ", ""))).unwrap();
+            writeln!(output, "{bar}").unwrap();
+            writeln!(output, "{} {text}", paint("1;34", &format!("{} |", location.line))).unwrap();
+
+            // Tabs are kept so the carets line up however wide the terminal shows them.
+            let indent: String =
+                text.chars().take(location.col as usize - 1).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+            writeln!(output, "{bar} {indent}{}", paint(style, &"^".repeat(self.length.max(1)))).unwrap();
+        }
+
+        for (kind, text) in self.help.iter().map(|help| ("help", help)).chain(self.notes.iter().map(|note| ("note", note)))
+        {
+            writeln!(output, "{}{kind}: {text}", paint("1;34", &format!("{:gutter$} = ", ""))).unwrap();
+        }
+
+        output
+    }
+}
+
+/// Gets the line a location is on, without its line break.
+fn line(sources: &Sources, location: Location) -> String {
+    let text = &sources.file(location.file).text;
+    text.lines().nth(location.line as usize - 1).unwrap_or_default().to_owned()
+}
+
+fn join(names: &[Name]) -> String {
+    names.iter().map(|name| name.text).collect::<Vec<_>>().join(" -> ")
+}
+
+impl Display for Name<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+impl Display for TokenKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let repr = match self {
+            TokenKind::Newline => "'\\n'",
+            TokenKind::Colon => "':'",
+            TokenKind::Comma => "','",
+            TokenKind::LabelIdentifier => "label identifier",
+            TokenKind::Instruction { .. } => "instruction",
+            TokenKind::PseudoInstruction { .. } => "pseudo-instruction",
+            TokenKind::Directive { .. } => "directive",
+            TokenKind::NumberLiteral { .. } => "number literal",
+            TokenKind::RegisterLiteral { .. } => "register literal",
+            TokenKind::StringLiteral => "string literal",
+            TokenKind::Plus => "'+'",
+            TokenKind::Minus => "'-'",
+            TokenKind::Ampersand => "'&'",
+            TokenKind::Pipe => "'|'",
+            TokenKind::ShiftLeft => "'<<'",
+            TokenKind::ShiftRight => "'>>'",
+            TokenKind::OpenParen => "'('",
+            TokenKind::CloseParen => "')'",
+        };
+        write!(f, "{}", repr)
+    }
+}
+
+impl Display for ErrorTokenKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let repr = match self {
+            ErrorTokenKind::Newline => "'\\n'",
+            ErrorTokenKind::Colon => "':'",
+            ErrorTokenKind::LabelIdentifier => "label identifier",
+            ErrorTokenKind::Instruction => "instruction",
+            ErrorTokenKind::Directive => "directive",
+            ErrorTokenKind::NumberLiteral => "number literal",
+            ErrorTokenKind::RegisterLiteral => "register literal",
+            ErrorTokenKind::Operator => "operator",
+            ErrorTokenKind::OpenParen => "'('",
+            ErrorTokenKind::CloseParen => "')'",
+        };
+        write!(f, "{}", repr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::lay_out;
+    use std::path::PathBuf;
+
+    fn diagnose(program: &str) -> String {
+        let sources = Sources::new(PathBuf::from("test.asm"), program.to_owned());
+        let nodes = sources.parse().ok().unwrap();
+        let errors = lay_out(&nodes).err().unwrap();
+        let diagnostics: Vec<_> =
+            errors.into_iter().map(|error| Diagnostic::from_assembly_error(error, &sources, &[])).collect();
+        diagnostics.iter().map(|diagnostic| diagnostic.render(false)).collect()
+    }
+
+    #[test]
+    fn underlines_the_offending_word() {
+        let rendered = diagnose("nop\n    brn nowhere ; oops\n");
+        assert_eq!(
+            rendered,
+            "error[E301]: Undefined label 'nowhere'\n --> test.asm:2:9\n  |\n2 |     brn nowhere ; oops\n  |         ^^^^^^^\n"
+        );
+    }
+
+    #[test]
+    fn shows_help_and_color() {
+        let diagnostic = Diagnostic {
+            help: Some("Check the path".to_owned()),
+            ..Diagnostic::error("E604", "Could not read input file a.asm".to_owned())
+        };
+        assert_eq!(diagnostic.render(false), "error[E604]: Could not read input file a.asm\n = help: Check the path\n");
+        assert!(diagnostic.render(true).starts_with("\x1b[1;31merror[E604]\x1b[0m"));
+    }
+}
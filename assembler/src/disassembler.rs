@@ -0,0 +1,218 @@
+use crate::flow::KnownState;
+use crate::lexer::{DirectiveKind, InstructionKind, Register};
+use crate::operands::{self, OperandKind};
+use common::architecture::{NUM_PAGES, PAGE_SIZE, PROGRAM_MEMORY_SIZE};
+use common::instruction::{decode_instruction, encode_instruction, Instruction};
+use std::fmt::Write;
+
+/// How many `NOP`s in a row are skipped over with `.org` instead of being written out.
+const SKIPPED_NOPS: usize = 4;
+
+/// Turns an image of program memory back into a program that assembles into the same image.
+///
+/// Every line shows the page and offset it is placed at, along with its encoding. Branches get a label at their
+/// target, like `L3_12` for offset 12 of page 3, which a branch to another page refers to as `offset(L3_12)`. Where a branch goes depends on the page buffer and the subroutine
+/// jump flag, which are followed from the start of the program the same way the assembler follows them. Code that
+/// can't be reached from there starts with nothing known, so branches in it whose page can't be known keep their
+/// offset as a number. Bytes that aren't an instruction are written with `.byte`, and runs of `NOP`s are skipped.
+///
+/// # Arguments
+///
+/// * `image`: The contents of program memory.
+pub fn disassemble(image: &[u8; PROGRAM_MEMORY_SIZE]) -> String {
+    let instructions: Vec<_> = image.iter().map(|byte| decode_instruction((*byte).into())).collect();
+    let targets = branch_targets(&instructions);
+    let mut labelled = vec![false; PROGRAM_MEMORY_SIZE];
+    for target in targets.iter().flatten() {
+        labelled[*target] = true;
+    }
+    let skipped = skipped_nops(image, &labelled);
+    let label = |index: usize| format!("L{}_{:02}", index / PAGE_SIZE, index % PAGE_SIZE);
+
+    let mut output = String::new();
+    let mut next = None;
+    for (index, (byte, instruction)) in image.iter().zip(&instructions).enumerate() {
+        if skipped[index] {
+            continue;
+        }
+
+        let offset = index % PAGE_SIZE;
+        // Every page is placed explicitly, so code filling one isn't moved to the next.
+        if next != Some(index) || offset == 0 {
+            let directive = if offset == 0 {
+                format!("{} {}", DirectiveKind::Page.name(), index / PAGE_SIZE)
+            } else {
+                format!("{} {index}", DirectiveKind::Org.name())
+            };
+            if !output.is_empty() {
+                output.push('\n');
+            }
+            writeln!(output, "{directive}").unwrap();
+        }
+        if labelled[index] {
+            writeln!(output, "{}:", label(index)).unwrap();
+        }
+
+        let text = if encode_instruction(decode_instruction((*byte).into())) == *byte {
+            // The assembler can't always tell that the page buffer holds another page, so a branch there is given
+            // only the offset of its target.
+            let target = targets[index].map(|target| {
+                if target / PAGE_SIZE == index / PAGE_SIZE { label(target) } else { format!("offset({})", label(target)) }
+            });
+            format_instruction(instruction, target)
+        } else {
+            format!("{} {byte:#04x}", DirectiveKind::Byte.name())
+        };
+        writeln!(output, "    {text:<24}; {}:{offset:02}  {byte:08b}", index / PAGE_SIZE).unwrap();
+        next = Some(index + 1);
+    }
+
+    output
+}
+
+/// Works out which bytes are left out: runs of `NOP`s without a label that are long enough to be worth a `.org`, or
+/// that fill the rest of their page.
+fn skipped_nops(image: &[u8; PROGRAM_MEMORY_SIZE], labelled: &[bool]) -> Vec<bool> {
+    let mut skipped = vec![false; PROGRAM_MEMORY_SIZE];
+    for page in 0..NUM_PAGES {
+        let mut offset = 0;
+        while offset < PAGE_SIZE {
+            let start = page * PAGE_SIZE + offset;
+            let length = (start..start + PAGE_SIZE - offset).take_while(|&i| image[i] == 0 && !labelled[i]).count();
+            if length >= SKIPPED_NOPS || offset + length == PAGE_SIZE {
+                skipped[start..start + length].fill(true);
+            }
+            offset += length.max(1);
+        }
+    }
+    skipped
+}
+
+/// Writes an instruction the way the assembler reads it, with `target` in place of the offset of a branch.
+fn format_instruction(instruction: &Instruction, target: Option<String>) -> String {
+    let (kind, fields) = InstructionKind::split(instruction);
+    let mut text = kind.mnemonic().to_owned();
+    for operand in operands::schema(kind).operands {
+        let value = fields[operand.field];
+        match (operand.kind, &target) {
+            (OperandKind::Register, _) => write!(text, " {}", Register::ALL[value as usize].name()).unwrap(),
+            (OperandKind::Label, Some(target)) => write!(text, " {target}").unwrap(),
+            _ => write!(text, " {value}").unwrap(),
+        }
+    }
+    text
+}
+
+/// Works out where each branch in program memory goes, by following what is known about the page buffer and the
+/// subroutine jump flag through every path. Returns the index each `BRN` jumps to, where it can be known.
+fn branch_targets(instructions: &[Instruction]) -> Vec<Option<usize>> {
+    let mut states: Vec<Option<KnownState>> = vec![None; instructions.len()];
+    let mut targets = vec![None; instructions.len()];
+
+    // Execution starts at index 0. Anything that can't be reached from there starts with nothing known, without
+    // taking away from what is known about the code that can, like when it runs into it by wrapping around a page.
+    let mut reachable = vec![];
+    for start in 0..instructions.len() {
+        if start == 1 {
+            reachable = states.iter().map(Option::is_some).collect();
+        }
+        if states[start].is_some() {
+            continue;
+        }
+        states[start] = Some(if start == 0 { KnownState::reset() } else { KnownState::unknown() });
+
+        let mut pending = vec![start];
+        while let Some(index) = pending.pop() {
+            let state = states[index].unwrap();
+            let (page, offset) = (index / PAGE_SIZE, index % PAGE_SIZE);
+            // The program counter wraps around within the page.
+            let next = page * PAGE_SIZE + (offset + 1) % PAGE_SIZE;
+
+            let mut exits = vec![];
+            match &instructions[index] {
+                Instruction::RET => {}
+                Instruction::BRN { immediate } => {
+                    let immediate: u8 = (*immediate).into();
+                    // A subroutine call stays on the page, while a jump goes to the page in the page buffer.
+                    let target_page = match (state.subroutine_jump, state.page_buffer) {
+                        (Some(true), _) => Some(page),
+                        (Some(false), page_buffer) => page_buffer.map(usize::from),
+                        (None, Some(page_buffer)) if page_buffer as usize == page => Some(page),
+                        (None, _) => None,
+                    };
+                    if state.status_flag != Some(false) {
+                        targets[index] = target_page.map(|page| page * PAGE_SIZE + immediate as usize);
+                        if let Some(target) = targets[index] {
+                            exits.push((target, KnownState { status_flag: Some(true), ..state }));
+                        }
+                    }
+
+                    match state {
+                        // A plain jump that is always taken never comes back.
+                        KnownState { status_flag: Some(true), subroutine_jump: Some(false), .. } => {}
+                        // The subroutine is assumed to leave the subroutine jump flag set, so the calls after it can
+                        // still be followed.
+                        KnownState { subroutine_jump: Some(true), status_flag, .. } if status_flag != Some(false) => {
+                            exits.push((next, KnownState { subroutine_jump: Some(true), ..KnownState::unknown() }))
+                        }
+                        _ => exits.push((next, state.after(&instructions[index]))),
+                    }
+                }
+                instruction => exits.push((next, state.after(instruction))),
+            }
+
+            for (exit, incoming) in exits.into_iter().filter(|(exit, _)| !reachable.get(*exit).unwrap_or(&false)) {
+                let merged = states[exit].map_or(incoming, |current| current.meet(incoming));
+                if states[exit] != Some(merged) {
+                    states[exit] = Some(merged);
+                    pending.push(exit);
+                }
+            }
+        }
+    }
+
+    targets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assemble, Options};
+
+    fn reassemble(text: &str) -> [u8; PROGRAM_MEMORY_SIZE] {
+        match assemble(text, &Options::default()) {
+            Ok(program) => program.image,
+            Err(diagnostics) => panic!("{}\n{text}", diagnostics.iter().map(|d| d.render(false)).collect::<String>()),
+        }
+    }
+
+    #[test]
+    fn labels_branch_targets() {
+        let program = "start:\n    lpb 2\n    ssf\n    brn far\n    mov z x\n.page 2\n    nop\nfar:\n    ssj\n    brn routine\n\
+                       loop:\n    brn loop\nroutine:\n    ret\n    .byte 0b00001001\n";
+        let image = reassemble(program);
+        let text = disassemble(&image);
+
+        assert!(text.starts_with(".page 0\n    lpb 2                   ; 0:00  00010010\n"));
+        assert!(text.contains("    brn offset(L2_01)       ; 0:02  10000001\n"));
+        assert!(text.contains("    mov z x                 ; 0:03  01000111\n"));
+        assert!(text.contains(".page 2\n    nop                     ; 2:00  00000000\nL2_01:\n    ssj"));
+        assert!(text.contains("L2_03:\n    brn L2_03 "));
+        assert!(text.contains("L2_04:\n    ret "));
+        assert!(text.contains("    .byte 0x09 "));
+        assert_eq!(reassemble(&text), image);
+    }
+
+    #[test]
+    fn round_trips_any_image() {
+        // Any byte has to come back as itself, including ones that aren't instructions.
+        let mut image = [0; PROGRAM_MEMORY_SIZE];
+        let mut seed = 12345u32;
+        for (i, byte) in image.iter_mut().enumerate() {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            // Leave gaps so skipping NOPs is covered as well.
+            *byte = if (i / 16) % 3 == 1 { 0 } else { (seed >> 16) as u8 };
+        }
+        assert_eq!(reassemble(&disassemble(&image)), image);
+    }
+}
@@ -0,0 +1,100 @@
+use crate::lexer::{DirectiveKind, InstructionKind, PseudoInstructionKind};
+use crate::location::Location;
+use crate::operands::Operand;
+use crate::parser::Name;
+
+pub enum AssemblyErrorKind<'a> {
+    /// A character the lexer can't read, since it isn't ASCII.
+    NonAscii { character: char },
+    /// An instruction is given the wrong number of operands.
+    OperandCount { instruction: InstructionKind, expected: usize, found: usize },
+    /// An operand is written as the wrong kind of thing, like a number where a register is expected.
+    OperandKind { instruction: InstructionKind, operand: Operand },
+    OperandOutOfRange { instruction: InstructionKind, operand: &'static str, bits: usize, value: i64 },
+    InvalidPseudoArguments { instruction: PseudoInstructionKind },
+    InvalidDirectiveArguments { directive: DirectiveKind },
+    JumpInSubroutineMode { instruction: PseudoInstructionKind },
+    /// A `BRN` to a label on another page, without that page known to be in the page buffer.
+    BranchToOtherPage { name: Name<'a>, page: usize },
+    UndefinedLabel { name: Name<'a> },
+    DuplicateLabel { name: Name<'a>, previous: Location },
+    LabelOutOfRange { name: Name<'a> },
+    ProgramTooLarge,
+    PageOverflow { page: usize },
+    Overlap { index: usize },
+    UnmatchedDirective { directive: DirectiveKind },
+    /// A subroutine could not be copied onto a page it is called from. `chain` lists the calls leading to it, and is
+    /// boxed to keep errors small, since they are returned everywhere.
+    SubroutineDoesNotFit { name: Name<'a>, page: usize, chain: Box<[Name<'a>]> },
+    /// A variable declared with `.var` doesn't fit into what is left of working memory.
+    OutOfWorkingMemory { name: Name<'a>, size: usize, free: usize },
+    /// A subroutine is called while another one is already running.
+    NestedCall { chain: Vec<Name<'a>> },
+    ValueOutOfRange { value: i64, bits: usize },
+    RecursiveConstant { name: Name<'a> },
+    NotAConstant { name: Name<'a> },
+    UndefinedMacro { name: &'a str },
+    DuplicateMacro { name: &'a str, previous: Location },
+    MacroArguments { name: &'a str, expected: usize, found: usize },
+    /// A macro ends up invoking itself. `chain` lists the macros invoked on the way there.
+    RecursiveMacro { chain: Vec<&'a str> },
+    /// None of the places searched for an included file have it.
+    IncludeNotFound { path: String, searched: Vec<String> },
+    UnreadableInclude { path: String, cause: String },
+    /// A file ends up including itself. `chain` lists the files included on the way there.
+    IncludeCycle { chain: Vec<String> },
+    UnusedMacro { name: &'a str },
+    UnusedConstant { name: Name<'a> },
+}
+
+impl AssemblyErrorKind<'_> {
+    /// The code identifying this kind of error. These must never change meaning, since they are documented in the
+    /// README and scripts may depend on them.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AssemblyErrorKind::NonAscii { .. } => "E105",
+            AssemblyErrorKind::OperandCount { .. } => "E201",
+            AssemblyErrorKind::OperandKind { .. } => "E202",
+            AssemblyErrorKind::OperandOutOfRange { .. } => "E203",
+            AssemblyErrorKind::InvalidPseudoArguments { .. } => "E204",
+            AssemblyErrorKind::InvalidDirectiveArguments { .. } => "E205",
+            AssemblyErrorKind::ValueOutOfRange { .. } => "E206",
+            AssemblyErrorKind::UndefinedLabel { .. } => "E301",
+            AssemblyErrorKind::DuplicateLabel { .. } => "E302",
+            AssemblyErrorKind::LabelOutOfRange { .. } => "E303",
+            AssemblyErrorKind::RecursiveConstant { .. } => "E304",
+            AssemblyErrorKind::NotAConstant { .. } => "E305",
+            AssemblyErrorKind::ProgramTooLarge => "E401",
+            AssemblyErrorKind::PageOverflow { .. } => "E402",
+            AssemblyErrorKind::Overlap { .. } => "E403",
+            AssemblyErrorKind::JumpInSubroutineMode { .. } => "E404",
+            AssemblyErrorKind::SubroutineDoesNotFit { .. } => "E405",
+            AssemblyErrorKind::NestedCall { .. } => "E406",
+            AssemblyErrorKind::UnmatchedDirective { .. } => "E407",
+            AssemblyErrorKind::OutOfWorkingMemory { .. } => "E408",
+            AssemblyErrorKind::BranchToOtherPage { .. } => "E409",
+            AssemblyErrorKind::UndefinedMacro { .. } => "E501",
+            AssemblyErrorKind::DuplicateMacro { .. } => "E502",
+            AssemblyErrorKind::MacroArguments { .. } => "E503",
+            AssemblyErrorKind::RecursiveMacro { .. } => "E504",
+            AssemblyErrorKind::IncludeNotFound { .. } => "E601",
+            AssemblyErrorKind::UnreadableInclude { .. } => "E602",
+            AssemblyErrorKind::IncludeCycle { .. } => "E603",
+            AssemblyErrorKind::UnusedMacro { .. } => "W001",
+            AssemblyErrorKind::UnusedConstant { .. } => "W002",
+        }
+    }
+
+    /// Whether this is only a warning, which doesn't stop the program from being assembled.
+    pub fn is_warning(&self) -> bool {
+        matches!(self, AssemblyErrorKind::UnusedMacro { .. } | AssemblyErrorKind::UnusedConstant { .. })
+    }
+}
+
+/// A problem found after parsing, while turning the program into a ROM image. Most of these are errors, but some are
+/// only warnings, as told by `AssemblyErrorKind::is_warning`.
+pub struct AssemblyError<'a> {
+    pub location: Location,
+    pub kind: AssemblyErrorKind<'a>,
+    pub help: Option<String>,
+}
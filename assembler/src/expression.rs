@@ -0,0 +1,302 @@
+use crate::error::{AssemblyError, AssemblyErrorKind};
+use crate::lexer::DirectiveKind;
+use crate::location::Location;
+use crate::parser::{BinaryOperator, Function, Name, Node};
+use crate::symbols::SymbolTable;
+use common::architecture::PAGE_SIZE;
+use std::collections::HashMap;
+
+/// A constant defined with `.equ`.
+#[derive(Clone)]
+pub struct Constant<'a> {
+    pub value: Node<'a>,
+    pub location: Location,
+}
+
+/// Every constant defined in a program. Constants are only evaluated when used, so they can refer to labels and to
+/// constants defined after them.
+#[derive(Clone, Default)]
+pub struct Constants<'a> {
+    constants: HashMap<Name<'a>, Constant<'a>>,
+}
+
+impl<'a> Constants<'a> {
+    /// Collects every `.equ` directive in a program.
+    ///
+    /// # Arguments
+    ///
+    /// * `program`: The nodes produced by the parser.
+    pub fn collect(program: &[Node<'a>]) -> Result<Constants<'a>, Vec<AssemblyError<'a>>> {
+        let mut constants: HashMap<Name<'a>, Constant<'a>> = HashMap::new();
+        let mut errors = vec![];
+
+        for node in program {
+            let Node::Directive { kind: DirectiveKind::Equ, arguments, location } = node else {
+                continue;
+            };
+
+            match arguments.as_slice() {
+                [Node::LabelReference { name, .. }, value] if value.is_expression() => match constants.get(name) {
+                    // Copies of a subroutine repeat the constants defined inside it.
+                    Some(previous) if previous.location == *location => {}
+                    Some(previous) => errors.push(AssemblyError {
+                        location: *location,
+                        kind: AssemblyErrorKind::DuplicateLabel { name: *name, previous: previous.location },
+                        help: None,
+                    }),
+                    None => {
+                        constants.insert(*name, Constant { value: value.clone(), location: *location });
+                    }
+                },
+                _ => errors.push(AssemblyError {
+                    location: *location,
+                    kind: AssemblyErrorKind::InvalidDirectiveArguments { directive: DirectiveKind::Equ },
+                    help: Some(format!("{} takes a name followed by its value", DirectiveKind::Equ.name())),
+                }),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(Constants { constants })
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn get(&self, name: Name<'a>) -> Option<&Constant<'a>> {
+        self.constants.get(&name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Name<'a>, &Constant<'a>)> {
+        self.constants.iter().map(|(name, constant)| (*name, constant))
+    }
+}
+
+/// Evaluates an expression once the program has been laid out, so labels can be used. A label's value is its index
+/// into program memory.
+///
+/// # Arguments
+///
+/// * `node`: The expression.
+/// * `symbols`: The symbol table, holding both labels and constants.
+/// * `page`: The page the expression is used on, used to pick between copies of a subroutine.
+pub fn evaluate<'a>(node: &Node<'a>, symbols: &SymbolTable<'a>, page: usize) -> Result<i64, AssemblyError<'a>> {
+    Evaluator { constants: symbols.constants(), symbols: Some(symbols), page, stack: vec![] }.evaluate(node)
+}
+
+/// Evaluates an expression before the program has been laid out, so only constants can be used.
+pub fn evaluate_constant<'a>(node: &Node<'a>, constants: &Constants<'a>) -> Result<i64, AssemblyError<'a>> {
+    Evaluator { constants, symbols: None, page: 0, stack: vec![] }.evaluate(node)
+}
+
+/// Checks that a value fits into an operand `bits` wide and returns it as the operand's bits. Negative values are
+/// stored in two's complement, so anything from -2^(bits - 1) up to 2^bits - 1 fits.
+pub fn fit<'a>(value: i64, bits: usize, location: Location) -> Result<u16, AssemblyError<'a>> {
+    let min = -(1 << (bits - 1));
+    let max = (1 << bits) - 1;
+    if value < min || value > max {
+        return Err(AssemblyError {
+            location,
+            kind: AssemblyErrorKind::ValueOutOfRange { value, bits },
+            help: Some(format!("Values from {min} to {max} fit into {bits} bits")),
+        });
+    }
+
+    Ok((value & max) as u16)
+}
+
+struct Evaluator<'s, 'a> {
+    constants: &'s Constants<'a>,
+    symbols: Option<&'s SymbolTable<'a>>,
+    page: usize,
+    /// The constants currently being evaluated, used to catch constants that depend on themselves.
+    stack: Vec<Name<'a>>,
+}
+
+impl<'a> Evaluator<'_, 'a> {
+    fn evaluate(&mut self, node: &Node<'a>) -> Result<i64, AssemblyError<'a>> {
+        match node {
+            Node::NumberLiteral { value, .. } => Ok(*value as i64),
+            Node::LabelReference { name, location } => self.name(*name, *location),
+            Node::Negation { operand, location } => {
+                let operand = self.evaluate(operand)?;
+                operand.checked_neg().ok_or_else(|| overflow(operand.wrapping_neg(), *location))
+            }
+            Node::BinaryOperation { operator, left, right, location } => {
+                let left = self.evaluate(left)?;
+                let right_location = right.location();
+                let right = self.evaluate(right)?;
+
+                let value = match operator {
+                    BinaryOperator::Add => left.checked_add(right).ok_or_else(|| left.wrapping_add(right)),
+                    BinaryOperator::Subtract => left.checked_sub(right).ok_or_else(|| left.wrapping_sub(right)),
+                    BinaryOperator::And => Ok(left & right),
+                    BinaryOperator::Or => Ok(left | right),
+                    BinaryOperator::ShiftLeft => {
+                        let shifted = left << shift_amount(right, right_location)?;
+                        // Shifting back gives something else if bits were shifted out, or into the sign bit.
+                        if shifted >> right == left { Ok(shifted) } else { Err(shifted) }
+                    }
+                    BinaryOperator::ShiftRight => Ok(left >> shift_amount(right, right_location)?),
+                };
+                value.map_err(|wrapped| overflow(wrapped, *location))
+            }
+            Node::FunctionCall { function, argument, .. } => {
+                let argument = self.evaluate(argument)?;
+                let value = match function {
+                    Function::Page => argument / PAGE_SIZE as i64,
+                    Function::Offset => argument % PAGE_SIZE as i64,
+                    Function::Hi => (argument >> 4) & 0xF,
+                    Function::Lo => argument & 0xF,
+                };
+                Ok(value)
+            }
+            other => panic!("Node at {:?} is not an expression", other.location()),
+        }
+    }
+
+    fn name(&mut self, name: Name<'a>, location: Location) -> Result<i64, AssemblyError<'a>> {
+        if let Some(constant) = self.constants.get(name) {
+            if self.stack.contains(&name) {
+                return Err(AssemblyError {
+                    location: constant.location,
+                    kind: AssemblyErrorKind::RecursiveConstant { name },
+                    help: None,
+                });
+            }
+
+            self.stack.push(name);
+            let value = self.evaluate(&constant.value);
+            self.stack.pop();
+            return value;
+        }
+
+        let Some(symbols) = self.symbols else {
+            return Err(AssemblyError {
+                location,
+                kind: AssemblyErrorKind::NotAConstant { name },
+                help: Some("Labels only get their addresses during layout, so they can't be used here".to_owned()),
+            });
+        };
+
+        match symbols.resolve(name, self.page) {
+            Some(symbol) => Ok(symbol.address.page as i64 * PAGE_SIZE as i64 + symbol.address.offset as i64),
+            None => Err(AssemblyError {
+                location,
+                kind: AssemblyErrorKind::UndefinedLabel { name },
+                help: None,
+            }),
+        }
+    }
+}
+
+/// Reports an expression whose value doesn't fit into the 64 bits it is worked out with. `wrapped` is what it would
+/// have wrapped around to, and isn't shown, since it would only be confusing.
+fn overflow<'a>(wrapped: i64, location: Location) -> AssemblyError<'a> {
+    AssemblyError {
+        location,
+        kind: AssemblyErrorKind::ValueOutOfRange { value: wrapped, bits: 64 },
+        help: Some(format!("Expressions are worked out with values from {} to {}", i64::MIN, i64::MAX)),
+    }
+}
+
+/// Checks that a shift moves by a sensible amount. Anything from 0 to 63 is allowed.
+fn shift_amount<'a>(amount: i64, location: Location) -> Result<i64, AssemblyError<'a>> {
+    if (0..64).contains(&amount) {
+        Ok(amount)
+    } else {
+        Err(AssemblyError {
+            location,
+            kind: AssemblyErrorKind::ValueOutOfRange { value: amount, bits: 6 },
+            help: Some("Shifts move by 0 to 63 bits".to_owned()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::lay_out;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    /// Lays out a program and evaluates the argument of its last node.
+    fn value_of(program: &str) -> Result<i64, AssemblyErrorKind<'_>> {
+        let mut lexer = Lexer::new(program, 0);
+        let mut parser = Parser::new(lexer.iter());
+        let nodes = parser.parse().ok().unwrap();
+        let (layout, symbols) = lay_out(&nodes).map_err(|mut errors| errors.remove(0).kind)?;
+
+        let last = nodes.len() - 1;
+        let (Node::Instruction { arguments, .. } | Node::Directive { arguments, .. }) = &nodes[last] else {
+            panic!("Last node has no arguments");
+        };
+        evaluate(arguments.last().unwrap(), &symbols, layout.page(last)).map_err(|error| error.kind)
+    }
+
+    #[test]
+    fn operators_follow_c_precedence() {
+        assert_eq!(value_of("out 1 + 2 << 1 | 1 & 0\n").ok(), Some(6));
+        assert_eq!(value_of("out 1 + (2 << 1)\n").ok(), Some(5));
+        assert_eq!(value_of("out 7 - 2 - 1\n").ok(), Some(4));
+        assert_eq!(value_of("out -0x3 + 0b101\n").ok(), Some(2));
+    }
+
+    #[test]
+    fn functions_split_values() {
+        assert_eq!(value_of("out hi(0x48)\n").ok(), Some(4));
+        assert_eq!(value_of("out lo(0x48)\n").ok(), Some(8));
+        assert_eq!(value_of(".org 70\nend:\nbrn page(end)\n").ok(), Some(1));
+        assert_eq!(value_of(".org 70\nend:\nbrn offset(end)\n").ok(), Some(6));
+    }
+
+    #[test]
+    fn constants_can_be_used_before_definition() {
+        assert_eq!(value_of("out LAST - 1\n.equ LAST COUNT + 1\n.equ COUNT 3\n").ok(), Some(3));
+    }
+
+    #[test]
+    fn constants_can_start_with_a_minus_or_parenthesis() {
+        assert_eq!(value_of(".equ K -1\nout K + 3\n").ok(), Some(2));
+        assert_eq!(value_of(".equ K (1 + 2) << 1\nout K\n").ok(), Some(6));
+    }
+
+    #[test]
+    fn constants_can_refer_to_labels() {
+        assert_eq!(value_of(".org 130\nend:\nnop\n.equ END_PAGE page(end)\nout END_PAGE\n").ok(), Some(2));
+    }
+
+    #[test]
+    fn recursive_constants() {
+        let result = value_of(".equ A B\n.equ B A + 1\nout A\n");
+        assert!(matches!(result, Err(AssemblyErrorKind::RecursiveConstant { .. })));
+    }
+
+    #[test]
+    fn placement_needs_constants() {
+        assert!(matches!(value_of("start:\n.org start\n"), Err(AssemblyErrorKind::NotAConstant { name: Name { text: "start", .. } })));
+    }
+
+    #[test]
+    fn overflow_is_reported() {
+        let overflows =
+            |program| matches!(value_of(program), Err(AssemblyErrorKind::ValueOutOfRange { bits: 64, .. }));
+        assert!(overflows(".equ A 1 << 62\n.equ B A + A\nldi x B\n"));
+        assert!(overflows("out 0 - (1 << 62) - (1 << 62) - 1\n"));
+        assert!(overflows("out 3 << 62\n"));
+        assert!(overflows("out -(0 - (1 << 62) - (1 << 62))\n"));
+        assert_eq!(value_of("out (1 << 62) - 1 + (1 << 62)\n").ok(), Some(i64::MAX));
+        assert_eq!(value_of("out -1 << 63\n").ok(), Some(i64::MIN));
+    }
+
+    #[test]
+    fn values_are_range_checked() {
+        let location = Location::start(0);
+        assert_eq!(fit(15, 4, location).ok(), Some(15));
+        assert_eq!(fit(-1, 4, location).ok(), Some(0b1111));
+        assert_eq!(fit(-8, 4, location).ok(), Some(0b1000));
+        let error = fit(17, 4, location).err().unwrap();
+        assert!(matches!(error.kind, AssemblyErrorKind::ValueOutOfRange { value: 17, bits: 4 }));
+        assert!(fit(-9, 4, location).is_err());
+    }
+}
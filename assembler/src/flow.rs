@@ -0,0 +1,334 @@
+use crate::codegen;
+use crate::layout::{jump_target, Layout};
+use crate::lexer::{DirectiveKind, InstructionKind, PseudoInstructionKind};
+use crate::parser::Node;
+use crate::symbols::SymbolTable;
+use common::instruction::Instruction;
+use common::architecture::PAGE_SIZE;
+
+/// What the assembler knows about the computer's flags and page buffer at some point in the program. `None` means the
+/// value could be anything.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct KnownState {
+    pub status_flag: Option<bool>,
+    pub page_buffer: Option<u8>,
+    pub subroutine_jump: Option<bool>,
+}
+
+impl KnownState {
+    /// The state of the computer when it is powered on.
+    pub fn reset() -> KnownState {
+        KnownState {
+            status_flag: Some(false),
+            page_buffer: Some(0),
+            subroutine_jump: Some(false),
+        }
+    }
+
+    /// A state where nothing is known, such as at a label that could be jumped to from anywhere.
+    pub fn unknown() -> KnownState {
+        KnownState {
+            status_flag: None,
+            page_buffer: None,
+            subroutine_jump: None,
+        }
+    }
+
+    /// Combines the states of two paths leading to the same point, keeping only what both agree on.
+    pub fn meet(self, other: KnownState) -> KnownState {
+        fn agree<T: Eq>(a: Option<T>, b: Option<T>) -> Option<T> {
+            if a == b { a } else { None }
+        }
+
+        KnownState {
+            status_flag: agree(self.status_flag, other.status_flag),
+            page_buffer: agree(self.page_buffer, other.page_buffer),
+            subroutine_jump: agree(self.subroutine_jump, other.subroutine_jump),
+        }
+    }
+
+    /// Returns the state when leaving a node, first for execution continuing with the next node and then for a jump to
+    /// the node's target.
+    pub fn exits(self, flow: &NodeFlow) -> (KnownState, KnownState) {
+        let Some(instructions) = &flow.instructions else {
+            return (KnownState::unknown(), KnownState::unknown());
+        };
+
+        match instructions.split_last() {
+            Some((branch @ Instruction::BRN { .. }, rest)) => {
+                let before = rest.iter().fold(self, |state, instruction| state.after(instruction));
+                (before.after(branch), KnownState { status_flag: Some(true), ..before })
+            }
+            _ => {
+                let after = instructions.iter().fold(self, |state, instruction| state.after(instruction));
+                (after, after)
+            }
+        }
+    }
+
+    /// Returns the state after `instruction` runs, assuming execution continues with the next instruction.
+    pub fn after(self, instruction: &Instruction) -> KnownState {
+        match instruction {
+            Instruction::SSF => KnownState { status_flag: Some(true), ..self },
+            Instruction::RSF => KnownState { status_flag: Some(false), ..self },
+            Instruction::SSJ => KnownState { subroutine_jump: Some(true), ..self },
+            Instruction::RSJ => KnownState { subroutine_jump: Some(false), ..self },
+            Instruction::LPB { immediate } => KnownState { page_buffer: Some((*immediate).into()), ..self },
+            Instruction::ADD { .. }
+            | Instruction::SUB { .. }
+            | Instruction::CMP { .. }
+            | Instruction::GRT { .. }
+            | Instruction::LES { .. } => KnownState { status_flag: None, ..self },
+            Instruction::BRN { .. } => match self {
+                // The branch is never taken.
+                KnownState { status_flag: Some(false), .. } => self,
+                // A plain jump never comes back, so falling through means the flag was not set.
+                KnownState { subroutine_jump: Some(false), .. } => KnownState { status_flag: Some(false), ..self },
+                // This might have been a subroutine call, which can change anything before returning.
+                _ => KnownState::unknown(),
+            },
+            _ => self,
+        }
+    }
+}
+
+/// Which parts of the computer's state may still be read before being overwritten.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Live {
+    pub status_flag: bool,
+    pub page_buffer: bool,
+}
+
+impl Live {
+    pub fn all() -> Live {
+        Live {
+            status_flag: true,
+            page_buffer: true,
+        }
+    }
+
+    pub fn union(self, other: Live) -> Live {
+        Live {
+            status_flag: self.status_flag || other.status_flag,
+            page_buffer: self.page_buffer || other.page_buffer,
+        }
+    }
+
+    /// Returns what is live before `instruction` runs, given what is live after it.
+    pub fn before(self, instruction: &Instruction) -> Live {
+        match instruction {
+            Instruction::SSF
+            | Instruction::RSF
+            | Instruction::CMP { .. }
+            | Instruction::GRT { .. }
+            | Instruction::LES { .. } => Live { status_flag: false, ..self },
+            Instruction::LPB { .. } => Live { page_buffer: false, ..self },
+            Instruction::BRN { .. } => Live::all(),
+            // We have no idea where we are returning to.
+            Instruction::RET => Live::all(),
+            _ => self,
+        }
+    }
+}
+
+/// How control moves through a single node of the program.
+pub struct NodeFlow {
+    /// The instructions the node turns into, or `None` if they could not be worked out, for example because of invalid
+    /// arguments.
+    pub instructions: Option<Vec<Instruction>>,
+    /// The index of the label node this node may jump to.
+    pub target: Option<usize>,
+    /// Whether execution can continue with the next node.
+    pub falls_through: bool,
+}
+
+/// Returns whether execution can continue with the node after `node`. Branches are assumed to be conditional, since
+/// whether the status flag is set is not always known.
+pub fn falls_through(node: &Node) -> bool {
+    match node {
+        Node::Instruction { kind, .. } => *kind != InstructionKind::RET,
+        Node::PseudoInstruction { kind, .. } => *kind != PseudoInstructionKind::Jmp,
+        // Placement directives move on to a different part of program memory.
+        Node::Directive { kind, .. } => !kind.is_placement(),
+        _ => true,
+    }
+}
+
+/// Works out how control moves through every node of a laid out program.
+pub fn node_flows(program: &[Node], layout: &Layout, symbols: &SymbolTable) -> Vec<NodeFlow> {
+    let label_node = |arguments: &[Node], page: usize| match arguments {
+        [Node::LabelReference { name, .. }] => symbols.resolve(*name, page).map(|symbol| symbol.node),
+        _ => None,
+    };
+
+    program
+        .iter()
+        .enumerate()
+        .map(|(i, node)| match node {
+            Node::Instruction { kind, arguments, location } => NodeFlow {
+                instructions: codegen::to_instruction(*kind, arguments, *location, symbols, layout.page(i))
+                    .ok()
+                    .map(|instruction| vec![instruction]),
+                target: if *kind == InstructionKind::BRN { label_node(arguments, layout.page(i)) } else { None },
+                falls_through: falls_through(node),
+            },
+            Node::PseudoInstruction { kind, arguments, .. } => NodeFlow {
+                instructions: jump_target(arguments, symbols, layout.page(i))
+                    .map(|target| codegen::expand(*kind, layout.form(i), target)),
+                target: label_node(arguments, layout.page(i)),
+                falls_through: falls_through(node),
+            },
+            // The bytes might be run as instructions, and what they would do isn't worked out, so nothing is known
+            // after them.
+            Node::Directive { kind: DirectiveKind::Byte | DirectiveKind::Fill, .. } => NodeFlow {
+                instructions: None,
+                target: None,
+                falls_through: falls_through(node),
+            },
+            _ => NodeFlow {
+                instructions: Some(vec![]),
+                target: None,
+                falls_through: falls_through(node),
+            },
+        })
+        .collect()
+}
+
+/// Works out what is known about the computer's state at the start of every node by following every path through the
+/// program. Nodes that nothing visibly flows into, such as code after a placement directive that is only reached
+/// through a numeric `BRN`, are assumed to start with nothing known.
+///
+/// # Arguments
+///
+/// * `flows`: How control moves through each node, as returned by `node_flows`.
+/// * `layout`: The layout of the program, used to account for jumps carrying execution over to the next page.
+pub fn known_states(flows: &[NodeFlow], layout: &Layout) -> Vec<KnownState> {
+    let mut states: Vec<Option<KnownState>> = vec![None; flows.len()];
+
+    let mut has_predecessor = vec![false; flows.len()];
+    for (i, flow) in flows.iter().enumerate() {
+        if flow.falls_through && i + 1 < flows.len() {
+            has_predecessor[i + 1] = true;
+        }
+        if let Some(target) = flow.target {
+            has_predecessor[target] = true;
+        }
+    }
+    for (i, state) in states.iter_mut().enumerate() {
+        if i == 0 {
+            *state = Some(KnownState::reset());
+        } else if !has_predecessor[i] {
+            *state = Some(KnownState::unknown());
+        }
+    }
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (i, flow) in flows.iter().enumerate() {
+            let Some(state) = states[i] else {
+                continue;
+            };
+
+            let (next, jump) = state.exits(flow);
+            if flow.falls_through && i + 1 < flows.len() {
+                let next = match layout.guard(i + 1) {
+                    Some(guard) => KnownState {
+                        status_flag: Some(true),
+                        page_buffer: Some((guard.target / PAGE_SIZE) as u8),
+                        ..next
+                    },
+                    None => next,
+                };
+                changed |= merge(&mut states[i + 1], next);
+            }
+            if let Some(target) = flow.target {
+                changed |= merge(&mut states[target], jump);
+            }
+        }
+    }
+
+    states.into_iter().map(|state| state.unwrap_or(KnownState::unknown())).collect()
+}
+
+/// Works out what may be read before being overwritten at the start of every node.
+///
+/// # Arguments
+///
+/// * `flows`: How control moves through each node, as returned by `node_flows`.
+pub fn live_states(flows: &[NodeFlow]) -> Vec<Live> {
+    let mut live = vec![Live::default(); flows.len()];
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (i, flow) in flows.iter().enumerate().rev() {
+            let mut after = Live::default();
+            if flow.falls_through && i + 1 < flows.len() {
+                after = after.union(live[i + 1]);
+            }
+            if let Some(target) = flow.target {
+                after = after.union(live[target]);
+            }
+
+            let before = match &flow.instructions {
+                Some(instructions) => instructions.iter().rev().fold(after, |live, instruction| live.before(instruction)),
+                None => Live::all(),
+            };
+            if before != live[i] {
+                live[i] = before;
+                changed = true;
+            }
+        }
+    }
+
+    live
+}
+
+fn merge(slot: &mut Option<KnownState>, incoming: KnownState) -> bool {
+    let merged = match *slot {
+        Some(current) => current.meet(incoming),
+        None => incoming,
+    };
+    let changed = *slot != Some(merged);
+    *slot = Some(merged);
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_page_buffer() {
+        let state = KnownState::reset().after(&Instruction::LPB { immediate: 5u8.into() });
+        assert_eq!(state.page_buffer, Some(5));
+    }
+
+    #[test]
+    fn comparisons_forget_status_flag() {
+        let state = KnownState::reset()
+            .after(&Instruction::SSF)
+            .after(&Instruction::CMP { register_id: 1u8.into() });
+        assert_eq!(state.status_flag, None);
+    }
+
+    #[test]
+    fn untaken_branch_clears_status_flag() {
+        let state = KnownState::unknown()
+            .after(&Instruction::RSJ)
+            .after(&Instruction::LPB { immediate: 2u8.into() })
+            .after(&Instruction::BRN { immediate: 0u8.into() });
+        assert_eq!(state.status_flag, Some(false));
+        assert_eq!(state.page_buffer, Some(2));
+    }
+
+    #[test]
+    fn possible_subroutine_call_forgets_everything() {
+        let state = KnownState::reset()
+            .after(&Instruction::SSJ)
+            .after(&Instruction::SSF)
+            .after(&Instruction::BRN { immediate: 0u8.into() });
+        assert_eq!(state, KnownState::unknown());
+    }
+}
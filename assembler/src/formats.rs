@@ -0,0 +1,176 @@
+use common::architecture::{PAGE_SIZE, PROGRAM_MEMORY_SIZE};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// A way of writing out an image of program memory, for the emulator, a logic simulator or a ROM programmer.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Format {
+    /// The bytes of the image as they are, which is what the emulator loads.
+    Raw,
+    /// Intel HEX, with 16 bytes per record.
+    IntelHex,
+    /// The "v2.0 raw" images Logisim-evolution loads into a ROM component.
+    Logisim,
+    /// Text for Verilog's `$readmemh`, one byte in hexadecimal per word.
+    ReadMemH,
+    /// Text for Verilog's `$readmemb`, one byte in binary per word.
+    ReadMemB,
+    /// A raw file per page, for programmers writing one 64 byte EEPROM page at a time.
+    Pages,
+}
+
+impl Format {
+    pub const ALL: [Format; 6] =
+        [Format::Raw, Format::IntelHex, Format::Logisim, Format::ReadMemH, Format::ReadMemB, Format::Pages];
+
+    /// The name of the format on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Raw => "raw",
+            Format::IntelHex => "ihex",
+            Format::Logisim => "logisim",
+            Format::ReadMemH => "readmemh",
+            Format::ReadMemB => "readmemb",
+            Format::Pages => "pages",
+        }
+    }
+
+    /// The extension of the files written in the format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Raw => "out",
+            Format::IntelHex => "hex",
+            Format::Logisim => "img",
+            Format::ReadMemH => "memh",
+            Format::ReadMemB => "memb",
+            Format::Pages => "bin",
+        }
+    }
+
+    /// Writes an image out in the format. Returns the path and contents of every file, which is only `output` itself
+    /// for every format but [`Format::Pages`]. Pages go next to it, numbered from `00` to `15`, like `rom.03.bin`.
+    ///
+    /// # Arguments
+    ///
+    /// * `image`: The contents of program memory.
+    /// * `output`: The path to write to.
+    pub fn write(self, image: &[u8; PROGRAM_MEMORY_SIZE], output: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+        let contents = match self {
+            Format::Raw => image.to_vec(),
+            Format::IntelHex => intel_hex(image).into_bytes(),
+            Format::Logisim => logisim(image).into_bytes(),
+            Format::ReadMemH => read_mem(image, 16, |byte| format!("{byte:02x}")).into_bytes(),
+            Format::ReadMemB => read_mem(image, 8, |byte| format!("{byte:08b}")).into_bytes(),
+            Format::Pages => {
+                let extension = output.extension().and_then(|extension| extension.to_str()).unwrap_or("bin");
+                return image
+                    .chunks(PAGE_SIZE)
+                    .enumerate()
+                    .map(|(page, bytes)| (output.with_extension(format!("{page:02}.{extension}")), bytes.to_vec()))
+                    .collect();
+            }
+        };
+        vec![(output.to_path_buf(), contents)]
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Format::ALL.into_iter().find(|format| format.name() == s).ok_or_else(|| format!("Unknown format {s}"))
+    }
+}
+
+/// Writes the image as Intel HEX data records of 16 bytes, followed by the end of file record.
+fn intel_hex(image: &[u8; PROGRAM_MEMORY_SIZE]) -> String {
+    let mut output = String::new();
+    for (i, bytes) in image.chunks(16).enumerate() {
+        let address = (i * 16) as u16;
+        let mut record = vec![bytes.len() as u8];
+        record.extend(address.to_be_bytes());
+        record.push(0x00);
+        record.extend(bytes);
+        // The checksum makes every byte of the record add up to zero.
+        let checksum = record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)).wrapping_neg();
+        record.push(checksum);
+
+        output.push(':');
+        for byte in record {
+            write!(output, "{byte:02X}").unwrap();
+        }
+        output.push('\n');
+    }
+    output.push_str(":00000001FF\n");
+    output
+}
+
+/// Writes the image as a Logisim-evolution image, with a row of 16 bytes per line.
+fn logisim(image: &[u8; PROGRAM_MEMORY_SIZE]) -> String {
+    let mut output = "v2.0 raw\n".to_owned();
+    for bytes in image.chunks(16) {
+        let words: Vec<_> = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+        writeln!(output, "{}", words.join(" ")).unwrap();
+    }
+    output
+}
+
+/// Writes the image for `$readmemh` or `$readmemb`, with `width` words per line and each page starting at a comment
+/// naming it.
+fn read_mem(image: &[u8; PROGRAM_MEMORY_SIZE], width: usize, word: impl Fn(u8) -> String) -> String {
+    let mut output = String::new();
+    for (page, bytes) in image.chunks(PAGE_SIZE).enumerate() {
+        writeln!(output, "// page {page}").unwrap();
+        for row in bytes.chunks(width) {
+            let words: Vec<_> = row.iter().map(|byte| word(*byte)).collect();
+            writeln!(output, "{}", words.join(" ")).unwrap();
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::architecture::NUM_PAGES;
+
+    fn image() -> [u8; PROGRAM_MEMORY_SIZE] {
+        let mut image = [0; PROGRAM_MEMORY_SIZE];
+        image[0..3].copy_from_slice(&[0b11010100, 0b11101000, 0b10000011]);
+        image[PROGRAM_MEMORY_SIZE - 1] = 0xff;
+        image
+    }
+
+    fn text(format: Format) -> String {
+        let files = format.write(&image(), Path::new("rom"));
+        assert_eq!(files.len(), 1);
+        String::from_utf8(files[0].1.clone()).unwrap()
+    }
+
+    #[test]
+    fn writes_text_formats() {
+        let hex = text(Format::IntelHex);
+        assert!(hex.starts_with(":10000000D4E88300000000000000000000000000B1\n"));
+        assert!(hex.ends_with(":1003F000000000000000000000000000000000FFFE\n:00000001FF\n"));
+        assert_eq!(hex.lines().count(), PROGRAM_MEMORY_SIZE / 16 + 1);
+
+        let logisim = text(Format::Logisim);
+        assert!(logisim.starts_with("v2.0 raw\nd4 e8 83 00 "));
+        assert_eq!(logisim.lines().count(), PROGRAM_MEMORY_SIZE / 16 + 1);
+
+        assert!(text(Format::ReadMemH).starts_with("// page 0\nd4 e8 83 00 "));
+        assert!(text(Format::ReadMemB).starts_with("// page 0\n11010100 11101000 10000011 00000000 "));
+        assert!(text(Format::ReadMemB).ends_with("00000000 11111111\n"));
+    }
+
+    #[test]
+    fn splits_pages() {
+        let files = Format::Pages.write(&image(), Path::new("out/rom.bin"));
+        assert_eq!(files.len(), NUM_PAGES);
+        assert_eq!(files[3].0, PathBuf::from("out/rom.03.bin"));
+        assert!(files.iter().all(|(_, bytes)| bytes.len() == PAGE_SIZE));
+        assert_eq!(files[0].1[0..3], image()[0..3]);
+        assert_eq!(files[15].1[PAGE_SIZE - 1], 0xff);
+    }
+}
@@ -0,0 +1,223 @@
+use crate::lexer::{DirectiveKind, InstructionKind, Lexer, NumberLiteralKind, PseudoInstructionKind, Token, TokenKind};
+use std::collections::HashSet;
+
+/// How far instructions and directives are indented.
+const INDENT: usize = 4;
+/// The column operands start at, unless the mnemonic before them is longer.
+const OPERAND_COLUMN: usize = 12;
+/// The column trailing comments start at, unless the code before them is longer.
+const COMMENT_COLUMN: usize = 40;
+
+/// Rewrites a program in the canonical layout: labels in column 0 on lines of their own, instructions and directives
+/// indented with their operands lined up, and trailing comments lined up in a column. Instructions, pseudo-instructions
+/// and directives are lowercased, and operands are spaced the same way everywhere.
+///
+/// Comments are kept as they are. Comments on lines of their own stay in column 0 if they were there, and are indented
+/// like instructions otherwise. Formatting only changes whitespace and the case of mnemonics, and formatting it again
+/// changes nothing. Labels and macro names keep their case. A mnemonic in another case that is also the name of a
+/// macro, like `LDI` after `.macro LDI`, is a call to that macro and is left alone. So is every mnemonic in another
+/// case in a file with an `.include`, since the included files may define it as a macro.
+///
+/// Returns an error if the program contains characters that aren't ASCII, which the assembler can't read.
+///
+/// # Arguments
+///
+/// * `source`: The text of the program.
+pub fn format(source: &str) -> Result<String, String> {
+    if !source.is_ascii() {
+        return Err("Only ASCII characters can be used in programs".to_owned());
+    }
+
+    let mut lexer = Lexer::new(source, 0);
+    let mut tokens: Vec<Vec<Token>> = vec![vec![]; source.lines().count()];
+    for token in lexer.iter().filter(|token| token.kind != TokenKind::Newline) {
+        tokens[token.location.line as usize - 1].push(token);
+    }
+    let macros = macro_names(&tokens);
+
+    let mut output = String::new();
+    let mut blank = false;
+    for (text, tokens) in source.lines().zip(&tokens) {
+        let line_start = text.as_ptr() as usize - source.as_ptr() as usize;
+        let code_end = tokens.last().map_or(0, |token| token.location.index as usize - line_start + token.text.len());
+        let comment = text[code_end..].trim();
+
+        if tokens.is_empty() && comment.is_empty() {
+            // Blank lines are kept to separate parts of the program, but never more than one in a row.
+            blank = !output.is_empty();
+            continue;
+        }
+        if blank {
+            output.push('\n');
+            blank = false;
+        }
+
+        if tokens.is_empty() {
+            let indent = if text.starts_with(';') { 0 } else { INDENT };
+            output.push_str(&format!("{:indent$}{comment}\n", ""));
+            continue;
+        }
+
+        // Every label gets a line of its own, and the comment goes with the last line.
+        let mut lines = vec![];
+        let mut rest = tokens.as_slice();
+        while let [label, Token { kind: TokenKind::Colon, .. }, after @ ..] = rest {
+            let decimal = TokenKind::NumberLiteral { kind: NumberLiteralKind::Decimal };
+            if label.kind != TokenKind::LabelIdentifier && label.kind != decimal {
+                break;
+            }
+            lines.push(format!("{}:", label.text));
+            rest = after;
+        }
+        if let [head, operands @ ..] = rest {
+            let mnemonic = mnemonic(head, macros.as_ref());
+            let width = (OPERAND_COLUMN - INDENT).max(mnemonic.len() + 1);
+            let statement = format!("{:INDENT$}{mnemonic:width$}{}", "", join(operands));
+            lines.push(statement.trim_end().to_owned());
+        }
+
+        let last = lines.len() - 1;
+        for (i, line) in lines.into_iter().enumerate() {
+            if i == last && !comment.is_empty() {
+                let column = COMMENT_COLUMN.max(line.len() + 1);
+                output.push_str(&format!("{line:column$}{comment}\n"));
+            } else {
+                output.push_str(&format!("{line}\n"));
+            }
+        }
+    }
+
+    Ok(output)
+}
+
+/// Gets the names of the macros defined in a program, which are left as they are written. Returns None if the program
+/// includes other files, since any name could be a macro defined in one of them.
+fn macro_names<'a>(lines: &[Vec<Token<'a>>]) -> Option<HashSet<&'a str>> {
+    let mut names = HashSet::new();
+    for tokens in lines {
+        match tokens.as_slice() {
+            [Token { kind: TokenKind::Directive { kind: DirectiveKind::Macro }, .. }, name, ..] => {
+                names.insert(name.text);
+            }
+            [Token { kind: TokenKind::Directive { kind: DirectiveKind::Include }, .. }, ..] => return None,
+            _ => {}
+        }
+    }
+    Some(names)
+}
+
+/// Writes the first word of a statement, lowercased if it is an instruction, pseudo-instruction or directive written
+/// in another case that can't be a macro.
+fn mnemonic(head: &Token, macros: Option<&HashSet<&str>>) -> String {
+    let lowercase = head.text.to_ascii_lowercase();
+    let known = InstructionKind::from_str(&lowercase).is_some()
+        || PseudoInstructionKind::from_str(&lowercase).is_some()
+        || DirectiveKind::from_str(&lowercase).is_some();
+    match macros {
+        Some(macros) if head.kind == TokenKind::LabelIdentifier && known && !macros.contains(head.text) => lowercase,
+        _ => head.text.to_owned(),
+    }
+}
+
+/// Writes the operands of a statement with a space between them and around binary operators, and none inside
+/// parentheses, before commas or after a unary minus.
+fn join(tokens: &[Token]) -> String {
+    let mut text = String::new();
+    let mut previous: Option<TokenKind> = None;
+    let mut unary = false;
+    for token in tokens {
+        let space = match (previous, token.kind) {
+            (None, _) => false,
+            (_, TokenKind::Comma | TokenKind::CloseParen) => false,
+            (Some(TokenKind::OpenParen), _) => false,
+            // A name followed by a parenthesis is always a function call.
+            (Some(TokenKind::LabelIdentifier), TokenKind::OpenParen) => false,
+            (Some(TokenKind::Minus), _) => !unary,
+            _ => true,
+        };
+        if space {
+            text.push(' ');
+        }
+        text.push_str(token.text);
+
+        // Registers and strings are arguments of their own, so a minus after them starts a new one.
+        let ends_value = matches!(
+            previous,
+            Some(TokenKind::LabelIdentifier | TokenKind::NumberLiteral { .. } | TokenKind::CloseParen)
+        );
+        unary = token.kind == TokenKind::Minus && !ends_value;
+        previous = Some(token.kind);
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lays_out_lines() {
+        let source = "; header\n\n\n  START: ldi x 4;load\nloop:  brn   loop\n1: .2: nop\n   ; note\n\
+                      .equ MASK,( 1<<2 )|-1 ;  keep  this\nldi y hi (MASK)-1\n  put_char 'H' , - 2\n";
+        let formatted = format(source).unwrap();
+        assert_eq!(
+            formatted,
+            "; header\n\
+             \n\
+             START:\n    ldi     x 4                         ;load\n\
+             loop:\n    brn     loop\n\
+             1:\n.2:\n    nop\n\
+             \x20   ; note\n\
+             \x20   .equ    MASK, (1 << 2) | -1         ;  keep  this\n\
+             \x20   ldi     y hi(MASK) - 1\n\
+             \x20   put_char 'H', -2\n"
+        );
+        assert_eq!(format(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn keeps_macro_names_and_strings() {
+        let source = ".macro NOP\n.endm\nNOP\nSSF\n.print \"a ; b\" ; c\n";
+        assert_eq!(
+            format(source).unwrap(),
+            "    .macro  NOP\n    .endm\n    NOP\n    ssf\n    .print  \"a ; b\"                     ; c\n"
+        );
+        assert!(format("nop ; caf\u{e9}\n").is_err());
+    }
+
+    #[test]
+    fn assembles_the_same_after_formatting() {
+        use crate::{assemble, Options};
+
+        let directory = std::env::temp_dir().join(format!("assembler-formatter-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("macros.asm"), ".macro LDI register value\n    ldi register value + 1\n.endm\n")
+            .unwrap();
+        let options = Options { path: directory.join("main.asm"), ..Options::default() };
+
+        // An uppercase instruction is a macro call, whether the macro is defined here or in an included file.
+        let defined = ".macro LDI r v\n  ldi r v+1\n.endm\nLDI x 4\n";
+        for source in [defined, ".include \"macros.asm\"\nLDI x 4\n"] {
+            let formatted = format(source).unwrap();
+            assert!(formatted.contains("LDI     x 4"));
+            let before = assemble(source, &options).map(|program| program.image);
+            let after = assemble(&formatted, &options).map(|program| program.image);
+            assert_eq!(before.is_ok(), after.is_ok());
+            assert_eq!(before.ok(), after.ok());
+        }
+    }
+
+    #[test]
+    fn lowercases_mnemonics() {
+        use crate::{assemble, Options};
+
+        let source = "Start: LDI x 4\n  Jmp_If Start\n.EQU Mask 1\n.Byte Mask\nSsf\n";
+        let formatted = format(source).unwrap();
+        assert_eq!(
+            formatted,
+            "Start:\n    ldi     x 4\n    jmp_if  Start\n    .equ    Mask 1\n    .byte   Mask\n    ssf\n"
+        );
+        assert_eq!(format(&formatted).unwrap(), formatted);
+        assert!(assemble(&formatted, &Options::default()).is_ok());
+    }
+}
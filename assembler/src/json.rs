@@ -0,0 +1,339 @@
+use std::fmt::{Display, Formatter, Write};
+
+/// A JSON value, as sent between the language server and editors.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    /// The members of an object, in the order they were written in.
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Creates an object from its members.
+    pub fn object<const N: usize>(members: [(&str, Json); N]) -> Json {
+        Json::Object(members.into_iter().map(|(key, value)| (key.to_owned(), value)).collect())
+    }
+
+    /// Reads a JSON value, which has to make up all of `text` apart from whitespace around it.
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut reader = Reader { text: text.as_bytes(), position: 0 };
+        let value = reader.value()?;
+        reader.skip_whitespace();
+        match reader.text.get(reader.position) {
+            None => Ok(value),
+            Some(_) => Err(reader.error("Expected the end of the text")),
+        }
+    }
+
+    /// Gets a member of an object. Missing members, and members of anything that isn't an object, are null, so
+    /// members of members can be looked up without checking each one.
+    pub fn get(&self, key: &str) -> &Json {
+        match self {
+            Json::Object(members) => {
+                members.iter().find(|(name, _)| name == key).map_or(&Json::Null, |(_, value)| value)
+            }
+            _ => &Json::Null,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Gets the value of a number that is a whole number and isn't negative.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Json::Number(value) if *value >= 0.0 && value.fract() == 0.0 => Some(*value as u64),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Self {
+        Json::Bool(value)
+    }
+}
+
+impl From<u32> for Json {
+    fn from(value: u32) -> Self {
+        Json::Number(value.into())
+    }
+}
+
+impl From<usize> for Json {
+    fn from(value: usize) -> Self {
+        Json::Number(value as f64)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Self {
+        Json::String(value.to_owned())
+    }
+}
+
+impl From<String> for Json {
+    fn from(value: String) -> Self {
+        Json::String(value)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(values: Vec<Json>) -> Self {
+        Json::Array(values)
+    }
+}
+
+/// Writes the value without any whitespace.
+impl Display for Json {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(value) => write!(f, "{value}"),
+            // Whole numbers are written without a fraction, since that is what ids and positions are read as.
+            Json::Number(value) if value.fract() == 0.0 && value.abs() < 1e15 => write!(f, "{}", *value as i64),
+            Json::Number(value) => write!(f, "{value}"),
+            Json::String(value) => write_string(f, value),
+            Json::Array(values) => {
+                f.write_char('[')?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                f.write_char(']')
+            }
+            Json::Object(members) => {
+                f.write_char('{')?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{value}")?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut Formatter<'_>, value: &str) -> std::fmt::Result {
+    f.write_char('"')?;
+    for c in value.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+/// Reads a JSON value from text, one byte at a time.
+struct Reader<'a> {
+    text: &'a [u8],
+    position: usize,
+}
+
+impl Reader<'_> {
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.text.get(self.position) {
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'[') => self.array(),
+            Some(b'{') => self.object(),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            _ => Err(self.error("Expected a value")),
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        if !self.text[self.position..].starts_with(word.as_bytes()) {
+            return Err(self.error("Expected a value"));
+        }
+        self.position += word.len();
+        Ok(value)
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.position;
+        while let Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') = self.text.get(self.position) {
+            self.position += 1;
+        }
+        // The text came from a string and only ASCII was taken, so it is still valid UTF-8.
+        let number = std::str::from_utf8(&self.text[start..self.position]).unwrap();
+        number.parse().map(Json::Number).map_err(|_| self.error(&format!("Invalid number {number}")))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let mut bytes = vec![];
+        loop {
+            match self.text.get(self.position) {
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    self.position += 1;
+                    let c = match self.text.get(self.position) {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => self.unicode_escape()?,
+                        _ => return Err(self.error("Invalid escape sequence")),
+                    };
+                    bytes.extend(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                Some(byte) => bytes.push(*byte),
+                None => return Err(self.error("Expected the end of the string")),
+            }
+            self.position += 1;
+        }
+        self.position += 1;
+        // Only whole characters are copied out of the text, so the bytes are valid UTF-8.
+        Ok(String::from_utf8(bytes).unwrap())
+    }
+
+    /// Reads the digits of a `\u` escape, along with the second half of a surrogate pair if there is one. Leaves the
+    /// position at the last digit.
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let first = self.hex_digits()?;
+        let code = if (0xd800..0xdc00).contains(&first) && self.text[self.position + 1..].starts_with(b"\\u") {
+            self.position += 2;
+            let second = self.hex_digits()?;
+            if !(0xdc00..0xe000).contains(&second) {
+                return Err(self.error("Invalid escape sequence"));
+            }
+            0x10000 + ((first - 0xd800) << 10) + (second - 0xdc00)
+        } else {
+            first
+        };
+        char::from_u32(code).ok_or_else(|| self.error("Invalid escape sequence"))
+    }
+
+    fn hex_digits(&mut self) -> Result<u32, String> {
+        let digits = self.text.get(self.position + 1..self.position + 5);
+        let value = digits.and_then(|digits| u32::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok());
+        self.position += 4;
+        value.ok_or_else(|| self.error("Invalid escape sequence"))
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        self.expect(b'[')?;
+        let mut values = vec![];
+        self.skip_whitespace();
+        if self.text.get(self.position) == Some(&b']') {
+            self.position += 1;
+            return Ok(Json::Array(values));
+        }
+        loop {
+            values.push(self.value()?);
+            self.skip_whitespace();
+            match self.text.get(self.position) {
+                Some(b',') => self.position += 1,
+                Some(b']') => break,
+                _ => return Err(self.error("Expected ',' or ']'")),
+            }
+        }
+        self.position += 1;
+        Ok(Json::Array(values))
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.expect(b'{')?;
+        let mut members = vec![];
+        self.skip_whitespace();
+        if self.text.get(self.position) == Some(&b'}') {
+            self.position += 1;
+            return Ok(Json::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.skip_whitespace();
+            self.expect(b':')?;
+            members.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.text.get(self.position) {
+                Some(b',') => self.position += 1,
+                Some(b'}') => break,
+                _ => return Err(self.error("Expected ',' or '}'")),
+            }
+        }
+        self.position += 1;
+        Ok(Json::Object(members))
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        if self.text.get(self.position) != Some(&byte) {
+            return Err(self.error(&format!("Expected '{}'", byte as char)));
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.text.get(self.position) {
+            self.position += 1;
+        }
+    }
+
+    fn error(&self, message: &str) -> String {
+        format!("{message} at byte {}", self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_what_it_writes() {
+        let text = r#" {"id": 1, "params": {"text": "nop\n\"\u00e9\ud83d\ude00", "list": [true, null, -2.5e1, []]}} "#;
+        let value = Json::parse(text).unwrap();
+        assert_eq!(value.get("id").as_u64(), Some(1));
+        assert_eq!(value.get("params").get("text").as_str(), Some("nop\n\"\u{e9}\u{1f600}"));
+        assert_eq!(value.get("params").get("list").as_array().unwrap()[2], Json::Number(-25.0));
+        assert_eq!(value.get("missing").get("member"), &Json::Null);
+
+        let written = value.to_string();
+        let expected = r#"{"id":1,"params":{"text":"nop\n\"é😀","list":[true,null,-25,[]]}}"#;
+        assert_eq!(written, expected);
+        assert_eq!(Json::parse(&written).unwrap(), value);
+    }
+
+    #[test]
+    fn rejects_malformed_text() {
+        assert!(Json::parse("{\"id\": }").is_err());
+        assert!(Json::parse("[1, 2").is_err());
+        assert!(Json::parse("\"\\x\"").is_err());
+        assert!(Json::parse("1 2").is_err());
+    }
+}
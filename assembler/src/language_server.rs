@@ -0,0 +1,648 @@
+use crate::json::Json;
+use crate::lexer::{DirectiveKind, InstructionKind, Lexer, PseudoInstructionKind, Register, Token, TokenKind};
+use crate::location::Location;
+use crate::operands;
+use crate::parser::{scope_labels, Name, Node, Parser};
+use crate::sources::Sources;
+use crate::{assemble, Diagnostic, Options, Severity};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+/// The error code for a request with a method the server doesn't know.
+const METHOD_NOT_FOUND: i32 = -32601;
+/// The error code for a message that isn't valid JSON.
+const PARSE_ERROR: i32 = -32700;
+
+/// How a completion is shown in the editor.
+const KEYWORD_COMPLETION: u32 = 14;
+const VARIABLE_COMPLETION: u32 = 6;
+
+/// Runs a language server for programs, talking to an editor over `input` and `output` with the Language Server
+/// Protocol, until the editor asks it to exit or closes `input`.
+///
+/// The whole program is assembled every time an open file changes, and the errors and warnings in it are shown as
+/// diagnostics. Each open file is assembled on its own, along with the files it includes. The server also finds where
+/// labels, constants, variables, subroutines and macros are defined, shows the bit pattern and description of an
+/// instruction when hovering over it, and completes mnemonics, directives and registers.
+///
+/// # Arguments
+///
+/// * `input`: Where messages from the editor are read from, usually stdin.
+/// * `output`: Where messages to the editor are written to, usually stdout.
+pub fn serve(mut input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+    let mut server = Server::default();
+    while let Some(body) = read_message(&mut input)? {
+        let replies = match Json::parse(&body) {
+            Ok(message) => server.handle(&message),
+            Err(err) => vec![error_response(Json::Null, PARSE_ERROR, err)],
+        };
+        for reply in replies {
+            let body = reply.to_string();
+            write!(output, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+        }
+        output.flush()?;
+
+        if server.exited {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the body of the next message, after the headers in front of it. Returns None once `input` is closed.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse().ok();
+            }
+        }
+    }
+
+    let Some(length) = length else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Message has no Content-Length header"));
+    };
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    String::from_utf8(body).map(Some).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// What the server knows about the editor: the text of every open file, keyed by its URI.
+#[derive(Default)]
+struct Server {
+    documents: HashMap<String, String>,
+    /// Whether the editor has asked the server to exit.
+    exited: bool,
+}
+
+impl Server {
+    /// Handles a message from the editor, returning the messages to send back.
+    fn handle(&mut self, message: &Json) -> Vec<Json> {
+        // Messages without a method are responses, and the server never sends any requests.
+        let Some(method) = message.get("method").as_str() else {
+            return vec![];
+        };
+        let params = message.get("params");
+        let uri = params.get("textDocument").get("uri").as_str().unwrap_or_default();
+
+        let id = message.get("id");
+        if *id == Json::Null {
+            return match method {
+                "textDocument/didOpen" => {
+                    let text = params.get("textDocument").get("text").as_str().unwrap_or_default();
+                    self.documents.insert(uri.to_owned(), text.to_owned());
+                    vec![self.diagnose(uri)]
+                }
+                "textDocument/didChange" => {
+                    // Every change holds the whole text, since that is how the server asks for them to be sent.
+                    let changes = params.get("contentChanges").as_array().unwrap_or_default();
+                    if let Some(text) = changes.last().and_then(|change| change.get("text").as_str()) {
+                        self.documents.insert(uri.to_owned(), text.to_owned());
+                    }
+                    vec![self.diagnose(uri)]
+                }
+                "textDocument/didClose" => {
+                    self.documents.remove(uri);
+                    vec![self.diagnose(uri)]
+                }
+                "exit" => {
+                    self.exited = true;
+                    vec![]
+                }
+                _ => vec![],
+            };
+        }
+
+        let result = match method {
+            "initialize" => capabilities(),
+            "shutdown" => Json::Null,
+            "textDocument/definition" => self.definition(params),
+            "textDocument/hover" => self.hover(params),
+            "textDocument/completion" => self.completion(params),
+            _ => return vec![error_response(id.clone(), METHOD_NOT_FOUND, format!("Unknown method {method}"))],
+        };
+        vec![Json::object([("jsonrpc", "2.0".into()), ("id", id.clone()), ("result", result)])]
+    }
+
+    /// Assembles an open file and lists the problems in it. A file that was closed has its problems cleared.
+    fn diagnose(&self, uri: &str) -> Json {
+        let diagnostics = match self.documents.get(uri) {
+            Some(text) => {
+                let options = Options { path: path(uri), ..Options::default() };
+                match assemble(text, &options) {
+                    Ok(program) => program.warnings,
+                    Err(diagnostics) => diagnostics,
+                }
+            }
+            None => vec![],
+        };
+
+        // Problems in included files are shown when those are open.
+        let diagnostics = diagnostics.iter().filter(|diagnostic| diagnostic.location.is_none_or(|at| at.file == 0));
+        let params = Json::object([
+            ("uri", uri.into()),
+            ("diagnostics", diagnostics.map(diagnostic).collect::<Vec<_>>().into()),
+        ]);
+        Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("method", "textDocument/publishDiagnostics".into()),
+            ("params", params),
+        ])
+    }
+
+    /// Finds where the label, constant, variable, subroutine or macro under the cursor is defined. The open file is
+    /// searched first, and then the files it includes, directly or through other files, which are read from disk.
+    fn definition(&self, params: &Json) -> Json {
+        let Some((uri, text, index)) = self.position(params) else {
+            return Json::Null;
+        };
+
+        let mut sources = Sources::new(path(uri), text.to_owned());
+        // Includes that can't be read are shown as diagnostics, and the ones that could still be searched.
+        let _ = sources.load_includes(&[]);
+        let mut occurrences = vec![];
+        for (file, source) in sources.files().iter().enumerate().filter(|(_, source)| source.text.is_ascii()) {
+            for node in &parse(&source.text, file as u32) {
+                find_symbols(node, &mut occurrences);
+            }
+        }
+
+        let Some(symbol) = occurrences.iter().find(|occurrence| {
+            occurrence.location.file == 0 && contains(occurrence.location, index)
+        }) else {
+            return Json::Null;
+        };
+        // Local and anonymous labels only mean something in the file they are written in.
+        let local = matches!(symbol.symbol, Symbol::Name(Name { scope: Some(_), .. }));
+        occurrences
+            .iter()
+            .filter(|occurrence| !local || occurrence.location.file == 0)
+            .find(|occurrence| occurrence.definition && occurrence.symbol == symbol.symbol)
+            .map_or(Json::Null, |definition| {
+                let location = definition.location;
+                let uri = match location.file {
+                    0 => uri.to_owned(),
+                    file => self::uri(&sources.file(file).path),
+                };
+                Json::object([("uri", uri.into()), ("range", range(location, location.length as usize))])
+            })
+    }
+
+    /// Shows how the instruction under the cursor is written, its bit pattern and what it does.
+    fn hover(&self, params: &Json) -> Json {
+        let Some((_, text, index)) = self.position(params) else {
+            return Json::Null;
+        };
+        let Some(Token { kind: TokenKind::Instruction { kind }, location, .. }) = token_at(text, index) else {
+            return Json::Null;
+        };
+
+        let syntax = operands::schema(kind).syntax(kind);
+        let value = format!("```\n{syntax}\n```\n`{}` {}", kind.pattern(), description(kind));
+        Json::object([
+            ("contents", Json::object([("kind", "markdown".into()), ("value", value.into())])),
+            ("range", range(location, location.length as usize)),
+        ])
+    }
+
+    /// Completes the word before the cursor. The first word of a statement is a mnemonic or directive, and any word
+    /// after it is an operand, which can be a register.
+    fn completion(&self, params: &Json) -> Json {
+        let Some((_, text, index)) = self.position(params) else {
+            return Json::Null;
+        };
+        let line_start = text[..index].rfind('\n').map_or(0, |i| i + 1);
+        let before = &text[line_start..index];
+        if before.contains(';') {
+            return Json::Array(vec![]);
+        }
+
+        let word_start = before.rfind(|c: char| c.is_ascii_whitespace() || ",()".contains(c)).map_or(0, |i| i + 1);
+        let word = before[word_start..].to_ascii_lowercase();
+        // Only labels can come before the first word of a statement, and they end in a colon.
+        let mut lexer = Lexer::new(&before[..word_start], 0);
+        let first = lexer.iter().last().is_none_or(|token| token.kind == TokenKind::Colon);
+
+        let candidates: Vec<(&str, u32, String, String)> = if first {
+            let instructions = InstructionKind::ALL
+                .iter()
+                .map(|kind| (kind.mnemonic(), kind.pattern().to_owned(), description(*kind).to_owned()));
+            let pseudo_instructions = PseudoInstructionKind::ALL
+                .iter()
+                .map(|kind| (kind.name(), "pseudo-instruction".to_owned(), String::new()));
+            let directives =
+                DirectiveKind::ALL.iter().map(|kind| (kind.name(), "directive".to_owned(), String::new()));
+            let keywords = instructions.chain(pseudo_instructions).chain(directives);
+            keywords.map(|(name, detail, documentation)| (name, KEYWORD_COMPLETION, detail, documentation)).collect()
+        } else {
+            let registers = Register::ALL.iter().map(|register| register.name());
+            registers.map(|name| (name, VARIABLE_COMPLETION, "register".to_owned(), String::new())).collect()
+        };
+
+        // The whole word is replaced, so directives aren't written with two dots.
+        let line = text[..index].matches('\n').count();
+        let span = span(line, word_start, before.len());
+        let items = candidates.into_iter().filter(|(name, ..)| name.starts_with(&word)).map(
+            |(name, kind, detail, documentation)| {
+                Json::object([
+                    ("label", name.into()),
+                    ("kind", kind.into()),
+                    ("detail", detail.into()),
+                    ("documentation", documentation.into()),
+                    ("textEdit", Json::object([("range", span.clone()), ("newText", name.into())])),
+                ])
+            },
+        );
+        Json::Array(items.collect())
+    }
+
+    /// Gets the URI, text and cursor of the open file a request is about. The cursor is a byte offset into the text.
+    /// Files that can't be assembled because they aren't ASCII aren't looked at.
+    fn position<'s>(&'s self, params: &'s Json) -> Option<(&'s str, &'s str, usize)> {
+        let uri = params.get("textDocument").get("uri").as_str()?;
+        let text = self.documents.get(uri).filter(|text| text.is_ascii())?;
+
+        let line = params.get("position").get("line").as_u64()? as usize;
+        let character = params.get("position").get("character").as_u64()? as usize;
+        let line_start: usize = text.split_inclusive('\n').take(line).map(str::len).sum();
+        let line_length = text[line_start..].find('\n').unwrap_or(text.len() - line_start);
+        Some((uri, text, line_start + character.min(line_length)))
+    }
+}
+
+/// What the server can do, as told to the editor when it starts.
+fn capabilities() -> Json {
+    let capabilities = Json::object([
+        // The whole text of a file is sent every time it changes.
+        ("textDocumentSync", 1u32.into()),
+        ("definitionProvider", true.into()),
+        ("hoverProvider", true.into()),
+        ("completionProvider", Json::object([("triggerCharacters", vec![".".into()].into())])),
+    ]);
+    Json::object([("capabilities", capabilities), ("serverInfo", Json::object([("name", "assembler".into())]))])
+}
+
+fn error_response(id: Json, code: i32, message: String) -> Json {
+    let error = Json::object([("code", Json::Number(code.into())), ("message", message.into())]);
+    Json::object([("jsonrpc", "2.0".into()), ("id", id), ("error", error)])
+}
+
+/// Gets the path of a file from its URI, so the files it includes can be found next to it.
+fn path(uri: &str) -> PathBuf {
+    let Some(path) = uri.strip_prefix("file://") else {
+        return PathBuf::from(uri);
+    };
+
+    // Characters like spaces are percent-encoded.
+    let mut bytes = vec![];
+    let mut rest = path.as_bytes();
+    while let [byte, after @ ..] = rest {
+        let decoded = match after {
+            [high, low, ..] if *byte == b'%' => {
+                std::str::from_utf8(&[*high, *low]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            }
+            _ => None,
+        };
+        match decoded {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &after[2..];
+            }
+            None => {
+                bytes.push(*byte);
+                rest = after;
+            }
+        }
+    }
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Gets the URI of a file from its path, percent-encoding what `path` decodes.
+fn uri(path: &Path) -> String {
+    let mut uri = "file://".to_owned();
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'.' | b'_' | b'~' => uri.push(byte as char),
+            _ => write!(uri, "%{byte:02X}").unwrap(),
+        }
+    }
+    uri
+}
+
+/// Turns a diagnostic into the form editors show, with its help and notes on lines of their own.
+fn diagnostic(diagnostic: &Diagnostic) -> Json {
+    let mut message = diagnostic.message.clone();
+    if let Some(help) = &diagnostic.help {
+        write!(message, "\nhelp: {help}").unwrap();
+    }
+    for note in &diagnostic.notes {
+        write!(message, "\nnote: {note}").unwrap();
+    }
+
+    let severity: u32 = match diagnostic.severity {
+        Severity::Error => 1,
+        Severity::Warning => 2,
+    };
+    Json::object([
+        ("range", diagnostic.location.map_or_else(|| span(0, 0, 0), |location| range(location, diagnostic.length))),
+        ("severity", severity.into()),
+        ("code", diagnostic.code.into()),
+        ("source", "assembler".into()),
+        ("message", message.into()),
+    ])
+}
+
+/// The part of a line starting at a location, `length` characters long.
+fn range(location: Location, length: usize) -> Json {
+    let start = location.col as usize - 1;
+    span(location.line as usize - 1, start, start + length)
+}
+
+fn span(line: usize, start: usize, end: usize) -> Json {
+    let position = |character: usize| Json::object([("line", line.into()), ("character", character.into())]);
+    Json::object([("start", position(start)), ("end", position(end))])
+}
+
+/// Whether a byte offset is in the token at a location, or right after it.
+fn contains(location: Location, index: usize) -> bool {
+    (location.index as usize..=(location.index + location.length) as usize).contains(&index)
+}
+
+/// Finds the token the cursor is on, or the one it is right after.
+fn token_at(text: &str, index: usize) -> Option<Token<'_>> {
+    let mut lexer = Lexer::new(text, 0);
+    let tokens: Vec<_> = lexer.iter().filter(|token| token.kind != TokenKind::Newline).collect();
+    let inside = tokens.iter().find(|token| {
+        let start = token.location.index as usize;
+        (start..start + token.text.len()).contains(&index)
+    });
+    inside.or_else(|| tokens.iter().find(|token| contains(token.location, index))).cloned()
+}
+
+/// Says what an instruction does, in terms of the fields in its bit pattern.
+fn description(kind: InstructionKind) -> &'static str {
+    match kind {
+        InstructionKind::STR => "Stores the value of register rr in working memory at the address in XY",
+        InstructionKind::LOD => "Loads register rr from working memory at the address in XY",
+        InstructionKind::LDI => "Loads an immediate value into register rr",
+        InstructionKind::INC => "Increments register rr",
+        InstructionKind::DEC => "Decrements register rr",
+        InstructionKind::MOV => "Moves the value in register rr to register kk",
+        InstructionKind::INP => "Reads the value of port pp into Z",
+        InstructionKind::OUT => "Writes the value of Z to port pp",
+        InstructionKind::SEP => "Sets pin qq",
+        InstructionKind::RSP => "Resets pin qq",
+        InstructionKind::ADD => "Adds rr to A, wrapping around on overflow. The status flag is left as it is",
+        InstructionKind::SUB => "Subtracts rr from A, wrapping around on underflow. The status flag is left as it is",
+        InstructionKind::BOR => "Bitwise ORs rr with A",
+        InstructionKind::AND => "Bitwise ANDs rr with A",
+        InstructionKind::NOT => "Not of A",
+        InstructionKind::SHR => "Logical shift right of A",
+        InstructionKind::SHL => "Logical shift left of A",
+        InstructionKind::GRT => "Compares rr with A. Sets the status flag if A is greater, and resets it otherwise",
+        InstructionKind::LES => "Compares rr with A. Sets the status flag if A is lesser, and resets it otherwise",
+        InstructionKind::CMP => "Compares rr with A. Sets the status flag if they are equal, and resets it otherwise",
+        InstructionKind::BRN => {
+            "Jumps to the immediate address in the page stored in PB if the status flag is set. If the subroutine jump \
+            flag is set, PB is ignored and a subroutine jump within the current page is performed"
+        }
+        InstructionKind::LPB => "Loads the immediate into the page buffer",
+        InstructionKind::SSJ => "Sets the subroutine jump flag",
+        InstructionKind::RSJ => "Resets the subroutine jump flag",
+        InstructionKind::RET => "Returns from a subroutine, continuing execution at the location in SB",
+        InstructionKind::SSF => "Sets the status flag",
+        InstructionKind::RSF => "Resets the status flag",
+        InstructionKind::NOP => "Does nothing",
+    }
+}
+
+/// Parses every line of a program that can be parsed, so a line being typed doesn't hide everything else.
+fn parse(text: &str, file: u32) -> Vec<Node<'_>> {
+    let mut lexer = Lexer::new(text, file);
+    let mut lines = vec![vec![]];
+    for token in lexer.iter() {
+        match token.kind {
+            TokenKind::Newline => lines.push(vec![]),
+            _ => lines.last_mut().unwrap().push(token),
+        }
+    }
+
+    let mut program = vec![];
+    for line in lines {
+        if let Ok(mut nodes) = Parser::new(line.into_iter()).parse() {
+            program.append(&mut nodes);
+        }
+    }
+    scope_labels(&mut program);
+    program
+}
+
+/// Something a name in a program can stand for. Macros are kept apart from everything else, since they are only ever
+/// invoked and never used in expressions.
+#[derive(Eq, PartialEq)]
+enum Symbol<'a> {
+    Name(Name<'a>),
+    Macro(&'a str),
+}
+
+/// A place a symbol is written in a program.
+struct Occurrence<'a> {
+    symbol: Symbol<'a>,
+    location: Location,
+    /// Whether the symbol is defined here, rather than used.
+    definition: bool,
+}
+
+/// Finds every symbol written in a node, along with the nodes in it.
+fn find_symbols<'a>(node: &Node<'a>, occurrences: &mut Vec<Occurrence<'a>>) {
+    let (found, nested): (_, Vec<&Node<'a>>) = match node {
+        Node::Label { name, location } => (Some((Symbol::Name(*name), *location, true)), vec![]),
+        Node::LabelReference { name, location } => (Some((Symbol::Name(*name), *location, false)), vec![]),
+        Node::MacroCall { name, arguments, location } => {
+            (Some((Symbol::Macro(name), *location, false)), arguments.iter().collect())
+        }
+        // The parameters of a macro only mean something inside it, so only its name is looked at.
+        Node::Directive { kind: DirectiveKind::Macro, arguments, .. } => match arguments.first() {
+            Some(Node::LabelReference { name, location }) => {
+                (Some((Symbol::Macro(name.text), *location, true)), vec![])
+            }
+            _ => (None, vec![]),
+        },
+        Node::Directive { kind: DirectiveKind::Equ | DirectiveKind::Var | DirectiveKind::Sub, arguments, .. } => {
+            match arguments.split_first() {
+                Some((Node::LabelReference { name, location }, rest)) => {
+                    (Some((Symbol::Name(*name), *location, true)), rest.iter().collect())
+                }
+                _ => (None, arguments.iter().collect()),
+            }
+        }
+        Node::Instruction { arguments, .. }
+        | Node::PseudoInstruction { arguments, .. }
+        | Node::Directive { arguments, .. } => (None, arguments.iter().collect()),
+        Node::Negation { operand, .. } => (None, vec![&**operand]),
+        Node::BinaryOperation { left, right, .. } => (None, vec![&**left, &**right]),
+        Node::FunctionCall { argument, .. } => (None, vec![&**argument]),
+        Node::RegisterLiteral { .. } | Node::NumberLiteral { .. } | Node::StringLiteral { .. } => (None, vec![]),
+    };
+
+    if let Some((symbol, location, definition)) = found {
+        occurrences.push(Occurrence { symbol, location, definition });
+    }
+    for node in nested {
+        find_symbols(node, occurrences);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "file:///tmp/test%20program.asm";
+    const PROGRAM: &str = ".equ COUNT 3\nstart:\n    ldi x COUNT\n.loop:\n    dec x\n    brn .loop\n    brn start\n";
+
+    fn request(server: &mut Server, method: &str, line: u32, character: u32) -> Json {
+        request_in(server, URI, method, line, character)
+    }
+
+    fn request_in(server: &mut Server, uri: &str, method: &str, line: u32, character: u32) -> Json {
+        let position = Json::object([("line", line.into()), ("character", character.into())]);
+        let params = Json::object([("textDocument", Json::object([("uri", uri.into())])), ("position", position)]);
+        let message = Json::object([("id", 1u32.into()), ("method", method.into()), ("params", params)]);
+        let mut replies = server.handle(&message);
+        assert_eq!(replies.len(), 1);
+        replies.remove(0).get("result").clone()
+    }
+
+    fn open(text: &str) -> (Server, Json) {
+        let mut server = Server::default();
+        let notification = open_in(&mut server, URI, text);
+        (server, notification)
+    }
+
+    fn open_in(server: &mut Server, uri: &str, text: &str) -> Json {
+        let document = Json::object([("uri", uri.into()), ("text", text.into())]);
+        let message = Json::object([
+            ("method", "textDocument/didOpen".into()),
+            ("params", Json::object([("textDocument", document)])),
+        ]);
+        let mut replies = server.handle(&message);
+        replies.remove(0)
+    }
+
+    fn start(range: &Json) -> (u64, u64) {
+        let start = range.get("start");
+        (start.get("line").as_u64().unwrap(), start.get("character").as_u64().unwrap())
+    }
+
+    #[test]
+    fn publishes_diagnostics_with_exact_ranges() {
+        let (_, notification) = open("nop\n  mov x y z ; oops\n.equ UNUSED 1\n");
+        let diagnostics = notification.get("params").get("diagnostics").as_array().unwrap();
+
+        let codes: Vec<_> = diagnostics.iter().map(|diagnostic| diagnostic.get("code").as_str().unwrap()).collect();
+        assert_eq!(codes, ["W002", "E201"]);
+        let range = diagnostics[1].get("range");
+        assert_eq!(start(range), (1, 10));
+        assert_eq!(range.get("end").get("character").as_u64(), Some(11));
+        assert_eq!(diagnostics[0].get("severity").as_u64(), Some(2));
+
+        let (_, notification) = open("nop ; caf\u{e9}\n");
+        let diagnostics = notification.get("params").get("diagnostics").as_array().unwrap();
+        assert_eq!(diagnostics[0].get("code").as_str(), Some("E105"));
+        assert_eq!(start(diagnostics[0].get("range")), (0, 9));
+    }
+
+    #[test]
+    fn finds_definitions() {
+        let (mut server, _) = open(PROGRAM);
+        // COUNT in `ldi x COUNT`, and the cursor right after `.loop`.
+        assert_eq!(start(request(&mut server, "textDocument/definition", 2, 12).get("range")), (0, 5));
+        assert_eq!(start(request(&mut server, "textDocument/definition", 5, 13).get("range")), (3, 0));
+        assert_eq!(start(request(&mut server, "textDocument/definition", 6, 9).get("range")), (1, 0));
+        assert_eq!(request(&mut server, "textDocument/definition", 4, 4), Json::Null);
+        assert_eq!(request(&mut server, "textDocument/definition", 2, 12).get("uri").as_str(), Some(URI));
+    }
+
+    #[test]
+    fn finds_definitions_in_included_files() {
+        let directory = std::env::temp_dir().join(format!("assembler-server includes-{}", std::process::id()));
+        std::fs::create_dir_all(directory.join("lib")).unwrap();
+        std::fs::write(directory.join("lib/constants.asm"), ".include \"more.asm\"\n.2:\n.equ COUNT 3\n").unwrap();
+        std::fs::write(directory.join("lib/more.asm"), "\n.macro twice\n.endm\n").unwrap();
+        let main = uri(&directory.join("main.asm"));
+        assert!(main.contains("%20"));
+
+        let mut server = Server::default();
+        open_in(&mut server, &main, ".include \"lib/constants.asm\"\nldi x COUNT\ntwice\nbrn .2\n");
+        let definition = request_in(&mut server, &main, "textDocument/definition", 1, 7);
+        assert_eq!(definition.get("uri").as_str(), Some(uri(&directory.join("lib/constants.asm")).as_str()));
+        assert_eq!(start(definition.get("range")), (2, 5));
+        let definition = request_in(&mut server, &main, "textDocument/definition", 2, 1);
+        assert_eq!(path(definition.get("uri").as_str().unwrap()), directory.join("lib/more.asm"));
+        assert_eq!(start(definition.get("range")), (1, 7));
+        // Local labels in other files can't be reached.
+        assert_eq!(request_in(&mut server, &main, "textDocument/definition", 3, 5), Json::Null);
+    }
+
+    #[test]
+    fn describes_instructions() {
+        let (mut server, _) = open(PROGRAM);
+        let hover = request(&mut server, "textDocument/hover", 2, 5);
+        assert_eq!(
+            hover.get("contents").get("value").as_str(),
+            Some("```\nldi register immediate\n```\n`11rrxxxx` Loads an immediate value into register rr")
+        );
+        assert_eq!(start(hover.get("range")), (2, 4));
+        assert_eq!(request(&mut server, "textDocument/hover", 2, 10), Json::Null);
+    }
+
+    #[test]
+    fn completes_mnemonics_and_registers() {
+        let (mut server, _) = open("start: ld\n    mov x \n    .p\n");
+        let labels = |completions: Json| -> Vec<String> {
+            let items = completions.as_array().unwrap().iter();
+            items.map(|item| item.get("label").as_str().unwrap().to_owned()).collect()
+        };
+
+        assert_eq!(labels(request(&mut server, "textDocument/completion", 0, 9)), ["ldi", "ldxy"]);
+        assert_eq!(labels(request(&mut server, "textDocument/completion", 1, 10)), ["a", "x", "y", "z"]);
+
+        let completions = request(&mut server, "textDocument/completion", 2, 6);
+        assert_eq!(labels(completions.clone()), [".page", ".print", ".printer"]);
+        assert_eq!(start(completions.as_array().unwrap()[0].get("textEdit").get("range")), (2, 4));
+    }
+
+    #[test]
+    fn speaks_the_protocol() {
+        let messages = [
+            r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#,
+            r#"{"jsonrpc":"2.0","id":2,"method":"unknown"}"#,
+            r#"{"jsonrpc":"2.0","id":3,"method":"shutdown"}"#,
+            r#"{"jsonrpc":"2.0","method":"exit"}"#,
+            r#"{"jsonrpc":"2.0","id":4,"method":"shutdown"}"#,
+        ];
+        let input: String =
+            messages.iter().map(|body| format!("Content-Length: {}\r\n\r\n{body}", body.len())).collect();
+        let mut output = vec![];
+        serve(input.as_bytes(), &mut output).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let bodies: Vec<_> = output.split("Content-Length: ").skip(1).collect();
+        assert_eq!(bodies.len(), 3);
+        assert!(bodies[0].contains(r#""definitionProvider":true"#));
+        let error = r#"{"jsonrpc":"2.0","id":2,"error":{"code":-32601,"message":"Unknown method unknown"}}"#;
+        assert!(bodies[1].ends_with(error));
+        assert!(bodies[2].ends_with(r#"{"jsonrpc":"2.0","id":3,"result":null}"#));
+    }
+}
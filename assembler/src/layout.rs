@@ -0,0 +1,615 @@
+use crate::error::{AssemblyError, AssemblyErrorKind};
+use crate::expression::{self, Constants};
+use crate::flow::{self, KnownState, Live, NodeFlow};
+use crate::lexer::{DirectiveKind, PseudoInstructionKind};
+use crate::parser::Node;
+use crate::symbols::{Address, SymbolTable};
+use common::architecture::{NUM_PAGES, PAGE_SIZE, PROGRAM_MEMORY_SIZE};
+
+/// The shape a far jump takes once the assembler knows where everything is. Each flag adds one instruction in front of
+/// the final `BRN`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct JumpForm {
+    /// Whether an `LPB` is needed to get the target page into the page buffer.
+    pub load_page: bool,
+    /// Whether an `SSF` is needed to make the branch unconditional.
+    pub set_flag: bool,
+}
+
+impl JumpForm {
+    /// The number of instructions this form expands to.
+    pub fn len(self) -> usize {
+        1 + self.load_page as usize + self.set_flag as usize
+    }
+}
+
+/// The most instructions a jump to the next page can take up.
+const GUARD_SIZE: usize = 3;
+
+/// A jump inserted at the end of a page so that straight-line code can carry on at the start of the next one, instead
+/// of wrapping around to the start of the same page.
+#[derive(Copy, Clone, Debug)]
+pub struct Guard {
+    /// The index into program memory the jump is placed at.
+    pub start: usize,
+    /// The index into program memory the jump goes to, which is always the start of a page.
+    pub target: usize,
+    pub form: JumpForm,
+}
+
+/// What a directive does to where the nodes after it go, worked out before anything is placed.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Space {
+    /// Code continues at the given index, from `.page` or `.org`.
+    Placement(usize),
+    /// Code continues at the next multiple of the given number, from `.align`.
+    Alignment(usize),
+    /// The given number of bytes is taken up, from `.byte` or `.fill`.
+    Data(usize),
+}
+
+/// Where each node of a program ends up in program memory.
+pub struct Layout {
+    /// The index into program memory at which each node starts. Labels take up no space, so they share their index
+    /// with whatever follows them.
+    starts: Vec<usize>,
+    /// The form chosen for each node. Only meaningful for pseudo-instructions.
+    forms: Vec<JumpForm>,
+    /// The number of bytes each node takes up.
+    sizes: Vec<usize>,
+    /// The jump placed in front of each node to get there from the previous page, if any.
+    guards: Vec<Option<Guard>>,
+    /// Every index code is explicitly placed at, in order.
+    placements: Vec<usize>,
+}
+
+impl Layout {
+    /// Places every node of a program.
+    ///
+    /// # Arguments
+    ///
+    /// * `program`: The nodes produced by the parser.
+    /// * `forms`: The form of every far jump.
+    /// * `breaks`: Whether each node should be moved to the start of the next page.
+    /// * `spaces`: What each directive does to where the nodes after it go.
+    fn new(program: &[Node], forms: Vec<JumpForm>, breaks: &[bool], spaces: &[Option<Space>]) -> Layout {
+        let mut starts = Vec::with_capacity(program.len());
+        let mut sizes = Vec::with_capacity(program.len());
+        let mut guards = Vec::with_capacity(program.len());
+        let mut index = 0;
+        let mut previous_falls_through = false;
+
+        for (i, node) in program.iter().enumerate() {
+            let mut guard = None;
+            if breaks[i] && index % PAGE_SIZE != 0 {
+                let next_page = (index / PAGE_SIZE + 1) * PAGE_SIZE;
+                if previous_falls_through {
+                    guard = Some(Guard { start: index, target: next_page, form: JumpForm::default() });
+                }
+                index = next_page;
+            }
+            guards.push(guard);
+            starts.push(index);
+
+            let size = match (node, spaces[i]) {
+                (Node::Instruction { .. } | Node::PseudoInstruction { .. }, _) => size(node, forms[i]),
+                (_, Some(Space::Data(size))) => size,
+                _ => 0,
+            };
+            sizes.push(size);
+            index += size;
+
+            match (node, spaces[i]) {
+                (Node::Instruction { .. } | Node::PseudoInstruction { .. }, _) => {
+                    previous_falls_through = flow::falls_through(node);
+                }
+                // The bytes might well be instructions, so nothing stops execution from running through them.
+                (_, Some(Space::Data(_))) => previous_falls_through = true,
+                (_, Some(Space::Placement(placement))) => {
+                    index = placement;
+                    previous_falls_through = false;
+                }
+                (_, Some(Space::Alignment(alignment))) => {
+                    index = index.next_multiple_of(alignment);
+                    previous_falls_through = false;
+                }
+                _ => {}
+            }
+        }
+
+        let mut placements: Vec<usize> = spaces
+            .iter()
+            .filter_map(|space| match space {
+                Some(Space::Placement(placement)) => Some(*placement),
+                _ => None,
+            })
+            .collect();
+        placements.sort_unstable();
+
+        Layout { starts, forms, sizes, guards, placements }
+    }
+
+    /// The index into program memory at which the node at `node` starts.
+    pub fn start(&self, node: usize) -> usize {
+        self.starts[node]
+    }
+
+    /// The index at which the free space around `index` ends. This is either the end of its page, or the start of
+    /// code placed explicitly further along the same page.
+    pub fn limit(&self, index: usize) -> usize {
+        let page_end = (index / PAGE_SIZE + 1) * PAGE_SIZE;
+        self.placements
+            .iter()
+            .copied()
+            .find(|&placement| placement > index)
+            .map_or(page_end, |placement| placement.min(page_end))
+    }
+
+    /// The page the node at `node` starts on.
+    pub fn page(&self, node: usize) -> usize {
+        self.starts[node] / PAGE_SIZE
+    }
+
+    pub fn form(&self, node: usize) -> JumpForm {
+        self.forms[node]
+    }
+
+    /// The number of bytes the node at `node` takes up.
+    pub fn size(&self, node: usize) -> usize {
+        self.sizes[node]
+    }
+
+    /// The jump that carries execution from the previous page over to the node at `node`, if any.
+    pub fn guard(&self, node: usize) -> Option<Guard> {
+        self.guards[node]
+    }
+}
+
+/// Lays out a program and resolves its labels.
+///
+/// Far jumps start out in their shortest form and are grown whenever the assembler cannot prove that the page buffer
+/// or status flag already hold what the jump needs.
+///
+/// Since the program counter wraps around within a page, straight-line code can't simply run off the end of one, or
+/// into code placed further along the page with `.page` or `.org`. When it would, the code is split at the latest point
+/// where a jump to the next page can be inserted without disturbing the status flag, page buffer or a subroutine jump.
+/// If there is no such point, an error is reported instead.
+///
+/// Since growing a jump or splitting code can move labels onto different pages, this repeats until nothing changes.
+/// Nothing ever shrinks again, so this always finishes.
+///
+/// # Arguments
+///
+/// * `program`: The nodes produced by the parser.
+pub fn lay_out<'a>(program: &[Node<'a>]) -> Result<(Layout, SymbolTable<'a>), Vec<AssemblyError<'a>>> {
+    let constants = Constants::collect(program)?;
+    let spaces = spaces(program, &constants)?;
+
+    let mut forms = vec![JumpForm::default(); program.len()];
+    let mut breaks = vec![false; program.len()];
+
+    loop {
+        let mut layout = Layout::new(program, forms.clone(), &breaks, &spaces);
+        let symbols = SymbolTable::build(program, &layout, constants.clone())?;
+        let mut flows = flow::node_flows(program, &layout, &symbols);
+        let mut states = flow::known_states(&flows, &layout);
+        // A grown jump can tell us more about the code after it, so settle on the forms before moving anything.
+        while let Some((i, form)) = grow_jump(program, &layout, &symbols, &states) {
+            layout.forms[i] = form;
+            flows = flow::node_flows(program, &layout, &symbols);
+            states = flow::known_states(&flows, &layout);
+        }
+        if layout.forms != forms {
+            forms = layout.forms;
+            continue;
+        }
+
+        let live = flow::live_states(&flows);
+        let mut errors = check_subroutine_jumps(program, &layout, &symbols, &states);
+        let mut changed = false;
+
+        for i in 1..program.len() {
+            let Some(guard) = &mut layout.guards[i] else {
+                continue;
+            };
+            let (state, _) = states[i - 1].exits(&flows[i - 1]);
+            guard.form = JumpForm {
+                load_page: state.page_buffer != Some((guard.target / PAGE_SIZE) as u8),
+                set_flag: state.status_flag != Some(true),
+            };
+        }
+
+        for n in 0..program.len() {
+            if !crosses_page(program, &layout, n) {
+                continue;
+            }
+
+            match find_break(program, &layout, &flows, &states, &live, n) {
+                Some(k) => {
+                    breaks[k] = true;
+                    changed = true;
+                    // Everything after the break moves, so the rest has to be checked again.
+                    break;
+                }
+                None => {
+                    let page = layout.start(n) / PAGE_SIZE;
+                    errors.push(AssemblyError {
+                        location: program[n].location(),
+                        kind: AssemblyErrorKind::PageOverflow { page },
+                        help: Some(
+                            "No jump to the next page could be inserted before this point without changing what the \
+                            program does. Place the code explicitly with .page or .org, or end the block with jmp"
+                                .to_owned(),
+                        ),
+                    });
+                }
+            }
+        }
+
+        if !changed {
+            return if errors.is_empty() {
+                Ok((layout, symbols))
+            } else {
+                Err(errors)
+            };
+        }
+    }
+}
+
+/// Finds the first far jump that can't rely on the page buffer or status flag already holding what it needs, and
+/// returns its index along with the form it has to grow to.
+///
+/// Only one jump is grown at a time, since what a grown jump loads can spare the jumps after it from growing too.
+fn grow_jump(
+    program: &[Node],
+    layout: &Layout,
+    symbols: &SymbolTable,
+    states: &[KnownState],
+) -> Option<(usize, JumpForm)> {
+    for (i, node) in program.iter().enumerate() {
+        let Node::PseudoInstruction { kind, arguments, .. } = node else {
+            continue;
+        };
+        let Some(target) = jump_target(arguments, symbols, layout.page(i)) else {
+            continue;
+        };
+
+        let current = layout.form(i);
+        let form = JumpForm {
+            load_page: current.load_page || states[i].page_buffer != Some(target.page),
+            set_flag: current.set_flag || (*kind == PseudoInstructionKind::Jmp && states[i].status_flag != Some(true)),
+        };
+        if form != current {
+            return Some((i, form));
+        }
+    }
+    None
+}
+
+fn check_subroutine_jumps<'a>(
+    program: &[Node<'a>],
+    layout: &Layout,
+    symbols: &SymbolTable,
+    states: &[KnownState],
+) -> Vec<AssemblyError<'a>> {
+    let mut errors = vec![];
+    for (i, node) in program.iter().enumerate() {
+        let Node::PseudoInstruction { kind, arguments, location } = node else {
+            continue;
+        };
+
+        if jump_target(arguments, symbols, layout.page(i)).is_some() && states[i].subroutine_jump == Some(true) {
+            errors.push(AssemblyError {
+                location: *location,
+                kind: AssemblyErrorKind::JumpInSubroutineMode { instruction: *kind },
+                help: Some("Reset the subroutine jump flag with rsj first".to_owned()),
+            });
+        }
+    }
+    errors
+}
+
+/// Returns whether the node at `n` runs past the end of the free space it is in, either by not fitting in it or by
+/// falling through into code that would have to continue on the next page.
+fn crosses_page(program: &[Node], layout: &Layout, n: usize) -> bool {
+    let node = &program[n];
+    if !matches!(node, Node::Instruction { .. } | Node::PseudoInstruction { .. }) {
+        return false;
+    }
+
+    let start = layout.start(n);
+    let end = start + layout.size(n);
+    let limit = layout.limit(start);
+    if end > limit {
+        return true;
+    }
+
+    let continues = program[n + 1..]
+        .iter()
+        .take_while(|next| !is_placement(next))
+        .any(|next| matches!(next, Node::Instruction { .. } | Node::PseudoInstruction { .. }));
+    end == limit && flow::falls_through(node) && continues
+}
+
+/// Finds the latest point on the same page before the node at `n` where the code can be split, moving everything from
+/// there on to the next page. Returns the index of the first node to move.
+fn find_break(
+    program: &[Node],
+    layout: &Layout,
+    flows: &[NodeFlow],
+    states: &[KnownState],
+    live: &[Live],
+    n: usize,
+) -> Option<usize> {
+    let page = layout.page(n);
+    let limit = layout.limit(layout.start(n));
+
+    for previous in (0..n).rev() {
+        let node = &program[previous];
+        match node {
+            _ if is_placement(node) => return None,
+            Node::Instruction { .. } | Node::PseudoInstruction { .. } => {}
+            _ => continue,
+        }
+        if layout.page(previous) != page {
+            return None;
+        }
+
+        let k = previous + 1;
+        // Nothing runs into the next node, so it can be moved without a jump.
+        if !flow::falls_through(node) {
+            return Some(k);
+        }
+
+        let (state, _) = states[previous].exits(&flows[previous]);
+        let live = live[k];
+        let fits = layout.start(k) + GUARD_SIZE <= limit;
+        let safe = state.subroutine_jump == Some(false)
+            && (!live.status_flag || state.status_flag == Some(true))
+            && (!live.page_buffer || state.page_buffer == Some(page as u8 + 1));
+        if fits && safe {
+            return Some(k);
+        }
+    }
+
+    None
+}
+
+/// The number of instructions a node takes up, if it is an instruction.
+fn size(node: &Node, form: JumpForm) -> usize {
+    match node {
+        Node::Instruction { .. } => 1,
+        Node::PseudoInstruction { .. } => form.len(),
+        _ => 0,
+    }
+}
+
+fn is_placement(node: &Node) -> bool {
+    matches!(node, Node::Directive { kind, .. } if kind.is_placement())
+}
+
+/// Works out what each placement and data directive does to where the nodes after it go.
+fn spaces<'a>(program: &[Node<'a>], constants: &Constants<'a>) -> Result<Vec<Option<Space>>, Vec<AssemblyError<'a>>> {
+    let mut spaces = vec![None; program.len()];
+    let mut errors = vec![];
+
+    for (i, node) in program.iter().enumerate() {
+        let Node::Directive { kind, arguments, location } = node else {
+            continue;
+        };
+
+        let help = match kind {
+            DirectiveKind::Page => format!("{} takes a page number below {NUM_PAGES}", kind.name()),
+            DirectiveKind::Org => format!("{} takes an index below {PROGRAM_MEMORY_SIZE}", kind.name()),
+            DirectiveKind::Align => format!("{} takes a power of two up to {PROGRAM_MEMORY_SIZE}", kind.name()),
+            DirectiveKind::Byte => format!("{} takes the values of the bytes to place", kind.name()),
+            DirectiveKind::Fill => format!("{} takes a count up to {PROGRAM_MEMORY_SIZE} and a value", kind.name()),
+            _ => continue,
+        };
+        let invalid = AssemblyError {
+            location: *location,
+            kind: AssemblyErrorKind::InvalidDirectiveArguments { directive: *kind },
+            help: Some(help),
+        };
+
+        if arguments.iter().any(|argument| !argument.is_expression()) {
+            errors.push(invalid);
+            continue;
+        }
+        // The values of the bytes are only needed once labels are known, but how many there are is needed now.
+        let value = match (kind, arguments.as_slice()) {
+            (DirectiveKind::Byte, [_, ..]) => {
+                spaces[i] = Some(Space::Data(arguments.len()));
+                continue;
+            }
+            (DirectiveKind::Byte, []) => {
+                errors.push(invalid);
+                continue;
+            }
+            (DirectiveKind::Fill, [count, _]) => count,
+            (DirectiveKind::Fill, _) => {
+                errors.push(invalid);
+                continue;
+            }
+            (_, [value]) => value,
+            _ => {
+                errors.push(invalid);
+                continue;
+            }
+        };
+
+        let value = match expression::evaluate_constant(value, constants) {
+            Ok(value) => value,
+            Err(err) => {
+                errors.push(err);
+                continue;
+            }
+        };
+        let size = PROGRAM_MEMORY_SIZE as i64;
+        spaces[i] = match kind {
+            DirectiveKind::Page if (0..NUM_PAGES as i64).contains(&value) => {
+                Some(Space::Placement(value as usize * PAGE_SIZE))
+            }
+            DirectiveKind::Org if (0..size).contains(&value) => Some(Space::Placement(value as usize)),
+            DirectiveKind::Align if (1..=size).contains(&value) && (value as usize).is_power_of_two() => {
+                Some(Space::Alignment(value as usize))
+            }
+            DirectiveKind::Fill if (0..=size).contains(&value) => Some(Space::Data(value as usize)),
+            _ => {
+                errors.push(invalid);
+                continue;
+            }
+        };
+    }
+
+    if errors.is_empty() {
+        Ok(spaces)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Gets the address a pseudo-instruction on `page` jumps to, if its arguments are valid.
+pub fn jump_target(arguments: &[Node], symbols: &SymbolTable, page: usize) -> Option<Address> {
+    match arguments {
+        [Node::LabelReference { name, .. }] => symbols.resolve(*name, page).map(|symbol| symbol.address),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    /// Returns the size of every far jump in the program, in order.
+    fn jump_sizes(program: &str) -> Vec<usize> {
+        let mut lexer = Lexer::new(program, 0);
+        let mut parser = Parser::new(lexer.iter());
+        let nodes = parser.parse().ok().unwrap();
+        let (layout, _) = lay_out(&nodes).ok().unwrap();
+
+        nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| matches!(node, Node::PseudoInstruction { .. }))
+            .map(|(i, _)| layout.form(i).len())
+            .collect()
+    }
+
+    #[test]
+    fn same_page_jump_skips_lpb() {
+        // The page buffer starts out as 0, so there is no need to load it.
+        assert_eq!(jump_sizes("jmp end\nend:\nnop\n"), vec![2]);
+        assert_eq!(jump_sizes("cmp x\njmp_if end\nend:\nnop\n"), vec![1]);
+    }
+
+    #[test]
+    fn far_jump_loads_page() {
+        assert_eq!(jump_sizes("jmp end\n.page 1\nend:\nnop\n"), vec![3]);
+    }
+
+    #[test]
+    fn data_forgets_what_is_known() {
+        // 0x13 is `lpb 3`, which runs like any other instruction.
+        assert_eq!(jump_sizes("target:\nnop\n.byte 0x13\njmp target\n"), vec![3]);
+        assert_eq!(jump_sizes("target:\nnop\n.fill 1 0x13\njmp target\n"), vec![3]);
+    }
+
+    #[test]
+    fn known_page_buffer_is_reused() {
+        let program = "cmp x\njmp_if first\njmp second\n.page 1\nfirst:\nnop\nsecond:\nnop\n";
+        assert_eq!(jump_sizes(program), vec![2, 2]);
+    }
+
+    #[test]
+    fn set_status_flag_is_reused() {
+        assert_eq!(jump_sizes("ssf\njmp end\nend:\nnop\n"), vec![1]);
+    }
+
+    #[test]
+    fn labels_keep_state_every_path_agrees_on() {
+        assert_eq!(jump_sizes("ssf\nloop:\njmp loop\n"), vec![1]);
+    }
+
+    #[test]
+    fn labels_forget_state_paths_disagree_on() {
+        // Nothing is known about code after a placement directive, so the loop can't rely on the first jump.
+        assert_eq!(jump_sizes("jmp loop\n.page 1\nnop\nloop:\njmp loop\n"), vec![3, 3]);
+    }
+
+    #[test]
+    fn growing_jump_moves_target_to_next_page() {
+        // A bare BRN would leave the target at the end of page 0, but adding the SSF pushes it onto page 1.
+        let program = format!("jmp end\n{}end:\nnop\n", "nop\n".repeat(62));
+        assert_eq!(jump_sizes(&program), vec![3]);
+    }
+
+    #[test]
+    fn jump_in_subroutine_mode() {
+        let mut lexer = Lexer::new("ssj\njmp end\nend:\nnop\n", 0);
+        let mut parser = Parser::new(lexer.iter());
+        let nodes = parser.parse().ok().unwrap();
+        let errors = lay_out(&nodes).err().unwrap();
+        assert!(matches!(errors[0].kind, AssemblyErrorKind::JumpInSubroutineMode { .. }));
+    }
+
+    fn lay_out_program(program: &str) -> Result<(Vec<Node<'_>>, Layout), Vec<AssemblyErrorKind<'_>>> {
+        let mut lexer = Lexer::new(program, 0);
+        let mut parser = Parser::new(lexer.iter());
+        let nodes = parser.parse().ok().unwrap();
+        let (layout, _) = lay_out(&nodes).map_err(|errors| errors.into_iter().map(|e| e.kind).collect::<Vec<_>>())?;
+        Ok((nodes, layout))
+    }
+
+    #[test]
+    fn guard_carries_code_to_next_page() {
+        let program = "nop\n".repeat(70);
+        let (_, layout) = lay_out_program(&program).ok().unwrap();
+        // The guard takes up the last three instructions of page 0.
+        let guard = layout.guard(61).unwrap();
+        assert_eq!((guard.start, guard.target, guard.form.len()), (61, 64, 3));
+        assert_eq!(layout.start(61), 64);
+        assert!((0..70).filter(|&i| i != 61).all(|i| layout.guard(i).is_none()));
+    }
+
+    #[test]
+    fn code_after_jump_moves_without_guard() {
+        let program = format!("{}jmp end\n{}end:\nnop\n", "nop\n".repeat(40), "nop\n".repeat(30));
+        let (_, layout) = lay_out_program(&program).ok().unwrap();
+        assert!(layout.guard(41).is_none());
+        assert_eq!(layout.start(41), 64);
+    }
+
+    #[test]
+    fn placement_directives() {
+        let (_, layout) = lay_out_program(".page 2\nnop\n.org 200\nnop\n").ok().unwrap();
+        assert_eq!((layout.start(1), layout.start(3)), (128, 200));
+    }
+
+    #[test]
+    fn code_stops_before_explicitly_placed_code() {
+        let program = format!("{}.org 10\nnop\n", "inc x\n".repeat(20));
+        let (_, layout) = lay_out_program(&program).ok().unwrap();
+        assert_eq!(layout.guard(7).map(|guard| guard.start), Some(7));
+        assert_eq!(layout.start(7), 64);
+    }
+
+    #[test]
+    fn invalid_placement() {
+        let errors = lay_out_program(".page 16\n.org\n").err().unwrap();
+        assert!(matches!(errors.as_slice(), [
+            AssemblyErrorKind::InvalidDirectiveArguments { directive: DirectiveKind::Page },
+            AssemblyErrorKind::InvalidDirectiveArguments { directive: DirectiveKind::Org },
+        ]));
+    }
+
+    #[test]
+    fn page_overflow_while_status_flag_in_use() {
+        // The comparison's result is still needed after the page boundary, and a guard would overwrite it.
+        let program = format!("cmp x\n{}jmp_if end\nend:\nnop\n", "nop\n".repeat(70));
+        let errors = lay_out_program(&program).err().unwrap();
+        assert!(matches!(errors.as_slice(), [AssemblyErrorKind::PageOverflow { page: 0 }]));
+    }
+}
@@ -1,66 +1,179 @@
 use crate::location::Location;
+use common::instruction::Instruction;
+use instruction_set_gen::make_mnemonics;
+
+// Generated from instructions.txt, so the assembler knows exactly the instructions `common::instruction` does.
+make_mnemonics!();
+
+/// Instructions that only exist in the assembler. Each one expands into a sequence of real instructions.
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum PseudoInstructionKind {
+    /// Unconditional jump to a label on any page.
+    Jmp,
+    /// Jump to a label on any page if the status flag is set.
+    JmpIf,
+    /// Load an address in working memory into X (high nibble) and Y (low nibble).
+    Ldxy,
+}
 
-#[derive(Eq, PartialEq, Copy, Clone)]
-pub enum InstructionKind {
-    NOP,
-    STR,
-    LOD,
-    LDI,
-    INC,
-    DEC,
-    MOV,
-    INP,
-    OUT,
-    SEP,
-    RSP,
-    ADD,
-    SUB,
-    BOR,
-    AND,
-    CMP,
-    GRT,
-    LES,
-    BRN,
-    SSJ,
-    RSJ,
-    RET,
-    SSF,
-    RSF,
+impl PseudoInstructionKind {
+    pub const ALL: [PseudoInstructionKind; 3] =
+        [PseudoInstructionKind::Jmp, PseudoInstructionKind::JmpIf, PseudoInstructionKind::Ldxy];
+
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "jmp" => Some(PseudoInstructionKind::Jmp),
+            "jmp_if" => Some(PseudoInstructionKind::JmpIf),
+            "ldxy" => Some(PseudoInstructionKind::Ldxy),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PseudoInstructionKind::Jmp => "jmp",
+            PseudoInstructionKind::JmpIf => "jmp_if",
+            PseudoInstructionKind::Ldxy => "ldxy",
+        }
+    }
 }
 
-impl InstructionKind {
+/// Assembler directives. These are written with a leading `.` and control how the program is assembled rather than
+/// turning into instructions themselves.
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum DirectiveKind {
+    /// Continues placing code at the start of the given page.
+    Page,
+    /// Continues placing code at the given index into program memory.
+    Org,
+    /// Starts a subroutine with the given name.
+    Sub,
+    /// Ends the current subroutine.
+    EndSub,
+    /// Defines a named constant.
+    Equ,
+    /// Starts the definition of a macro with the given name and parameters.
+    Macro,
+    /// Ends the definition of the current macro.
+    EndMacro,
+    /// Pulls in the contents of another source file.
+    Include,
+    /// Writes a string to the console, one character at a time, using the subroutine set with `.printer`.
+    Print,
+    /// Sets the subroutine `.print` calls to write a character.
+    Printer,
+    /// Places the given bytes into program memory as they are.
+    Byte,
+    /// Places the given number of copies of a byte into program memory.
+    Fill,
+    /// Continues placing code at the next index that is a multiple of the given number.
+    Align,
+    /// Reserves space in working memory for a variable with the given name and size.
+    Var,
+    /// Only assembles what follows if the given expression isn't zero.
+    If,
+    /// Only assembles what follows if the given name is defined as a constant.
+    IfDef,
+    /// Assembles what follows instead if nothing before it in the same `.if` was, and the given expression isn't zero.
+    Elif,
+    /// Assembles what follows instead if nothing before it in the same `.if` was.
+    Else,
+    /// Ends a `.if`.
+    EndIf,
+}
+
+impl DirectiveKind {
+    pub const ALL: [DirectiveKind; 19] = [
+        DirectiveKind::Page,
+        DirectiveKind::Org,
+        DirectiveKind::Sub,
+        DirectiveKind::EndSub,
+        DirectiveKind::Equ,
+        DirectiveKind::Macro,
+        DirectiveKind::EndMacro,
+        DirectiveKind::Include,
+        DirectiveKind::Print,
+        DirectiveKind::Printer,
+        DirectiveKind::Byte,
+        DirectiveKind::Fill,
+        DirectiveKind::Align,
+        DirectiveKind::Var,
+        DirectiveKind::If,
+        DirectiveKind::IfDef,
+        DirectiveKind::Elif,
+        DirectiveKind::Else,
+        DirectiveKind::EndIf,
+    ];
+
     pub fn from_str(s: &str) -> Option<Self> {
         match s {
-            "nop" => Some(InstructionKind::NOP),
-            "str" => Some(InstructionKind::STR),
-            "lod" => Some(InstructionKind::LOD),
-            "ldi" => Some(InstructionKind::LDI),
-            "inc" => Some(InstructionKind::INC),
-            "dec" => Some(InstructionKind::DEC),
-            "mov" => Some(InstructionKind::MOV),
-            "inp" => Some(InstructionKind::INP),
-            "out" => Some(InstructionKind::OUT),
-            "sep" => Some(InstructionKind::SEP),
-            "rsp" => Some(InstructionKind::RSP),
-            "add" => Some(InstructionKind::ADD),
-            "sub" => Some(InstructionKind::SUB),
-            "bor" => Some(InstructionKind::BOR),
-            "and" => Some(InstructionKind::AND),
-            "cmp" => Some(InstructionKind::CMP),
-            "grt" => Some(InstructionKind::GRT),
-            "les" => Some(InstructionKind::LES),
-            "brn" => Some(InstructionKind::BRN),
-            "ssj" => Some(InstructionKind::SSJ),
-            "rsj" => Some(InstructionKind::RSJ),
-            "ret" => Some(InstructionKind::RET),
-            "ssf" => Some(InstructionKind::SSF),
-            "rsf" => Some(InstructionKind::RSF),
+            ".page" => Some(DirectiveKind::Page),
+            ".org" => Some(DirectiveKind::Org),
+            ".sub" => Some(DirectiveKind::Sub),
+            ".endsub" => Some(DirectiveKind::EndSub),
+            ".equ" => Some(DirectiveKind::Equ),
+            ".macro" => Some(DirectiveKind::Macro),
+            ".endm" => Some(DirectiveKind::EndMacro),
+            ".include" => Some(DirectiveKind::Include),
+            ".print" => Some(DirectiveKind::Print),
+            ".printer" => Some(DirectiveKind::Printer),
+            ".byte" => Some(DirectiveKind::Byte),
+            ".fill" => Some(DirectiveKind::Fill),
+            ".align" => Some(DirectiveKind::Align),
+            ".var" => Some(DirectiveKind::Var),
+            ".if" => Some(DirectiveKind::If),
+            ".ifdef" => Some(DirectiveKind::IfDef),
+            ".elif" => Some(DirectiveKind::Elif),
+            ".else" => Some(DirectiveKind::Else),
+            ".endif" => Some(DirectiveKind::EndIf),
             _ => None,
         }
     }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DirectiveKind::Page => ".page",
+            DirectiveKind::Org => ".org",
+            DirectiveKind::Sub => ".sub",
+            DirectiveKind::EndSub => ".endsub",
+            DirectiveKind::Equ => ".equ",
+            DirectiveKind::Macro => ".macro",
+            DirectiveKind::EndMacro => ".endm",
+            DirectiveKind::Include => ".include",
+            DirectiveKind::Print => ".print",
+            DirectiveKind::Printer => ".printer",
+            DirectiveKind::Byte => ".byte",
+            DirectiveKind::Fill => ".fill",
+            DirectiveKind::Align => ".align",
+            DirectiveKind::Var => ".var",
+            DirectiveKind::If => ".if",
+            DirectiveKind::IfDef => ".ifdef",
+            DirectiveKind::Elif => ".elif",
+            DirectiveKind::Else => ".else",
+            DirectiveKind::EndIf => ".endif",
+        }
+    }
+
+    /// Whether the directive moves on to a different part of program memory.
+    pub fn is_placement(self) -> bool {
+        matches!(self, DirectiveKind::Page | DirectiveKind::Org | DirectiveKind::Align)
+    }
+
+    /// Whether the directive is part of a `.if` block.
+    pub fn is_conditional(self) -> bool {
+        matches!(
+            self,
+            DirectiveKind::If | DirectiveKind::IfDef | DirectiveKind::Elif | DirectiveKind::Else | DirectiveKind::EndIf
+        )
+    }
+
+    /// Whether the directive places raw bytes into program memory.
+    pub fn is_data(self) -> bool {
+        matches!(self, DirectiveKind::Byte | DirectiveKind::Fill)
+    }
 }
 
-#[derive(Eq, PartialEq, Copy, Clone)]
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
 pub enum Register {
     A,
     X,
@@ -69,6 +182,9 @@ pub enum Register {
 }
 
 impl Register {
+    /// Every register, in the order of their ids.
+    pub const ALL: [Register; 4] = [Register::A, Register::X, Register::Y, Register::Z];
+
     pub fn from_str(s: &str) -> Option<Self> {
         match s {
             "a" => Some(Register::A),
@@ -78,6 +194,15 @@ impl Register {
             _ => None,
         }
     }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Register::A => "a",
+            Register::X => "x",
+            Register::Y => "y",
+            Register::Z => "z",
+        }
+    }
 }
 
 #[derive(Eq, PartialEq, Copy, Clone)]
@@ -85,16 +210,30 @@ pub enum NumberLiteralKind {
     Decimal,
     Hex,
     Binary,
+    /// A character in single quotes, standing for its ASCII code.
+    Character,
 }
 
 #[derive(Eq, PartialEq, Copy, Clone)]
 pub enum TokenKind {
     Newline,
     Colon,
+    Comma,
     LabelIdentifier,
     Instruction { kind: InstructionKind },
+    PseudoInstruction { kind: PseudoInstructionKind },
+    Directive { kind: DirectiveKind },
     NumberLiteral { kind: NumberLiteralKind },
-    RegisterLiteral { register: Register }
+    RegisterLiteral { register: Register },
+    StringLiteral,
+    Plus,
+    Minus,
+    Ampersand,
+    Pipe,
+    ShiftLeft,
+    ShiftRight,
+    OpenParen,
+    CloseParen,
 }
 
 #[derive(Clone)]
@@ -117,16 +256,17 @@ impl<'a> Lexer<'a> {
     /// # Arguments
     ///
     /// * `program`: The program, containing only ASCII characters.
-    pub fn new(program: &'a str) -> Self {
+    /// * `file`: The source file the program comes from.
+    pub fn new(program: &'a str, file: u32) -> Self {
         assert!(program.is_ascii());
 
         Lexer {
             program,
-            location: Location::start(),
+            location: Location::start(file),
         }
     }
 
-    pub fn iter(&'a mut self) -> Iter {
+    pub fn iter(&mut self) -> Iter<'_, 'a> {
         Iter {
             lexer: self
         }
@@ -134,22 +274,132 @@ impl<'a> Lexer<'a> {
 
     pub fn next_token(&mut self) -> Option<Token<'a>> {
         while let Some(char) = self.current_char() {
-            match char {
-                c if let Some(kind) = single_char_token(c) => return self.handle_single(kind),
-                c if c.is_ascii_digit() => return self.handle_number(),
-                c if c.is_ascii_whitespace() => self.advance(),
-                ';' => self.handle_comment(),
-                _ => return self.handle_ident()
+            let token = match char {
+                c if let Some(kind) = single_char_token(c) => self.handle_single(kind),
+                c if c.is_ascii_digit() => self.handle_number(),
+                '<' | '>' => self.handle_shift(),
+                '"' => self.handle_string(),
+                '\'' => self.handle_character(),
+                c if c.is_ascii_whitespace() => {
+                    self.advance();
+                    continue;
+                }
+                ';' => {
+                    self.handle_comment();
+                    continue;
+                }
+                _ => self.handle_ident()
             };
+
+            // Every token spans exactly the bytes of its text.
+            return token.map(|token| Token {
+                location: Location { length: token.text.len() as u32, ..token.location },
+                ..token
+            });
         };
 
         None
     }
 
     fn handle_single(&mut self, kind: TokenKind) -> Option<Token<'a>> {
+        let start = self.location;
         let current = self.current_char_as_str()?;
         self.advance();
-        Some(Token { kind, location: self.location, text: current })
+        Some(Token { kind, location: start, text: current })
+    }
+
+    /// Handles `<<` and `>>`. A lone `<` or `>` is not a valid token, so it is passed on as an identifier for the
+    /// parser to reject.
+    fn handle_shift(&mut self) -> Option<Token<'a>> {
+        let start = self.location;
+        let first = self.current_char()?;
+        self.advance();
+
+        let kind = match (first, self.current_char()) {
+            ('<', Some('<')) => TokenKind::ShiftLeft,
+            ('>', Some('>')) => TokenKind::ShiftRight,
+            _ => TokenKind::LabelIdentifier,
+        };
+        if kind != TokenKind::LabelIdentifier {
+            self.advance();
+        }
+
+        Some(Token {
+            kind,
+            location: start,
+            text: &self.program[start.index as usize..self.location.index as usize],
+        })
+    }
+
+    /// Handles a string literal, which runs up to the next `"` on the same line. The token's text includes the quotes.
+    /// A string without a closing quote is not a valid token, so it is passed on as an identifier for the parser to
+    /// reject.
+    fn handle_string(&mut self) -> Option<Token<'a>> {
+        let start = self.location;
+        self.advance();
+
+        while let Some(current) = self.current_char() {
+            if current == '"' || current == '\n' {
+                break;
+            }
+            self.advance()
+        }
+
+        let kind = match self.current_char() {
+            Some('"') => {
+                self.advance();
+                TokenKind::StringLiteral
+            }
+            _ => TokenKind::LabelIdentifier,
+        };
+
+        Some(Token {
+            kind,
+            location: start,
+            text: &self.program[start.index as usize..self.location.index as usize],
+        })
+    }
+
+    /// Handles a character literal like `'H'` or `'\n'`. The token's text includes the quotes. Like with strings, a
+    /// malformed literal is passed on as an identifier for the parser to reject.
+    fn handle_character(&mut self) -> Option<Token<'a>> {
+        let start = self.location;
+        self.advance();
+
+        while let Some(current) = self.current_char() {
+            if current == '\'' || current == '\n' {
+                break;
+            }
+            self.advance();
+            // The escaped character might be a quote.
+            if current == '\\' && self.current_char() != Some('\n') {
+                self.advance();
+            }
+        }
+
+        let inner = &self.program[start.index as usize + 1..self.location.index as usize];
+        let valid = match inner.as_bytes() {
+            [b'\\', escaped] => unescape(*escaped as char).is_some(),
+            [c] => *c != b'\\',
+            _ => false,
+        };
+        let kind = match self.current_char() {
+            Some('\'') => {
+                self.advance();
+                if valid {
+                    TokenKind::NumberLiteral { kind: NumberLiteralKind::Character }
+                } else {
+                    TokenKind::LabelIdentifier
+                }
+            }
+            _ => TokenKind::LabelIdentifier,
+        };
+
+        Some(Token {
+            kind,
+            location: start,
+            text: &self.program[start.index as usize..self.location.index as usize],
+        })
     }
 
     fn handle_number(&mut self) -> Option<Token<'a>> {
@@ -162,6 +412,12 @@ impl<'a> Lexer<'a> {
             _ => NumberLiteralKind::Decimal
         };
 
+        // `1b` and `1f` refer to the closest anonymous label `1:` before or after them.
+        let digits = num.trim_end_matches(['b', 'f']);
+        if num.len() == digits.len() + 1 && digits.chars().all(|c| c.is_ascii_digit()) {
+            return Some(Token { kind: TokenKind::LabelIdentifier, location: start, text: num });
+        }
+
         Some(Token {
             kind: TokenKind::NumberLiteral { kind },
             location: start,
@@ -175,6 +431,8 @@ impl<'a> Lexer<'a> {
 
         let kind = match str {
             s if let Some(inst) = InstructionKind::from_str(s) => TokenKind::Instruction { kind: inst },
+            s if let Some(inst) = PseudoInstructionKind::from_str(s) => TokenKind::PseudoInstruction { kind: inst },
+            s if let Some(directive) = DirectiveKind::from_str(s) => TokenKind::Directive { kind: directive },
             s if let Some(reg) = Register::from_str(s) => TokenKind::RegisterLiteral { register: reg },
             _ => TokenKind::LabelIdentifier
         };
@@ -209,7 +467,7 @@ impl<'a> Lexer<'a> {
             self.advance()
         }
 
-        let result = self.program.get(start.index..self.location.index);
+        let result = self.program.get(start.index as usize..self.location.index as usize);
         assert!(!result.is_some_and(|val| val.is_empty()));
 
         result
@@ -221,42 +479,62 @@ impl<'a> Lexer<'a> {
 
         if let Some('\n') = current {
             self.location = self.location.advance_line()
-        } else if let Some(_) = current {
+        } else if current.is_some() {
             self.location = self.location.advance_col()
         }
     }
 
     /// Returns the current character if the lexer has not finished. Returns None if it has.
     fn current_char(&self) -> Option<char> {
-        self.program.as_bytes().get(self.location.index).map(|&c| c as char)
+        self.program.as_bytes().get(self.location.index as usize).map(|&c| c as char)
     }
 
     // Hack of the century
     /// Does roughly the same thing as current_char, but returns a string slice instead to make
     /// constructing tokens easier.
     fn current_char_as_str(&self) -> Option<&'a str> {
-        let index = self.location.index;
+        let index = self.location.index as usize;
         self.program.get(index..=index)
     }
 }
 
 fn is_sequence_terminator(c: char) -> bool {
-    single_char_token(c).is_some() || c.is_ascii_whitespace() || c == ';'
+    single_char_token(c).is_some() || c.is_ascii_whitespace() || matches!(c, ';' | '<' | '>' | '"' | '\'')
+}
+
+/// Gets the ASCII code of the character an escape sequence like `\n` stands for, given the character after the
+/// backslash. Used in both character and string literals.
+pub fn unescape(c: char) -> Option<u8> {
+    match c {
+        'n' => Some(b'\n'),
+        't' => Some(b'\t'),
+        'r' => Some(b'\r'),
+        '0' => Some(0),
+        '\\' | '\'' | '"' => Some(c as u8),
+        _ => None,
+    }
 }
 
 fn single_char_token(c: char) -> Option<TokenKind> {
     match c {
         '\n' => Some(TokenKind::Newline),
         ':' => Some(TokenKind::Colon),
+        ',' => Some(TokenKind::Comma),
+        '+' => Some(TokenKind::Plus),
+        '-' => Some(TokenKind::Minus),
+        '&' => Some(TokenKind::Ampersand),
+        '|' => Some(TokenKind::Pipe),
+        '(' => Some(TokenKind::OpenParen),
+        ')' => Some(TokenKind::CloseParen),
         _ => None
     }
 }
 
-pub struct Iter<'a> {
-    lexer: &'a mut Lexer<'a>
+pub struct Iter<'l, 'a> {
+    lexer: &'l mut Lexer<'a>
 }
 
-impl<'a> Iterator for Iter<'a> {
+impl<'a> Iterator for Iter<'_, 'a> {
     type Item = Token<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
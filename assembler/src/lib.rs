@@ -0,0 +1,225 @@
+#![feature(generic_const_exprs)]
+#![allow(incomplete_features)]
+
+mod codegen;
+mod conditionals;
+mod debug_info;
+mod diagnostics;
+mod disassembler;
+mod error;
+mod expression;
+mod flow;
+mod formats;
+mod formatter;
+mod json;
+mod language_server;
+mod layout;
+mod lexer;
+mod listing;
+mod location;
+mod macros;
+mod operands;
+mod optimizer;
+mod parser;
+mod routines;
+mod sources;
+mod suggestions;
+mod symbols;
+mod variables;
+mod warnings;
+
+pub use crate::diagnostics::{Diagnostic, Severity, Snippet};
+pub use crate::disassembler::disassemble;
+pub use crate::formats::Format;
+pub use crate::formatter::format;
+pub use crate::language_server::serve;
+pub use crate::location::Location;
+
+use crate::error::AssemblyError;
+use crate::macros::Expansion;
+use crate::sources::Sources;
+use common::architecture::PROGRAM_MEMORY_SIZE;
+use common::debug_info::DebugInfo;
+use std::path::PathBuf;
+
+/// How to assemble a program.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// The path the program was read from. Files it includes are looked for next to it, and diagnostics and the debug
+    /// information refer to it.
+    pub path: PathBuf,
+    /// The extra directories to look for included files in, in order.
+    pub include_paths: Vec<PathBuf>,
+    /// Constants defined as if by `.equ name value` at the start of the program, in order.
+    pub definitions: Vec<(String, String)>,
+    /// Whether to take out instructions that can be shown to change nothing, or to never run.
+    pub optimize: bool,
+}
+
+/// Everything the assembler produces for a program that assembled.
+#[derive(Clone, Debug)]
+pub struct AssembledProgram {
+    /// The contents of program memory, ready to be loaded by the emulator.
+    pub image: [u8; PROGRAM_MEMORY_SIZE],
+    /// Where every instruction came from, and every label and constant.
+    pub debug_info: DebugInfo,
+    /// Every instruction with where it was placed, its encoding and its source line, followed by how full each page
+    /// is.
+    pub listing: String,
+    /// Things in the program that are allowed but probably a mistake.
+    pub warnings: Vec<Diagnostic>,
+    /// What the optimizer took out of the program and why, one line each, followed by how many bytes that saved.
+    /// Empty unless `Options::optimize` is set.
+    pub optimizations: String,
+}
+
+/// Assembles a program into an image of program memory.
+///
+/// Included files are read from disk, relative to `options.path` and the include paths. If the program can't be
+/// assembled, every error found is returned, along with any warnings.
+///
+/// # Arguments
+///
+/// * `source`: The text of the program.
+/// * `options`: Where the program came from and how to assemble it.
+pub fn assemble(source: &str, options: &Options) -> Result<AssembledProgram, Vec<Diagnostic>> {
+    let mut sources = Sources::new(options.path.clone(), source.to_owned());
+    for (name, value) in &options.definitions {
+        sources.define(name, value);
+    }
+    if let Err(errors) = sources.load_includes(&options.include_paths) {
+        return Err(diagnose(&sources, errors, &[]));
+    }
+
+    let program = match sources.parse() {
+        Ok(program) => program,
+        Err(errors) => {
+            return Err(errors.into_iter().map(|error| Diagnostic::from_parse_error(error, &sources)).collect())
+        }
+    };
+
+    let warnings = diagnose(&sources, warnings::check(&program), &[]);
+
+    let program = match conditionals::resolve(&program) {
+        Ok(program) => program,
+        Err(errors) => return Err(fail(warnings, diagnose(&sources, errors, &[]))),
+    };
+
+    let mut expansions = vec![];
+    let mut program = match macros::expand(&program, &mut expansions) {
+        Ok(program) => program,
+        Err(errors) => return Err(fail(warnings, diagnose(&sources, errors, &expansions))),
+    };
+    parser::scope_labels(&mut program);
+
+    let program = match variables::allocate(&program) {
+        Ok(program) => program,
+        Err(errors) => return Err(fail(warnings, diagnose(&sources, errors, &expansions))),
+    };
+
+    let program = match routines::place(&program) {
+        Ok(program) => program,
+        Err(errors) => return Err(fail(warnings, diagnose(&sources, errors, &expansions))),
+    };
+
+    let (program, optimizations) = if options.optimize {
+        let (program, removals) = optimizer::optimize(&program);
+        (program, optimizer::report(&removals, &sources, &expansions))
+    } else {
+        (program, String::new())
+    };
+
+    let (layout, symbols) = match layout::lay_out(&program) {
+        Ok(result) => result,
+        Err(errors) => return Err(fail(warnings, diagnose(&sources, errors, &expansions))),
+    };
+
+    let image = match codegen::generate(&program, &layout, &symbols) {
+        Ok(image) => image,
+        Err(errors) => return Err(fail(warnings, diagnose(&sources, errors, &expansions))),
+    };
+
+    Ok(AssembledProgram {
+        image,
+        debug_info: debug_info::collect(&sources, &program, &layout, &symbols),
+        listing: listing::generate(&sources, &program, &layout, &image, &expansions),
+        warnings,
+        optimizations,
+    })
+}
+
+fn diagnose(sources: &Sources, errors: Vec<AssemblyError>, expansions: &[Expansion]) -> Vec<Diagnostic> {
+    errors.into_iter().map(|error| Diagnostic::from_assembly_error(error, sources, expansions)).collect()
+}
+
+/// Puts the warnings found before an error in front of it, so they aren't lost.
+fn fail(mut warnings: Vec<Diagnostic>, mut errors: Vec<Diagnostic>) -> Vec<Diagnostic> {
+    warnings.append(&mut errors);
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assembles_in_process() {
+        let options = Options { definitions: vec![("START".to_owned(), "2".to_owned())], ..Options::default() };
+        let program = assemble(".equ UNUSED 1\n.org START\nloop:\n    brn loop\n", &options).unwrap();
+
+        assert_eq!(program.image[2], 0b10000010);
+        assert_eq!(program.debug_info.labels, [("loop".to_owned(), 2)]);
+        assert!(program.listing.contains("0:02  10000010  loop:"));
+        assert_eq!(program.warnings.iter().map(|warning| warning.code).collect::<Vec<_>>(), ["W002"]);
+    }
+
+    #[test]
+    fn defines_negative_and_parenthesized_values() {
+        let definitions = [("N", "-1"), ("P", "(1 + 2)")].map(|(name, value)| (name.to_owned(), value.to_owned()));
+        let options = Options { definitions: definitions.to_vec(), ..Options::default() };
+        let program = assemble(".byte N, P\n", &options).unwrap();
+        assert_eq!(program.image[..2], [0xFF, 3]);
+    }
+
+    #[test]
+    fn returns_warnings_and_errors() {
+        let diagnostics = assemble(".equ UNUSED 1\nbrn nowhere\n", &Options::default()).err().unwrap();
+
+        let codes: Vec<_> = diagnostics.iter().map(|diagnostic| (diagnostic.severity, diagnostic.code)).collect();
+        assert_eq!(codes, [(Severity::Warning, "W002"), (Severity::Error, "E301")]);
+        assert_eq!(diagnostics[1].snippet.as_ref().unwrap().text, "brn nowhere");
+    }
+
+    #[test]
+    fn reports_characters_that_are_not_ascii() {
+        let options = Options { definitions: vec![("NAME".to_owned(), "\u{e9}".to_owned())], ..Options::default() };
+        for (source, options) in [("nop ; caf\u{e9}\n", Options::default()), ("nop\n", options)] {
+            let diagnostics = assemble(source, &options).err().unwrap();
+            assert_eq!(diagnostics.iter().map(|diagnostic| diagnostic.code).collect::<Vec<_>>(), ["E105"]);
+        }
+    }
+
+    #[test]
+    fn scopes_labels_after_conditionals_and_macros() {
+        let program = assemble("brn 1f\n.if 0\n1: nop\n.endif\n1: nop\n", &Options::default()).unwrap();
+        assert_eq!(program.image[0], 0b10000001);
+
+        let source = "\
+            .macro twice\n1: nop\nbrn 1b\ninner:\n.end: nop\n.endm\n\
+            brn 1f\n1: nop\nmain:\n.loop: twice\ntwice\nbrn 1b\nbrn .loop\n";
+        let program = assemble(source, &Options::default()).unwrap();
+        let targets: Vec<_> = [0, 3, 6, 8, 9].map(|address| program.image[address] & 0b111111).to_vec();
+        assert_eq!(targets, [1, 2, 5, 1, 2]);
+    }
+
+    #[test]
+    fn optimizes_when_asked() {
+        let source = "lpb 0\nloop:\n    ssf\n    brn loop\n    mov x x\n";
+        let plain = assemble(source, &Options::default()).unwrap();
+        assert!(plain.optimizations.is_empty());
+
+        let optimized = assemble(source, &Options { optimize: true, ..Options::default() }).unwrap();
+        assert_eq!(optimized.image[..3], [0b00000011, 0b10000000, 0]);
+        assert!(optimized.optimizations.ends_with("Removed 2 instructions, saving 2 bytes\n"));
+    }
+}
@@ -0,0 +1,146 @@
+use crate::layout::Layout;
+use crate::location::Location;
+use crate::macros::Expansion;
+use crate::parser::Node;
+use crate::sources::Sources;
+use crate::symbols::Address;
+use common::architecture::{NUM_PAGES, PAGE_SIZE, PROGRAM_MEMORY_SIZE};
+use std::fmt::Write;
+
+/// Builds a listing of an assembled program, showing where each instruction ended up in program memory and what it
+/// was encoded as, next to the source it came from. A summary of how full each page is closes the listing.
+///
+/// Code is listed in the order it appears in the program rather than by address, so each line is shown as
+/// `page:offset  bits  label: source`.
+///
+/// # Arguments
+///
+/// * `sources`: The source files of the program, used to show the line each instruction was written on.
+/// * `program`: The nodes the image was generated from.
+/// * `layout`: Where each node of `program` is placed in program memory.
+/// * `image`: The generated ROM image.
+/// * `expansions`: The macro expansions in `program`, used to show which call code coming from a macro belongs to.
+pub fn generate(
+    sources: &Sources,
+    program: &[Node],
+    layout: &Layout,
+    image: &[u8; PROGRAM_MEMORY_SIZE],
+    expansions: &[Expansion],
+) -> String {
+    let label_width = program
+        .iter()
+        .filter_map(|node| match node {
+            Node::Label { name, .. } => Some(name.text.len() + 1),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+
+    let mut listing = String::new();
+    let mut used = [0; NUM_PAGES];
+    let mut labels = vec![];
+    let mut line = |index: usize, label: &str, text: &str, listing: &mut String| {
+        let Address { page, offset } = Address::from_index(index);
+        used[page as usize] += 1;
+        let label = if label.is_empty() { String::new() } else { format!("{label}:") };
+        let line = format!("{page:2}:{offset:02}  {:08b}  {label:label_width$} {text}", image[index]);
+        writeln!(listing, "{}", line.trim_end()).unwrap();
+    };
+
+    for (i, node) in program.iter().enumerate() {
+        if let Some(guard) = layout.guard(i) {
+            for index in guard.start..guard.start + guard.form.len() {
+                let text = format!("; continues on page {}", guard.target / PAGE_SIZE);
+                line(index, "", if index == guard.start { &text } else { "" }, &mut listing);
+            }
+        }
+
+        let size = match node {
+            Node::Label { name, .. } => {
+                labels.push(name.text);
+                continue;
+            }
+            Node::Instruction { .. } | Node::PseudoInstruction { .. } => layout.size(i),
+            // `.fill 0` takes up nothing, so any labels on it go with what comes next.
+            Node::Directive { kind, .. } if kind.is_data() && layout.size(i) > 0 => layout.size(i),
+            _ => continue,
+        };
+
+        let start = layout.start(i);
+        // Only the last label gets to share a line with the instruction.
+        let label = labels.pop().unwrap_or_default();
+        for extra in labels.drain(..) {
+            let Address { page, offset } = Address::from_index(start);
+            writeln!(listing, "{page:2}:{offset:02}  {:8}  {extra}:", "").unwrap();
+        }
+
+        let location = node.location();
+        let mut text = source_text(sources, location);
+        // The line in the macro still has its parameters in it, so show what they were given as.
+        if let Some(expansion) = location.expansion {
+            write!(text, " ; from {}", source_text(sources, expansions[expansion as usize].call)).unwrap();
+        }
+
+        line(start, label, &text, &mut listing);
+        for index in start + 1..start + size {
+            line(index, "", "", &mut listing);
+        }
+    }
+
+    writeln!(listing).unwrap();
+    for (page, used) in used.iter().enumerate() {
+        let percent = used * 100 / PAGE_SIZE;
+        writeln!(listing, "; page {page:2}: {used:2}/{PAGE_SIZE} used ({percent}%)").unwrap();
+    }
+    let total: usize = used.iter().sum();
+    writeln!(listing, "; total:   {total}/{PROGRAM_MEMORY_SIZE} used ({}%)", total * 100 / PROGRAM_MEMORY_SIZE).unwrap();
+
+    listing
+}
+
+/// Gets the source of an instruction, from where it starts to the end of its line. Anything before it on the line,
+/// like a label, is left out.
+fn source_text(sources: &Sources, location: Location) -> String {
+    let text = &sources.file(location.file).text;
+    let line = text.lines().nth(location.line as usize - 1).unwrap_or_default();
+    line.chars().skip(location.col as usize - 1).collect::<String>().trim_end().to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen;
+    use crate::layout::lay_out;
+    use std::path::PathBuf;
+
+    fn list(program: &str) -> String {
+        let sources = Sources::new(PathBuf::from("test.asm"), program.to_owned());
+        let nodes = sources.parse().ok().unwrap();
+        let (layout, symbols) = lay_out(&nodes).ok().unwrap();
+        let image = codegen::generate(&nodes, &layout, &symbols).ok().unwrap();
+        generate(&sources, &nodes, &layout, &image, &[])
+    }
+
+    #[test]
+    fn lists_each_instruction_with_its_label() {
+        let listing = list("start:\n    ldi x 4 ; four\nend: brn end\n");
+        let lines: Vec<_> = listing.lines().take(2).collect();
+        assert_eq!(lines, [" 0:00  11010100  start: ldi x 4 ; four", " 0:01  10000001  end:   brn end"]);
+    }
+
+    #[test]
+    fn lists_every_instruction_of_a_far_jump() {
+        let listing = list("jmp far\n.page 1\nfar:\nnop\n");
+        let lines: Vec<_> = listing.lines().take(3).collect();
+        assert_eq!(lines, [" 0:00  00010001       jmp far", " 0:01  00000011", " 0:02  10000000"]);
+    }
+
+    #[test]
+    fn summarizes_each_page() {
+        let listing = list(&"inc x\n".repeat(70));
+        assert!(listing.contains(" 0:61  00010001   ; continues on page 1\n"));
+        assert!(listing.contains("; page  0: 64/64 used (100%)\n"));
+        assert!(listing.contains("; page  1:  9/64 used (14%)\n"));
+        assert!(listing.ends_with("; total:   73/1024 used (7%)\n"));
+    }
+}
@@ -1,32 +1,49 @@
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct Location {
-    pub index: usize,
-    pub line: usize,
-    pub col: usize,
+    /// The source file the location is in. Indexes into `Sources`.
+    pub file: u32,
+    /// The byte offset into the file.
+    pub index: u32,
+    /// How many bytes the token at the location spans, or 0 if the location is between tokens.
+    pub length: u32,
+    pub line: u32,
+    pub col: u32,
+    /// The macro expansion the location was copied into, if any. Indexes into the expansions returned by
+    /// `macros::expand`.
+    pub expansion: Option<u32>,
 }
 
 impl Location {
-    pub fn start() -> Location {
+    pub fn start(file: u32) -> Location {
         Location {
+            file,
             index: 0,
+            length: 0,
             line: 1,
             col: 1,
+            expansion: None,
         }
     }
 
     pub fn advance_col(self) -> Location {
         Location {
+            file: self.file,
             index: self.index + 1,
+            length: 0,
             line: self.line,
-            col: self.col + 1
+            col: self.col + 1,
+            expansion: self.expansion,
         }
     }
 
     pub fn advance_line(self) -> Location {
         Location {
+            file: self.file,
             index: self.index + 1,
+            length: 0,
             line: self.line + 1,
-            col: 1
+            col: 1,
+            expansion: self.expansion,
         }
     }
 }
@@ -0,0 +1,466 @@
+use crate::error::{AssemblyError, AssemblyErrorKind};
+use crate::lexer::{unescape, DirectiveKind, InstructionKind, PseudoInstructionKind, Register};
+use crate::location::Location;
+use crate::parser::{Name, Node};
+use crate::suggestions;
+use std::collections::{HashMap, HashSet};
+
+/// A macro defined between `.macro name parameters...` and `.endm`.
+struct Macro<'a> {
+    /// Where the `.macro` directive is.
+    location: Location,
+    parameters: Vec<&'a str>,
+    body: Vec<Node<'a>>,
+    /// The labels, constants and variables defined in the body. Each expansion gets its own copy of them.
+    locals: HashSet<&'a str>,
+}
+
+/// A single invocation of a macro, replaced by the body of the macro.
+#[derive(Copy, Clone, Debug)]
+pub struct Expansion<'a> {
+    pub name: &'a str,
+    /// Where the `.macro` directive defining the macro is.
+    pub definition: Location,
+    /// Where the macro is invoked. This lies inside another expansion when one macro invokes another.
+    pub call: Location,
+}
+
+/// Replaces every macro invocation in a program with the body of the macro and takes the definitions out.
+///
+/// Every parameter in the body is replaced by the matching argument, and the labels and constants defined in the body
+/// get new names local to the expansion, so a macro can be invoked any number of times. Macros can invoke other
+/// macros, but not themselves. Macros can be invoked before they are defined.
+///
+/// `.print` is expanded here as well, into the instructions writing each character of its string.
+///
+/// Nodes copied out of a macro keep the location they have in its definition, marked with the expansion they belong
+/// to. The expansions are added to `expansions`, so errors can be traced back to the invocation.
+///
+/// # Arguments
+///
+/// * `program`: The nodes produced by the parser.
+/// * `expansions`: Where to record every expansion. `Location::expansion` indexes into it.
+pub fn expand<'a>(
+    program: &[Node<'a>],
+    expansions: &mut Vec<Expansion<'a>>,
+) -> Result<Vec<Node<'a>>, Vec<AssemblyError<'a>>> {
+    let (macros, rest) = find_macros(program)?;
+
+    let mut expander = Expander { macros: &macros, expansions, stack: vec![], printer: None, errors: vec![] };
+    let mut expanded = vec![];
+    expander.expand(&rest, &mut expanded);
+
+    if expander.errors.is_empty() {
+        Ok(expanded)
+    } else {
+        Err(expander.errors)
+    }
+}
+
+/// Takes every `.macro` block out of a program, returning the macros and the nodes left over.
+#[allow(clippy::type_complexity)]
+fn find_macros<'a>(
+    program: &[Node<'a>],
+) -> Result<(HashMap<&'a str, Macro<'a>>, Vec<Node<'a>>), Vec<AssemblyError<'a>>> {
+    let mut macros: HashMap<&'a str, Macro<'a>> = HashMap::new();
+    let mut rest = vec![];
+    let mut errors = vec![];
+    let mut open: Option<(&'a str, Macro<'a>)> = None;
+
+    for node in program {
+        let Node::Directive { kind: kind @ (DirectiveKind::Macro | DirectiveKind::EndMacro), arguments, location } =
+            node
+        else {
+            match &mut open {
+                Some((_, definition)) => {
+                    definition.locals.extend(defined_name(node));
+                    definition.body.push(node.clone());
+                }
+                None => rest.push(node.clone()),
+            }
+            continue;
+        };
+        let (kind, location) = (*kind, *location);
+
+        match (kind, open.take()) {
+            (DirectiveKind::Macro, Some((_, outer))) => errors.push(AssemblyError {
+                location: outer.location,
+                kind: AssemblyErrorKind::UnmatchedDirective { directive: DirectiveKind::Macro },
+                help: Some("Macros can't be defined inside other macros. Close this one with .endm first".to_owned()),
+            }),
+            (DirectiveKind::Macro, None) => match parameters(arguments) {
+                Some((name, parameters)) => {
+                    let definition = Macro { location, parameters, body: vec![], locals: HashSet::new() };
+                    open = Some((name, definition));
+                }
+                None => errors.push(AssemblyError {
+                    location,
+                    kind: AssemblyErrorKind::InvalidDirectiveArguments { directive: kind },
+                    help: Some(format!("{} takes the name of the macro followed by its parameters", kind.name())),
+                }),
+            },
+            (DirectiveKind::EndMacro, Some((name, definition))) => {
+                if let Some(previous) = macros.get(name) {
+                    errors.push(AssemblyError {
+                        location: definition.location,
+                        kind: AssemblyErrorKind::DuplicateMacro { name, previous: previous.location },
+                        help: None,
+                    });
+                } else {
+                    macros.insert(name, definition);
+                }
+            }
+            (DirectiveKind::EndMacro, None) => errors.push(AssemblyError {
+                location,
+                kind: AssemblyErrorKind::UnmatchedDirective { directive: kind },
+                help: None,
+            }),
+            _ => unreachable!("Only macro directives get here"),
+        }
+
+        if let (DirectiveKind::EndMacro, [argument, ..]) = (kind, arguments.as_slice()) {
+            errors.push(AssemblyError {
+                location: argument.location(),
+                kind: AssemblyErrorKind::InvalidDirectiveArguments { directive: kind },
+                help: Some(format!("{} takes no arguments", kind.name())),
+            });
+        }
+    }
+
+    if let Some((_, definition)) = open {
+        errors.push(AssemblyError {
+            location: definition.location,
+            kind: AssemblyErrorKind::UnmatchedDirective { directive: DirectiveKind::Macro },
+            help: None,
+        });
+    }
+
+    if errors.is_empty() {
+        Ok((macros, rest))
+    } else {
+        Err(errors)
+    }
+}
+
+/// The name a node defines, if it is a label, a constant or a variable.
+fn defined_name<'a>(node: &Node<'a>) -> Option<&'a str> {
+    match node {
+        Node::Label { name, .. } => Some(name.text),
+        Node::Directive { kind: DirectiveKind::Equ | DirectiveKind::Var, arguments, .. } => match arguments.first() {
+            Some(Node::LabelReference { name, .. }) => Some(name.text),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Turns the text of a string literal into the ASCII codes it stands for, resolving escape sequences. Returns None if
+/// there is an escape sequence that doesn't exist.
+fn unescape_string(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => bytes.push(unescape(chars.next()?)?),
+            c => bytes.push(c as u8),
+        }
+    }
+    Some(bytes)
+}
+
+/// Splits the arguments of a `.macro` directive into the name of the macro and the names of its parameters. Returns
+/// None if any of them isn't a plain name.
+fn parameters<'a>(arguments: &[Node<'a>]) -> Option<(&'a str, Vec<&'a str>)> {
+    let mut names = arguments.iter().map(|argument| match argument {
+        Node::LabelReference { name, .. } => Some(name.text),
+        _ => None,
+    });
+    let name = names.next()??;
+    let parameters = names.collect::<Option<Vec<_>>>()?;
+    Some((name, parameters))
+}
+
+struct Expander<'m, 'a> {
+    macros: &'m HashMap<&'a str, Macro<'a>>,
+    expansions: &'m mut Vec<Expansion<'a>>,
+    /// The macros currently being expanded, used to catch macros that invoke themselves.
+    stack: Vec<&'a str>,
+    /// The subroutine `.print` calls, as set by the last `.printer`.
+    printer: Option<Node<'a>>,
+    errors: Vec<AssemblyError<'a>>,
+}
+
+impl<'a> Expander<'_, 'a> {
+    /// Copies `nodes` into `output`, expanding every macro invocation along the way.
+    fn expand(&mut self, nodes: &[Node<'a>], output: &mut Vec<Node<'a>>) {
+        for node in nodes {
+            match node {
+                Node::MacroCall { name, arguments, location } => self.invoke(name, arguments, *location, output),
+                Node::Directive { kind: kind @ DirectiveKind::Printer, arguments, location } => {
+                    match arguments.as_slice() {
+                        [routine @ Node::LabelReference { .. }] => self.printer = Some(routine.clone()),
+                        _ => self.errors.push(AssemblyError {
+                            location: *location,
+                            kind: AssemblyErrorKind::InvalidDirectiveArguments { directive: *kind },
+                            help: Some(format!("{} takes the name of the subroutine writing a character", kind.name())),
+                        }),
+                    }
+                }
+                Node::Directive { kind: DirectiveKind::Print, arguments, location } => {
+                    self.print(arguments, *location, output)
+                }
+                _ => output.push(node.clone()),
+            }
+        }
+    }
+
+    /// Expands `.print "text"` into loading each character into X (high nibble) and Y (low nibble), followed by a
+    /// branch to the printer. The branch only calls the printer with the subroutine jump flag set, like `put_char`.
+    fn print(&mut self, arguments: &[Node<'a>], location: Location, output: &mut Vec<Node<'a>>) {
+        let kind = DirectiveKind::Print;
+        let error = |help: String| AssemblyError {
+            location,
+            kind: AssemblyErrorKind::InvalidDirectiveArguments { directive: kind },
+            help: Some(help),
+        };
+
+        let [Node::StringLiteral { value, .. }] = arguments else {
+            self.errors.push(error(format!("{} takes the text to write in double quotes", kind.name())));
+            return;
+        };
+        let Some(text) = unescape_string(value) else {
+            self.errors.push(error("The escape sequences are \\n, \\t, \\r, \\0, \\\\, \\' and \\\"".to_owned()));
+            return;
+        };
+        let Some(printer) = &self.printer else {
+            let printer = DirectiveKind::Printer.name();
+            self.errors.push(error(format!("Set the subroutine writing a character with {printer} first")));
+            return;
+        };
+
+        for c in text {
+            for (register, value) in [(Register::X, c >> 4), (Register::Y, c & 0xF)] {
+                let arguments = vec![
+                    Node::RegisterLiteral { register, location },
+                    Node::NumberLiteral { value: value as u16, location },
+                ];
+                output.push(Node::Instruction { kind: InstructionKind::LDI, arguments, location });
+            }
+            let arguments = vec![printer.clone()];
+            output.push(Node::Instruction { kind: InstructionKind::BRN, arguments, location });
+        }
+    }
+
+    fn invoke(&mut self, name: &'a str, arguments: &[Node<'a>], location: Location, output: &mut Vec<Node<'a>>) {
+        let Some(definition) = self.macros.get(name) else {
+            // Anything unknown at the start of a line ends up here, so it could be meant as any of these.
+            let candidates = self
+                .macros
+                .keys()
+                .copied()
+                .chain(InstructionKind::ALL.iter().map(|kind| kind.mnemonic()))
+                .chain(PseudoInstructionKind::ALL.map(PseudoInstructionKind::name))
+                .chain(DirectiveKind::ALL.map(DirectiveKind::name));
+            let help = match suggestions::closest(name, candidates) {
+                Some(suggestion) => format!("Did you mean `{suggestion}`?"),
+                None => "Labels need a ':' after their name".to_owned(),
+            };
+            self.errors.push(AssemblyError {
+                location,
+                kind: AssemblyErrorKind::UndefinedMacro { name },
+                help: Some(help),
+            });
+            return;
+        };
+
+        if arguments.len() != definition.parameters.len() {
+            self.errors.push(AssemblyError {
+                location,
+                kind: AssemblyErrorKind::MacroArguments {
+                    name,
+                    expected: definition.parameters.len(),
+                    found: arguments.len(),
+                },
+                help: (!definition.parameters.is_empty())
+                    .then(|| format!("The parameters of '{name}' are {}", definition.parameters.join(", "))),
+            });
+            return;
+        }
+
+        if self.stack.contains(&name) {
+            let mut chain = self.stack.clone();
+            chain.push(name);
+            self.errors.push(AssemblyError {
+                location,
+                kind: AssemblyErrorKind::RecursiveMacro { chain },
+                help: None,
+            });
+            return;
+        }
+
+        let expansion = self.expansions.len() as u32;
+        self.expansions.push(Expansion { name, definition: definition.location, call: location });
+        let substitution = Substitution { definition, arguments, expansion };
+        let body: Vec<_> = definition.body.iter().map(|node| substitution.apply(node)).collect();
+
+        self.stack.push(name);
+        self.expand(&body, output);
+        self.stack.pop();
+    }
+}
+
+/// Turns the body of a macro into the nodes of one expansion of it.
+struct Substitution<'m, 'a> {
+    definition: &'m Macro<'a>,
+    arguments: &'m [Node<'a>],
+    expansion: u32,
+}
+
+impl<'a> Substitution<'_, 'a> {
+    fn apply(&self, node: &Node<'a>) -> Node<'a> {
+        let location = Location { expansion: Some(self.expansion), ..node.location() };
+        let arguments = |arguments: &[Node<'a>]| arguments.iter().map(|argument| self.apply(argument)).collect();
+
+        match node {
+            Node::Label { name, .. } => Node::Label { name: self.local(*name), location },
+            Node::LabelReference { name, .. } => {
+                match self.definition.parameters.iter().position(|parameter| *parameter == name.text) {
+                    // Arguments stay as they were written at the invocation.
+                    Some(i) => self.arguments[i].clone(),
+                    None => Node::LabelReference { name: self.local(*name), location },
+                }
+            }
+            Node::Instruction { kind, arguments: args, .. } => {
+                Node::Instruction { kind: *kind, arguments: arguments(args), location }
+            }
+            Node::PseudoInstruction { kind, arguments: args, .. } => {
+                Node::PseudoInstruction { kind: *kind, arguments: arguments(args), location }
+            }
+            Node::Directive { kind, arguments: args, .. } => {
+                Node::Directive { kind: *kind, arguments: arguments(args), location }
+            }
+            Node::MacroCall { name, arguments: args, .. } => {
+                Node::MacroCall { name, arguments: arguments(args), location }
+            }
+            Node::RegisterLiteral { register, .. } => Node::RegisterLiteral { register: *register, location },
+            Node::NumberLiteral { value, .. } => Node::NumberLiteral { value: *value, location },
+            Node::StringLiteral { value, .. } => Node::StringLiteral { value, location },
+            Node::Negation { operand, .. } => Node::Negation { operand: Box::new(self.apply(operand)), location },
+            Node::BinaryOperation { operator, left, right, .. } => Node::BinaryOperation {
+                operator: *operator,
+                left: Box::new(self.apply(left)),
+                right: Box::new(self.apply(right)),
+                location,
+            },
+            Node::FunctionCall { function, argument, .. } => {
+                Node::FunctionCall { function: *function, argument: Box::new(self.apply(argument)), location }
+            }
+        }
+    }
+
+    /// Moves a name into the expansion if the macro defines it. A reference like `1f` belongs to the expansion if the
+    /// macro defines the anonymous label `1`.
+    fn local(&self, name: Name<'a>) -> Name<'a> {
+        let text = if name.is_anonymous_reference() { &name.text[..name.text.len() - 1] } else { name.text };
+        if self.definition.locals.contains(text) {
+            Name { expansion: Some(self.expansion), ..name }
+        } else {
+            name
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::generate;
+    use crate::layout::lay_out;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn expand_program(program: &str) -> Result<(Vec<Node<'_>>, Vec<Expansion<'_>>), Vec<AssemblyErrorKind<'_>>> {
+        let mut lexer = Lexer::new(program, 0);
+        let mut parser = Parser::new(lexer.iter());
+        let nodes = parser.parse().ok().unwrap();
+        let mut expansions = vec![];
+        let expanded = expand(&nodes, &mut expansions)
+            .map_err(|errors| errors.into_iter().map(|error| error.kind).collect::<Vec<_>>())?;
+        Ok((expanded, expansions))
+    }
+
+    fn assemble(program: &str) -> Vec<u8> {
+        let (nodes, _) = expand_program(program).ok().unwrap();
+        let (layout, symbols) = lay_out(&nodes).ok().unwrap();
+        generate(&nodes, &layout, &symbols).ok().unwrap().to_vec()
+    }
+
+    #[test]
+    fn substitutes_parameters() {
+        let image = assemble(".macro put reg value\nldi reg value + 1\nout 0\n.endm\nput x 3\nput y hi(0x48)\n");
+        // LDI X 4, OUT 0, LDI Y 5, OUT 0
+        assert_eq!(image[0..4], [0b11010100, 0b01110100, 0b11100101, 0b01110100]);
+    }
+
+    #[test]
+    fn labels_are_local_to_each_expansion() {
+        let image = assemble(".macro spin\nloop:\nbrn loop\n.endm\nnop\nspin\nspin\n");
+        assert_eq!(image[0..3], [0b00000000, 0b10000001, 0b10000010]);
+    }
+
+    #[test]
+    fn macros_can_invoke_other_macros() {
+        let image = assemble("twice z\n.macro twice reg\nonce reg\nonce reg\n.endm\n.macro once r\ninc r\n.endm\n");
+        assert_eq!(image[0..3], [0b00101011, 0b00101011, 0b00000000]);
+    }
+
+    #[test]
+    fn expanded_nodes_point_at_the_invocation() {
+        let (nodes, expansions) = expand_program(".macro inner\nnop\n.endm\n.macro outer\ninner\n.endm\nouter\n")
+            .ok()
+            .unwrap();
+        let location = nodes[0].location();
+        assert_eq!((location.line, location.expansion), (2, Some(1)));
+        assert_eq!(expansions[1].call.line, 5);
+        assert_eq!(expansions[1].call.expansion, Some(0));
+        assert_eq!(expansions[0].call.line, 7);
+    }
+
+    #[test]
+    fn prints_each_character_with_the_printer() {
+        let image = assemble("write:\nret\n.printer write\n.print \"Hi\\n\"\n");
+        // LDI X 4, LDI Y 8, BRN write, then the same for 'i' (0x69) and '\n' (0x0A)
+        assert_eq!(image[1..4], [0b11010100, 0b11101000, 0b10000000]);
+        assert_eq!(image[4..10], [0b11010110, 0b11101001, 0b10000000, 0b11010000, 0b11101010, 0b10000000]);
+
+        let errors = expand_program(".print \"Hi\"\n.printer 5\n.print 5\n").err().unwrap();
+        assert_eq!(errors.len(), 3);
+    }
+
+    #[test]
+    fn rejects_recursive_macros() {
+        let errors = expand_program(".macro ping\npong\n.endm\n.macro pong\nping\n.endm\nping\n").err().unwrap();
+        assert!(matches!(
+            errors.as_slice(),
+            [AssemblyErrorKind::RecursiveMacro { chain }] if chain == &["ping", "pong", "ping"]
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_invocations() {
+        let errors = expand_program(".macro m a_\n.endm\nm\nm 1 2\nmissing\n").err().unwrap();
+        assert!(matches!(
+            errors.as_slice(),
+            [
+                AssemblyErrorKind::MacroArguments { expected: 1, found: 0, .. },
+                AssemblyErrorKind::MacroArguments { expected: 1, found: 2, .. },
+                AssemblyErrorKind::UndefinedMacro { name: "missing" },
+            ]
+        ));
+    }
+
+    #[test]
+    fn rejects_unmatched_directives() {
+        let errors = expand_program(".endm\n.macro m\n").err().unwrap();
+        assert_eq!(errors.len(), 2);
+    }
+}
@@ -1,149 +1,211 @@
-#![feature(if_let_guard)]
-#![feature(stmt_expr_attributes)]
-#![feature(proc_macro_hygiene)]
-
-mod lexer;
-mod location;
-mod parser;
-
-use crate::lexer::{Lexer, TokenKind};
-use crate::parser::{ErrorTokenKind, ParseError, ParseErrorKind, Parser};
-use std::fmt::{Display, Formatter};
-use std::fs::File;
-use std::io::Read;
-use std::path::{Path, PathBuf};
-
-fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    let input_filename = Path::new(args.get(1).expect("Input file is required")).to_path_buf();
-    let output_filename = match args.get(2) {
-        None => input_filename.with_extension("out"),
-        Some(f) => Path::new(f).to_path_buf(),
-    };
+use assembler::{Diagnostic, Format, Options, Severity};
+use std::fs;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+const USAGE: &str = concat!(
+    "Usage: assembler [-I dir]... [-D name[=value]]... [--format=raw|ihex|logisim|readmemh|readmemb|pages] ",
+    "[-O] [--listing] [--symbols] [--color=auto|always|never] input [output]\n",
+    "       assembler fmt [--check] file..."
+);
+
+/// What the assembler was asked to do on the command line.
+struct Arguments {
+    /// How to assemble the input, including its path.
+    options: Options,
+    output: PathBuf,
+    format: Format,
+    write_listing: bool,
+    write_symbols: bool,
+    color: bool,
+}
+
+impl Arguments {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Arguments, String> {
+        let mut include_paths = vec![];
+        let mut definitions = vec![];
+        let mut positional = vec![];
+        let mut write_listing = false;
+        let mut write_symbols = false;
+        let mut optimize = false;
+        let mut color = None;
+        let mut format = Format::Raw;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-O" => optimize = true,
+                "--listing" => write_listing = true,
+                "--symbols" => write_symbols = true,
+                "--color" | "--color=always" => color = Some(true),
+                "--color=never" => color = Some(false),
+                "--color=auto" => color = None,
+                _ if arg.starts_with("--format") => {
+                    let name = match arg.strip_prefix("--format=") {
+                        Some(name) => name.to_owned(),
+                        None if arg == "--format" => args.next().ok_or("--format needs a format")?,
+                        None => return Err(format!("Unknown option {arg}")),
+                    };
+                    format = name.parse()?;
+                }
+                _ if arg.starts_with("--") => return Err(format!("Unknown option {arg}")),
+                _ if arg.starts_with("-D") => {
+                    let definition = match &arg[2..] {
+                        "" => args.next().ok_or("-D needs a name")?,
+                        definition => definition.to_owned(),
+                    };
+                    // A name on its own is defined as 1, so it works with both .if and .ifdef.
+                    let (name, value) = definition.split_once('=').unwrap_or((&definition, "1"));
+                    if name.is_empty() || value.is_empty() {
+                        return Err(format!("Invalid definition {definition}, expected name=value"));
+                    }
+                    definitions.push((name.to_owned(), value.to_owned()));
+                }
+                _ => match arg.strip_prefix("-I") {
+                    Some("") => include_paths.push(PathBuf::from(args.next().ok_or("-I needs a directory")?)),
+                    Some(path) => include_paths.push(PathBuf::from(path)),
+                    None => positional.push(arg),
+                },
+            }
+        }
+
+        let mut positional = positional.into_iter();
+        let input = PathBuf::from(positional.next().ok_or("Input file is required")?);
+        let output = positional.next().map_or_else(|| input.with_extension(format.extension()), PathBuf::from);
+        if let Some(extra) = positional.next() {
+            return Err(format!("Unexpected argument {extra}"));
+        }
 
-    let mut input_file = match File::open(input_filename.clone()) {
-        Ok(file) => file,
-        Err(err) => panic!(
-            "Could not open input file {}. Cause: {}",
-            input_filename.display(),
-            err
-        ),
+        // Color is only on by default when a person is likely to be reading, and they haven't asked for it to be off.
+        let color = color.unwrap_or_else(|| std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none());
+        let options = Options { path: input, include_paths, definitions, optimize };
+        Ok(Arguments { options, output, format, write_listing, write_symbols, color })
+    }
+}
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1).peekable();
+    if args.next_if_eq("fmt").is_some() {
+        return format_files(args);
+    }
+
+    let arguments = match Arguments::parse(args) {
+        Ok(arguments) => arguments,
+        Err(message) => {
+            eprintln!("{message}\n{USAGE}");
+            return ExitCode::from(2);
+        }
     };
 
-    let mut output_file = match File::create(output_filename.clone()) {
-        Ok(file) => file,
-        Err(err) => panic!(
-            "Could not create output file {}. Cause: {}",
-            output_filename.display(),
-            err
-        ),
+    if assemble(&arguments) {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Assembles the program and writes out everything asked for, printing any diagnostics along the way. Returns whether
+/// it succeeded.
+fn assemble(arguments: &Arguments) -> bool {
+    let color = arguments.color;
+    let path = &arguments.options.path;
+    let input = match fs::read_to_string(path) {
+        Ok(input) => input,
+        Err(err) => {
+            let message = format!("Could not read input file {}. Cause: {err}", path.display());
+            return report(vec![Diagnostic::error("E604", message)], color);
+        }
     };
 
-    let mut input = String::new();
-    match input_file.read_to_string(&mut input) {
-        Ok(_) => (),
-        Err(err) => panic!(
-            "Could not read input file {}. Cause: {}",
-            input_filename.display(),
-            err
-        ),
+    let program = match assembler::assemble(&input, &arguments.options) {
+        Ok(program) => program,
+        Err(diagnostics) => return report(diagnostics, color),
     };
+    report(program.warnings, color);
+    print!("{}", program.optimizations);
 
-    let mut lexer = Lexer::new(input.as_str());
+    let mut outputs = arguments.format.write(&program.image, &arguments.output);
+    if arguments.write_listing {
+        outputs.push((arguments.output.with_extension("lst"), program.listing.into_bytes()));
+    }
+    if arguments.write_symbols {
+        outputs.push((arguments.output.with_extension("sym"), program.debug_info.to_string().into_bytes()));
+    }
 
-    let mut parser = Parser::new(lexer.iter());
-    let result = parser.parse();
+    let failures: Vec<_> = outputs
+        .into_iter()
+        .filter_map(|(path, contents)| {
+            let err = fs::write(&path, contents).err()?;
+            Some(Diagnostic::error("E605", format!("Could not write output file {}. Cause: {err}", path.display())))
+        })
+        .collect();
+    report(failures, color)
+}
 
-    if let Err(errors) = result {
-        report_errors(&input_filename, errors);
+/// Rewrites each file given in the canonical layout. With `--check`, the files are left alone and the ones that aren't
+/// formatted yet are listed instead.
+fn format_files(args: impl Iterator<Item = String>) -> ExitCode {
+    let (flags, paths): (Vec<_>, Vec<_>) = args.partition(|arg| arg.starts_with("--"));
+    let check = flags.iter().any(|flag| flag == "--check");
+    if paths.is_empty() || flags.iter().any(|flag| flag != "--check") {
+        eprintln!("{USAGE}");
+        return ExitCode::from(2);
     }
-}
 
-fn report_errors(file: &PathBuf, errors: Vec<ParseError>) {
-    for error in errors {
-        let location = error
-            .token
-            .clone()
-            .map(|t| format!("{}:{}", t.location.line, t.location.col))
-            .unwrap_or("eof".to_owned());
-
-        print!("Error in {} at {}: ", file.display(), location);
-
-        match error {
-            ParseError {
-                kind: ParseErrorKind::UnexpectedToken { expected_types },
-                token: Some(token),
-                ..
-            } => {
-                let expected = expected_types
-                    .iter()
-                    .map(|e| e.to_string())
-                    .collect::<Vec<String>>()
-                    .join(", ");
-                let found = token.kind;
-                let text = token.text;
-                print!("Expected one of {expected} but found {found} ");
-
-                if found != TokenKind::Newline && found != TokenKind::Colon {
-                    println!("'{text}'");
-                } else {
-                    println!();
-                }
-            }
-            ParseError {
-                kind: ParseErrorKind::UnexpectedToken { expected_types },
-                token: None,
-                ..
-            } => {
-                let expected = expected_types
-                    .iter()
-                    .map(|e| e.to_string())
-                    .collect::<Vec<String>>()
-                    .join(", ");
-                println!("Expected one of {expected} but found eof")
-            }
-            ParseError {
-                kind: ParseErrorKind::InvalidNumberLiteral { cause },
-                token: Some(token),
-                ..
-            } => {
-                println!("Invalid number literal '{}'. Cause: {}", token.text, cause)
-            }
-            _ => {
-                println!("Unknown error")
-            }
+    let mut success = true;
+    for path in paths {
+        let formatted = fs::read_to_string(&path)
+            .map_err(|err| format!("Could not read {path}. Cause: {err}"))
+            .and_then(|source| match assembler::format(&source) {
+                Ok(formatted) => Ok((source, formatted)),
+                Err(err) => Err(format!("Could not format {path}. {err}")),
+            });
+        let message = match formatted {
+            Ok((source, formatted)) if source == formatted => continue,
+            Ok(_) if check => format!("{path} is not formatted"),
+            Ok((_, formatted)) => match fs::write(&path, formatted) {
+                Ok(()) => continue,
+                Err(err) => format!("Could not write {path}. Cause: {err}"),
+            },
+            Err(message) => message,
         };
+        eprintln!("{message}");
+        success = false;
+    }
 
-        if let Some(help) = error.help {
-            println!("Help: {help}")
-        }
+    if success {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
     }
 }
 
-impl Display for TokenKind {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let repr = match self {
-            TokenKind::Newline => "'\\n'",
-            TokenKind::Colon => "':'",
-            TokenKind::LabelIdentifier => "label identifier",
-            TokenKind::Instruction { .. } => "instruction",
-            TokenKind::NumberLiteral { .. } => "number literal",
-            TokenKind::RegisterLiteral { .. } => "register literal",
-        };
-        write!(f, "{}", repr)
+/// Prints diagnostics to stderr. Returns whether none of them were errors.
+fn report(diagnostics: Vec<Diagnostic>, color: bool) -> bool {
+    let mut success = true;
+    for diagnostic in diagnostics {
+        eprintln!("{}", diagnostic.render(color));
+        success &= diagnostic.severity != Severity::Error;
     }
+    success
 }
 
-impl Display for ErrorTokenKind {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let repr = match self {
-            ErrorTokenKind::Newline => "'\\n'",
-            ErrorTokenKind::Colon => "':'",
-            ErrorTokenKind::LabelIdentifier => "label identifier",
-            ErrorTokenKind::Instruction => "instruction",
-            ErrorTokenKind::NumberLiteral => "number literal",
-            ErrorTokenKind::RegisterLiteral => "register literal",
-        };
-        write!(f, "{}", repr)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fails_on_characters_that_are_not_ascii() {
+        let directory = std::env::temp_dir().join(format!("assembler-cli-ascii-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("main.asm"), "nop\n.include \"lib.asm\"\n").unwrap();
+        fs::write(directory.join("lib.asm"), "inc x ; caf\u{e9}\n").unwrap();
+        fs::write(directory.join("comment.asm"), "; \u{2192} x\nnop\n").unwrap();
+
+        for input in ["main.asm", "comment.asm"] {
+            let args = ["--color=never", &directory.join(input).display().to_string()].map(str::to_owned);
+            let arguments = Arguments::parse(args.into_iter()).unwrap();
+            assert!(!assemble(&arguments));
+            assert!(!arguments.output.exists());
+        }
     }
 }
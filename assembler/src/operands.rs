@@ -0,0 +1,149 @@
+use crate::error::{AssemblyError, AssemblyErrorKind};
+use crate::expression;
+use crate::lexer::InstructionKind;
+use crate::location::Location;
+use crate::parser::Node;
+use common::architecture::PC_BITS;
+
+/// What an operand has to be written as.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OperandKind {
+    Register,
+    Immediate,
+    Port,
+    Pin,
+    /// Where to branch to within the page. A bare label stands for its offset.
+    Label,
+}
+
+/// A single operand of an instruction, taken from one of the fields in its definition.
+#[derive(Copy, Clone, Debug)]
+pub struct Operand {
+    /// What the operand is called in error messages, like `immediate` or `source register`.
+    pub name: &'static str,
+    pub kind: OperandKind,
+    pub bits: usize,
+    /// Which of the instruction's fields the operand goes into.
+    pub field: usize,
+}
+
+impl Operand {
+    fn from_field(field: usize, (field_name, bits): (&'static str, usize)) -> Self {
+        let (name, kind) = match field_name {
+            "register_id" => ("register", OperandKind::Register),
+            "register_from_id" => ("source register", OperandKind::Register),
+            "register_to_id" => ("destination register", OperandKind::Register),
+            "port_id" => ("port", OperandKind::Port),
+            "pin_id" => ("pin", OperandKind::Pin),
+            // An immediate as wide as the program counter is loaded into it.
+            "immediate" if bits == PC_BITS => ("immediate", OperandKind::Label),
+            "immediate" => ("immediate", OperandKind::Immediate),
+            other => panic!("Unknown field '{other}' in instructions.txt"),
+        };
+        Operand { name, kind, bits, field }
+    }
+
+    /// Checks that a value fits into the operand and returns it as the operand's bits.
+    pub fn fit<'a>(
+        &self,
+        instruction: InstructionKind,
+        value: i64,
+        location: Location,
+    ) -> Result<u8, AssemblyError<'a>> {
+        let bits = self.bits;
+        expression::fit(value, bits, location).map(|value| value as u8).map_err(|error| AssemblyError {
+            kind: AssemblyErrorKind::OperandOutOfRange { instruction, operand: self.name, bits, value },
+            ..error
+        })
+    }
+}
+
+/// The operands an instruction takes, in the order they are written in.
+#[derive(Debug)]
+pub struct Schema {
+    pub operands: Vec<Operand>,
+}
+
+impl Schema {
+    /// Checks that an instruction is given the right number and kinds of operands. Whether values fit is only known
+    /// once they can be evaluated, which is up to `Operand::fit`.
+    pub fn check<'a>(
+        &self,
+        instruction: InstructionKind,
+        arguments: &[Node<'a>],
+        location: Location,
+    ) -> Result<(), AssemblyError<'a>> {
+        if arguments.len() != self.operands.len() {
+            return Err(AssemblyError {
+                location: arguments.get(self.operands.len()).map_or(location, Node::location),
+                kind: AssemblyErrorKind::OperandCount {
+                    instruction,
+                    expected: self.operands.len(),
+                    found: arguments.len(),
+                },
+                help: Some(self.usage(instruction)),
+            });
+        }
+
+        for (operand, argument) in self.operands.iter().zip(arguments) {
+            let matches = match operand.kind {
+                OperandKind::Register => matches!(argument, Node::RegisterLiteral { .. }),
+                _ => argument.is_expression(),
+            };
+            if !matches {
+                return Err(AssemblyError {
+                    location: argument.location(),
+                    kind: AssemblyErrorKind::OperandKind { instruction, operand: *operand },
+                    help: Some(self.usage(instruction)),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Shows how the instruction is written, like `ldi register immediate`.
+    pub fn syntax(&self, instruction: InstructionKind) -> String {
+        let mnemonic = instruction.mnemonic();
+        let operands = self.operands.iter().map(|operand| format!(" {}", operand.name.replace(' ', "_")));
+        format!("{mnemonic}{}", operands.collect::<String>())
+    }
+
+    fn usage(&self, instruction: InstructionKind) -> String {
+        format!("Usage: {}", self.syntax(instruction))
+    }
+}
+
+/// Gets the operands an instruction takes, from the fields it is declared with in `instructions.txt`.
+pub fn schema(instruction: InstructionKind) -> Schema {
+    let fields = instruction.fields().iter().copied().enumerate();
+    let mut operands: Vec<_> = fields.map(|(field, definition)| Operand::from_field(field, definition)).collect();
+    // Like most assemblers, the destination is written first: `mov z x` copies x into z.
+    operands.sort_by_key(|operand| operand.name != "destination register");
+
+    Schema { operands }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operands_follow_the_definitions() {
+        let ldi = schema(InstructionKind::LDI);
+        let operands: Vec<_> = ldi.operands.iter().map(|operand| (operand.kind, operand.bits)).collect();
+        assert_eq!(operands, [(OperandKind::Register, 2), (OperandKind::Immediate, 4)]);
+
+        assert!(schema(InstructionKind::NOP).operands.is_empty());
+        assert_eq!(schema(InstructionKind::OUT).operands[0].kind, OperandKind::Port);
+        assert_eq!(schema(InstructionKind::BRN).operands[0].kind, OperandKind::Label);
+        assert_eq!(schema(InstructionKind::LPB).operands[0].kind, OperandKind::Immediate);
+    }
+
+    #[test]
+    fn destination_comes_first() {
+        let operands: Vec<_> =
+            schema(InstructionKind::MOV).operands.iter().map(|operand| (operand.name, operand.field)).collect();
+        assert_eq!(operands, [("destination register", 1), ("source register", 0)]);
+    }
+}
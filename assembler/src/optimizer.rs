@@ -0,0 +1,266 @@
+use crate::flow::{self, KnownState, NodeFlow};
+use crate::layout::lay_out;
+use crate::location::Location;
+use crate::macros::Expansion;
+use crate::parser::Node;
+use crate::sources::Sources;
+use common::instruction::Instruction;
+use std::fmt::Write;
+
+/// Why an instruction was taken out of a program.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Reason {
+    /// An `LPB` loading the page the page buffer already holds.
+    PageBuffer(u8),
+    /// An `SSF` or `RSF` giving the status flag the value it already has.
+    StatusFlag(bool),
+    /// An `SSJ` or `RSJ` giving the subroutine jump flag the value it already has.
+    SubroutineJump(bool),
+    /// A `MOV` from a register to itself.
+    SelfMove,
+    /// Code right after an instruction that never continues with the next one, without a label in between.
+    Unreachable,
+}
+
+impl Reason {
+    fn describe(self) -> String {
+        let value = |set: bool| if set { "set" } else { "clear" };
+        match self {
+            Reason::PageBuffer(page) => format!("the page buffer already holds {page}"),
+            Reason::StatusFlag(set) => format!("the status flag is already {}", value(set)),
+            Reason::SubroutineJump(set) => format!("the subroutine jump flag is already {}", value(set)),
+            Reason::SelfMove => "it moves a register to itself".to_owned(),
+            Reason::Unreachable => "it can never run".to_owned(),
+        }
+    }
+}
+
+/// An instruction or pseudo-instruction taken out of a program.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Removal {
+    pub location: Location,
+    pub reason: Reason,
+    /// How many bytes the node took up when it was taken out.
+    pub size: usize,
+}
+
+/// Takes out instructions that don't change anything: `LPB`s, `SSF`s, `RSF`s, `SSJ`s and `RSJ`s setting what is
+/// already known to be set, `MOV`s from a register to itself, and code after a `RET`, a `JMP` or a `BRN` that is
+/// always taken, up to the next label.
+///
+/// What is known comes from following every path through the laid out program, so like the layout, this assumes
+/// branches only go to labels, and not to numeric offsets. Taking code out moves the rest around, which can change
+/// which pages code ends up on and how far jumps are. So the program is laid out again after every round of removals,
+/// every removal is checked again against the new layout, and the ones that no longer hold are put back, until nothing
+/// changes. If the program can't be laid out, it is returned as it is, so the error is reported on the code as written.
+///
+/// Returns the program without the removed nodes, along with what was removed in the order it appears in the program.
+///
+/// # Arguments
+///
+/// * `program`: The program with its macros expanded, its variables allocated and its subroutines placed.
+pub fn optimize<'a>(program: &[Node<'a>]) -> (Vec<Node<'a>>, Vec<Removal>) {
+    let mut removed: Vec<Option<Removal>> = vec![None; program.len()];
+    // Nodes whose removal was put back. They are never removed again, so the rounds can't go back and forth forever.
+    let mut kept = vec![false; program.len()];
+    let mut last_round = vec![];
+
+    loop {
+        let survivors: Vec<usize> = (0..program.len()).filter(|&i| removed[i].is_none()).collect();
+        let candidate: Vec<Node> = survivors.iter().map(|&i| program[i].clone()).collect();
+
+        let Ok((layout, symbols)) = lay_out(&candidate) else {
+            if last_round.is_empty() {
+                return (program.to_vec(), vec![]);
+            }
+            for i in last_round.drain(..) {
+                removed[i] = None;
+                kept[i] = true;
+            }
+            continue;
+        };
+        let flows = flow::node_flows(&candidate, &layout, &symbols);
+        let states = flow::known_states(&flows, &layout);
+
+        // A removal is checked where the node used to be, which is right in front of the next node that is left.
+        let wrong: Vec<usize> = (0..program.len())
+            .filter(|&i| {
+                let Some(removal) = removed[i] else {
+                    return false;
+                };
+                let next = survivors.partition_point(|&survivor| survivor < i);
+                // Nothing follows a node removed from the end of the program, so what it saw is what the last node
+                // left behind.
+                let state = states.get(next).copied().or_else(|| {
+                    let last = next.checked_sub(1)?;
+                    flows[last].falls_through.then(|| states[last].exits(&flows[last]).0)
+                });
+                let holds = match removal.reason {
+                    Reason::PageBuffer(page) => state.is_some_and(|state| state.page_buffer == Some(page)),
+                    Reason::StatusFlag(set) => state.is_some_and(|state| state.status_flag == Some(set)),
+                    Reason::SubroutineJump(set) => state.is_some_and(|state| state.subroutine_jump == Some(set)),
+                    Reason::SelfMove => true,
+                    Reason::Unreachable => {
+                        next > 0 && never_continues(&candidate[next - 1], &flows[next - 1], states[next - 1])
+                    }
+                };
+                !holds
+            })
+            .collect();
+        if !wrong.is_empty() {
+            for i in wrong {
+                removed[i] = None;
+                kept[i] = true;
+            }
+            last_round.clear();
+            continue;
+        }
+
+        last_round.clear();
+        let mut unreachable = false;
+        for (j, node) in candidate.iter().enumerate() {
+            let i = survivors[j];
+            if !matches!(node, Node::Instruction { .. } | Node::PseudoInstruction { .. }) {
+                unreachable = false;
+                continue;
+            }
+
+            let reason = if unreachable { Some(Reason::Unreachable) } else { redundant(&flows[j], states[j]) };
+            if let Some(reason) = reason.filter(|_| !kept[i]) {
+                removed[i] = Some(Removal { location: node.location(), reason, size: layout.size(j) });
+                last_round.push(i);
+            }
+            unreachable |= never_continues(node, &flows[j], states[j]);
+        }
+        if last_round.is_empty() {
+            break;
+        }
+    }
+
+    let optimized = program.iter().zip(&removed).filter(|(_, removal)| removal.is_none()).map(|(node, _)| node);
+    (optimized.cloned().collect(), removed.into_iter().flatten().collect())
+}
+
+/// Works out why a node that is a single instruction doesn't change anything, if it doesn't.
+fn redundant(flow: &NodeFlow, state: KnownState) -> Option<Reason> {
+    match flow.instructions.as_deref()? {
+        [Instruction::LPB { immediate }] => {
+            let page = u8::from(*immediate);
+            (state.page_buffer == Some(page)).then_some(Reason::PageBuffer(page))
+        }
+        [Instruction::SSF] => (state.status_flag == Some(true)).then_some(Reason::StatusFlag(true)),
+        [Instruction::RSF] => (state.status_flag == Some(false)).then_some(Reason::StatusFlag(false)),
+        [Instruction::SSJ] => (state.subroutine_jump == Some(true)).then_some(Reason::SubroutineJump(true)),
+        [Instruction::RSJ] => (state.subroutine_jump == Some(false)).then_some(Reason::SubroutineJump(false)),
+        [Instruction::MOV { register_from_id, register_to_id }] => {
+            (u8::from(*register_from_id) == u8::from(*register_to_id)).then_some(Reason::SelfMove)
+        }
+        _ => None,
+    }
+}
+
+/// Returns whether execution never continues with the node after `node`, because it returns, or because it ends with a
+/// `BRN` that always jumps and never calls a subroutine.
+fn never_continues(node: &Node, flow: &NodeFlow, state: KnownState) -> bool {
+    if !matches!(node, Node::Instruction { .. } | Node::PseudoInstruction { .. }) {
+        return false;
+    }
+    if !flow.falls_through {
+        return true;
+    }
+
+    match flow.instructions.as_deref().and_then(<[_]>::split_last) {
+        Some((Instruction::BRN { .. }, rest)) => {
+            let before = rest.iter().fold(state, |state, instruction| state.after(instruction));
+            before.status_flag == Some(true) && before.subroutine_jump == Some(false)
+        }
+        _ => false,
+    }
+}
+
+/// Describes what was removed, one line for each removal, followed by how many bytes that saved.
+///
+/// # Arguments
+///
+/// * `removals`: What was removed, as returned by `optimize`.
+/// * `sources`: The files the program was read from, to show the code that was removed.
+/// * `expansions`: The macro expansions in the program, used to show which call removed code from a macro belongs to.
+pub fn report(removals: &[Removal], sources: &Sources, expansions: &[Expansion]) -> String {
+    let mut text = String::new();
+    for removal in removals {
+        let location = removal.location;
+        write!(text, "{}: removed `{}`", sources.position(location), code(sources, location)).unwrap();
+        if let Some(expansion) = location.expansion {
+            let Expansion { name, call, .. } = expansions[expansion as usize];
+            write!(text, " from {name} at {}", sources.position(call)).unwrap();
+        }
+        writeln!(text, ", {}", removal.reason.describe()).unwrap();
+    }
+
+    let count = removals.len();
+    let size: usize = removals.iter().map(|removal| removal.size).sum();
+    let plural = |count: usize| if count == 1 { "" } else { "s" };
+    writeln!(text, "Removed {count} instruction{}, saving {size} byte{}", plural(count), plural(size)).unwrap();
+    text
+}
+
+/// Gets the code of a statement, from where it starts to the end of its line, leaving out any comment.
+fn code(sources: &Sources, location: Location) -> &str {
+    let text = &sources.file(location.file).text;
+    let line = text[location.index as usize..].lines().next().unwrap_or_default();
+    line.split(';').next().unwrap_or_default().trim_end()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn optimize_text(program: &str) -> Vec<(u32, Reason)> {
+        let sources = Sources::new(PathBuf::from("test.asm"), program.to_owned());
+        let nodes = sources.parse().ok().unwrap();
+        let (_, removals) = optimize(&nodes);
+        removals.iter().map(|removal| (removal.location.line, removal.reason)).collect()
+    }
+
+    #[test]
+    fn removes_what_changes_nothing() {
+        let program = "lpb 0\nrsf\nmov x x\nmov x y\nssf\nloop:\nssf\nlpb 0\nrsj\nbrn loop\nnop\nret\nend:\nret\n";
+        assert_eq!(
+            optimize_text(program),
+            [
+                (1, Reason::PageBuffer(0)),
+                (2, Reason::StatusFlag(false)),
+                (3, Reason::SelfMove),
+                (7, Reason::StatusFlag(true)),
+                (8, Reason::PageBuffer(0)),
+                (9, Reason::SubroutineJump(false)),
+                (11, Reason::Unreachable),
+                (12, Reason::Unreachable),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_what_paths_disagree_on() {
+        // The branch back to `loop` is taken with the status flag set, and a subroutine call can change anything.
+        let program = "rsf\nloop:\nrsf\ncmp x\nbrn loop\nssj\nssf\nbrn end\nrsj\nlpb 0\nend:\nret\n";
+        assert_eq!(optimize_text(program), []);
+    }
+
+    #[test]
+    fn reports_removals() {
+        let program = ".macro SET\n    ssf ; again\n.endm\nssf\n    SET\n";
+        let sources = Sources::new(PathBuf::from("test.asm"), program.to_owned());
+        let mut expansions = vec![];
+        let nodes = crate::macros::expand(&sources.parse().ok().unwrap(), &mut expansions).ok().unwrap();
+        let (optimized, removals) = optimize(&nodes);
+
+        assert_eq!(optimized.len(), nodes.len() - 1);
+        assert_eq!(
+            report(&removals, &sources, &expansions),
+            "test.asm:2:5: removed `ssf` from SET at test.asm:5:5, the status flag is already set\n\
+             Removed 1 instruction, saving 1 byte\n"
+        );
+    }
+}
@@ -1,13 +1,150 @@
+use std::collections::HashMap;
 use std::iter::Peekable;
 use std::num::ParseIntError;
-use crate::lexer::{InstructionKind, NumberLiteralKind, Register, Token, TokenKind};
+use crate::lexer::{
+    unescape, DirectiveKind, InstructionKind, NumberLiteralKind, PseudoInstructionKind, Register, Token, TokenKind,
+};
+use crate::location::Location;
 
+/// The name of a label or constant. Names defined inside a macro belong to a single expansion of it, so the same text
+/// can name something different in each expansion.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Name<'a> {
+    pub text: &'a str,
+    /// The macro expansion the name is local to, if any.
+    pub expansion: Option<u32>,
+    /// Tells apart local and anonymous labels with the same text. Set by `scope_labels`.
+    pub scope: Option<u32>,
+}
+
+impl<'a> Name<'a> {
+    pub fn new(text: &'a str) -> Self {
+        Name { text, expansion: None, scope: None }
+    }
+
+    /// Whether this is a local label like `.loop`, which belongs to the global label before it.
+    pub fn is_local(&self) -> bool {
+        self.text.starts_with('.')
+    }
+
+    /// Whether this is an anonymous label like `1`, which is referred to as `1b` or `1f`.
+    pub fn is_anonymous(&self) -> bool {
+        self.text.chars().all(|c| c.is_ascii_digit())
+    }
+
+    /// Whether this is a reference to an anonymous label, like `1b` or `1f`.
+    pub fn is_anonymous_reference(&self) -> bool {
+        match self.text.strip_suffix(['b', 'f']) {
+            Some(digits) => !digits.is_empty() && Name::new(digits).is_anonymous(),
+            None => false,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub enum Node<'a> {
-    Label { name: &'a str },
-    LabelReference { name: &'a str },
-    Instruction { kind: InstructionKind, arguments: Vec<Node<'a>> },
-    RegisterLiteral { register: Register },
-    NumberLiteral { value: u8 },
+    Label { name: Name<'a>, location: Location },
+    LabelReference { name: Name<'a>, location: Location },
+    Instruction { kind: InstructionKind, arguments: Vec<Node<'a>>, location: Location },
+    PseudoInstruction { kind: PseudoInstructionKind, arguments: Vec<Node<'a>>, location: Location },
+    Directive { kind: DirectiveKind, arguments: Vec<Node<'a>>, location: Location },
+    RegisterLiteral { register: Register, location: Location },
+    NumberLiteral { value: u16, location: Location },
+    /// A string in double quotes. The value doesn't include the quotes.
+    StringLiteral { value: &'a str, location: Location },
+    Negation { operand: Box<Node<'a>>, location: Location },
+    BinaryOperation { operator: BinaryOperator, left: Box<Node<'a>>, right: Box<Node<'a>>, location: Location },
+    FunctionCall { function: Function, argument: Box<Node<'a>>, location: Location },
+    MacroCall { name: &'a str, arguments: Vec<Node<'a>>, location: Location },
+}
+
+impl Node<'_> {
+    pub fn location(&self) -> Location {
+        match self {
+            Node::Label { location, .. } => *location,
+            Node::LabelReference { location, .. } => *location,
+            Node::Instruction { location, .. } => *location,
+            Node::PseudoInstruction { location, .. } => *location,
+            Node::Directive { location, .. } => *location,
+            Node::RegisterLiteral { location, .. } => *location,
+            Node::NumberLiteral { location, .. } => *location,
+            Node::StringLiteral { location, .. } => *location,
+            Node::Negation { location, .. } => *location,
+            Node::BinaryOperation { location, .. } => *location,
+            Node::FunctionCall { location, .. } => *location,
+            Node::MacroCall { location, .. } => *location,
+        }
+    }
+
+    /// Whether the node is an expression that evaluates to a number.
+    pub fn is_expression(&self) -> bool {
+        matches!(
+            self,
+            Node::LabelReference { .. }
+                | Node::NumberLiteral { .. }
+                | Node::Negation { .. }
+                | Node::BinaryOperation { .. }
+                | Node::FunctionCall { .. }
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BinaryOperator {
+    Add,
+    Subtract,
+    And,
+    Or,
+    ShiftLeft,
+    ShiftRight,
+}
+
+impl BinaryOperator {
+    fn from_token(kind: TokenKind) -> Option<Self> {
+        match kind {
+            TokenKind::Plus => Some(BinaryOperator::Add),
+            TokenKind::Minus => Some(BinaryOperator::Subtract),
+            TokenKind::Ampersand => Some(BinaryOperator::And),
+            TokenKind::Pipe => Some(BinaryOperator::Or),
+            TokenKind::ShiftLeft => Some(BinaryOperator::ShiftLeft),
+            TokenKind::ShiftRight => Some(BinaryOperator::ShiftRight),
+            _ => None,
+        }
+    }
+
+    /// How tightly the operator binds. Operators with a higher precedence are applied first, like in C.
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOperator::Or => 0,
+            BinaryOperator::And => 1,
+            BinaryOperator::ShiftLeft | BinaryOperator::ShiftRight => 2,
+            BinaryOperator::Add | BinaryOperator::Subtract => 3,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Function {
+    /// The page of an index into program memory.
+    Page,
+    /// The offset within its page of an index into program memory.
+    Offset,
+    /// The high nibble of a byte.
+    Hi,
+    /// The low nibble of a byte.
+    Lo,
+}
+
+impl Function {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "page" => Some(Function::Page),
+            "offset" => Some(Function::Offset),
+            "hi" => Some(Function::Hi),
+            "lo" => Some(Function::Lo),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Eq, PartialEq)]
@@ -16,13 +153,22 @@ pub enum ErrorTokenKind {
     Colon,
     LabelIdentifier,
     Instruction,
+    Directive,
     NumberLiteral,
     RegisterLiteral,
+    Operator,
+    OpenParen,
+    CloseParen,
 }
 
 pub enum ParseErrorKind {
     InvalidNumberLiteral { cause: ParseIntError },
     UnexpectedToken { expected_types: Vec<ErrorTokenKind> },
+    UnknownFunction,
+    /// A character or string literal that isn't closed, or a character literal that doesn't hold one character.
+    InvalidLiteral,
+    /// A label definition using a name like `1b` that only refers to anonymous labels.
+    ReservedLabelName,
 }
 
 pub struct ParseError<'a> {
@@ -31,7 +177,6 @@ pub struct ParseError<'a> {
     pub help: Option<String>,
 }
 
-// TODO: better errors
 pub struct Parser<'a, TIter> where TIter: Iterator<Item=Token<'a>> {
     input_tokens: Peekable<TIter>,
 }
@@ -43,34 +188,59 @@ impl<'a, TIter> Parser<'a, TIter> where TIter: Iterator<Item=Token<'a>> {
         }
     }
 
-    pub fn parse(&mut self) -> Result<Vec<Node>, Vec<ParseError>> {
+    pub fn parse(&mut self) -> Result<Vec<Node<'a>>, Vec<ParseError<'a>>> {
         let mut program: Vec<Node> = vec![];
         let mut errors: Vec<ParseError> = vec![];
 
         while let Some(token) = self.input_tokens.next() {
-            match token {
-                Token { kind: TokenKind::Newline, .. } => {}
-                Token { kind: TokenKind::LabelIdentifier, text, .. } => {
-                    match self.parse_label(text) {
-                        Ok(n) => program.push(n),
-                        Err(err) => errors.push(err)
-                    }
+            let result = match token {
+                Token { kind: TokenKind::Newline, .. } => continue,
+                // Anything that looks like a label but isn't followed by a colon is a macro call.
+                Token { kind: TokenKind::LabelIdentifier, text, location }
+                if !matches!(self.input_tokens.peek(), Some(Token { kind: TokenKind::Colon, .. })) => {
+                    self.parse_arguments().map(|arguments| Node::MacroCall { name: text, arguments, location })
+                }
+                // `1b` and `1f` always refer to anonymous labels, so no label can be named like them.
+                label @ Token { kind: TokenKind::LabelIdentifier, text, .. }
+                if Name::new(text).is_anonymous_reference() => {
+                    let digits = &text[..text.len() - 1];
+                    Err(ParseError {
+                        token: Some(label),
+                        kind: ParseErrorKind::ReservedLabelName,
+                        help: Some(format!("Anonymous labels are defined with digits alone, like `{digits}:`")),
+                    })
                 }
-                Token { kind: TokenKind::Instruction { kind }, .. } => {
-                    match self.parse_instruction(kind) {
-                        Ok(n) => program.push(n),
-                        Err(err) => errors.push(err)
-                    }
+                Token { kind: TokenKind::LabelIdentifier, text, location } => self.parse_label(text, location),
+                // A number followed by a colon is an anonymous label.
+                Token { kind: TokenKind::NumberLiteral { kind: NumberLiteralKind::Decimal }, text, location }
+                if matches!(self.input_tokens.peek(), Some(Token { kind: TokenKind::Colon, .. })) => {
+                    self.parse_label(text, location)
                 }
-                other => errors.push(ParseError {
+                Token { kind: TokenKind::Instruction { kind }, location, .. } => self.parse_instruction(kind, location),
+                Token { kind: TokenKind::PseudoInstruction { kind }, location, .. } => {
+                    self.parse_arguments().map(|arguments| Node::PseudoInstruction { kind, arguments, location })
+                }
+                Token { kind: TokenKind::Directive { kind }, location, .. } => self.parse_directive(kind, location),
+                other => Err(ParseError {
                     token: Some(other),
                     kind: ParseErrorKind::UnexpectedToken {
                         expected_types: vec![
-                            ErrorTokenKind::Newline, ErrorTokenKind::LabelIdentifier, ErrorTokenKind::Instruction,
+                            ErrorTokenKind::Newline,
+                            ErrorTokenKind::LabelIdentifier,
+                            ErrorTokenKind::Instruction,
+                            ErrorTokenKind::Directive,
                         ]
                     },
                     help: None,
                 })
+            };
+
+            match result {
+                Ok(node) => program.push(node),
+                Err(err) => {
+                    self.recover(&err);
+                    errors.push(err);
+                }
             }
         }
 
@@ -81,9 +251,24 @@ impl<'a, TIter> Parser<'a, TIter> where TIter: Iterator<Item=Token<'a>> {
         }
     }
 
-    pub fn parse_label(&mut self, text: &'a str) -> Result<Node<'a>, ParseError<'a>> {
+    /// Skips the rest of the line an error was found on, so the next line is parsed on its own instead of the rest of
+    /// this one causing more errors.
+    fn recover(&mut self, error: &ParseError<'a>) {
+        // Every error takes the token it is about out of the stream, which might already be the end of the line.
+        if matches!(error.token, None | Some(Token { kind: TokenKind::Newline, .. })) {
+            return;
+        }
+
+        for token in self.input_tokens.by_ref() {
+            if token.kind == TokenKind::Newline {
+                break;
+            }
+        }
+    }
+
+    pub fn parse_label(&mut self, text: &'a str, location: Location) -> Result<Node<'a>, ParseError<'a>> {
         match self.input_tokens.next() {
-            Some(Token { kind: TokenKind::Colon, .. }) => Ok(Node::Label { name: text }),
+            Some(Token { kind: TokenKind::Colon, .. }) => Ok(Node::Label { name: Name::new(text), location }),
             other => Err(ParseError {
                 token: other,
                 kind: ParseErrorKind::UnexpectedToken { expected_types: vec![ErrorTokenKind::Colon] },
@@ -92,53 +277,317 @@ impl<'a, TIter> Parser<'a, TIter> where TIter: Iterator<Item=Token<'a>> {
         }
     }
 
-    pub fn parse_instruction(&mut self, kind: InstructionKind) -> Result<Node<'a>, ParseError<'a>> {
-        let mut args = vec![];
+    pub fn parse_instruction(&mut self, kind: InstructionKind, location: Location) -> Result<Node<'a>, ParseError<'a>> {
+        Ok(Node::Instruction {
+            kind,
+            arguments: self.parse_arguments()?,
+            location,
+        })
+    }
 
-        while let Some(token) = self.input_tokens.next() {
-            match token {
-                Token { kind: TokenKind::Newline, .. } => break,
-                Token { kind: TokenKind::LabelIdentifier, text, .. } =>
-                    args.push(Node::LabelReference { name: text }),
-                Token { kind: TokenKind::RegisterLiteral { register }, .. } =>
-                    args.push(Node::RegisterLiteral { register }),
-                Token { kind: TokenKind::NumberLiteral { kind: num_kind }, text, .. } => {
-                    match parse_number_literal(num_kind, text) {
-                        Ok(value) => args.push(Node::NumberLiteral { value }),
-                        Err(err) => return Err(ParseError {
-                            token: Some(token),
-                            kind: ParseErrorKind::InvalidNumberLiteral { cause: err },
-                            help: None,
-                        })
-                    }
+    /// Parses a directive and its arguments. The first argument of a directive defining a name, like `.equ NAME value`,
+    /// is the name alone, so a value after it starting with `-` or `(` isn't taken as part of it.
+    fn parse_directive(&mut self, kind: DirectiveKind, location: Location) -> Result<Node<'a>, ParseError<'a>> {
+        let mut arguments = vec![];
+        if matches!(kind, DirectiveKind::Equ | DirectiveKind::Var) {
+            if let Some(&Token { kind: TokenKind::LabelIdentifier, text, location }) = self.input_tokens.peek() {
+                if !text.starts_with(['\'', '"']) {
+                    self.input_tokens.next();
+                    arguments.push(Node::LabelReference { name: Name::new(text), location });
                 }
-                other => return Err(ParseError {
-                    token: Some(other),
-                    kind: ParseErrorKind::UnexpectedToken {
-                        expected_types: vec![
-                            ErrorTokenKind::Newline,
-                            ErrorTokenKind::LabelIdentifier,
-                            ErrorTokenKind::RegisterLiteral,
-                            ErrorTokenKind::NumberLiteral,
-                        ]
-                    },
+            }
+        }
+
+        let arguments = self.continue_arguments(arguments)?;
+        Ok(Node::Directive { kind, arguments, location })
+    }
+
+    /// Parses the arguments following an instruction up to and including the end of the line. Arguments can be
+    /// separated by commas, but don't have to be.
+    pub fn parse_arguments(&mut self) -> Result<Vec<Node<'a>>, ParseError<'a>> {
+        self.continue_arguments(vec![])
+    }
+
+    /// Parses the rest of the arguments on a line, after the ones in `args`.
+    fn continue_arguments(&mut self, mut args: Vec<Node<'a>>) -> Result<Vec<Node<'a>>, ParseError<'a>> {
+        let mut after_comma = false;
+
+        while let Some(token) = self.input_tokens.peek() {
+            match token.kind {
+                TokenKind::Newline if !after_comma => {
+                    self.input_tokens.next();
+                    break;
+                }
+                TokenKind::Comma if !args.is_empty() && !after_comma => {
+                    self.input_tokens.next();
+                    after_comma = true;
+                    continue;
+                }
+                TokenKind::RegisterLiteral { register } => {
+                    args.push(Node::RegisterLiteral { register, location: token.location });
+                    self.input_tokens.next();
+                }
+                TokenKind::StringLiteral => {
+                    let value = &token.text[1..token.text.len() - 1];
+                    args.push(Node::StringLiteral { value, location: token.location });
+                    self.input_tokens.next();
+                }
+                _ => args.push(self.parse_expression(0)?),
+            }
+            after_comma = false;
+        }
+
+        Ok(args)
+    }
+
+    /// Parses an expression, only taking in binary operators that bind at least as tightly as `min_precedence`.
+    fn parse_expression(&mut self, min_precedence: u8) -> Result<Node<'a>, ParseError<'a>> {
+        let mut left = self.parse_unary()?;
+
+        while let Some(operator) = self.input_tokens.peek().and_then(|token| BinaryOperator::from_token(token.kind)) {
+            if operator.precedence() < min_precedence {
+                break;
+            }
+            self.input_tokens.next();
+
+            let right = self.parse_expression(operator.precedence() + 1)?;
+            let location = left.location();
+            left = Node::BinaryOperation { operator, left: Box::new(left), right: Box::new(right), location };
+        }
+
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<Node<'a>, ParseError<'a>> {
+        match self.input_tokens.next() {
+            Some(Token { kind: TokenKind::Minus, location, .. }) => {
+                Ok(Node::Negation { operand: Box::new(self.parse_unary()?), location })
+            }
+            Some(token @ Token { kind: TokenKind::NumberLiteral { kind }, .. }) => match parse_number_literal(kind, token.text) {
+                Ok(value) => Ok(Node::NumberLiteral { value, location: token.location }),
+                Err(err) => Err(ParseError {
+                    token: Some(token),
+                    kind: ParseErrorKind::InvalidNumberLiteral { cause: err },
                     help: None,
-                })
+                }),
+            },
+            // Malformed character and string literals are passed on as identifiers.
+            Some(token @ Token { kind: TokenKind::LabelIdentifier, .. }) if token.text.starts_with(['\'', '"']) => {
+                let help = if token.text.starts_with('"') {
+                    "String literals end with a '\"' on the same line"
+                } else {
+                    "Character literals hold a single character, like 'H' or '\\n'"
+                };
+                Err(ParseError { token: Some(token), kind: ParseErrorKind::InvalidLiteral, help: Some(help.to_owned()) })
+            }
+            Some(token @ Token { kind: TokenKind::LabelIdentifier, .. }) => {
+                if !matches!(self.input_tokens.peek(), Some(Token { kind: TokenKind::OpenParen, .. })) {
+                    return Ok(Node::LabelReference { name: Name::new(token.text), location: token.location });
+                }
+
+                let Some(function) = Function::from_str(token.text) else {
+                    return Err(ParseError {
+                        token: Some(token),
+                        kind: ParseErrorKind::UnknownFunction,
+                        help: Some("The available functions are page, offset, hi and lo".to_owned()),
+                    });
+                };
+                self.input_tokens.next();
+                let argument = self.parse_parenthesized()?;
+                Ok(Node::FunctionCall { function, argument: Box::new(argument), location: token.location })
             }
+            Some(Token { kind: TokenKind::OpenParen, .. }) => self.parse_parenthesized(),
+            other => Err(ParseError {
+                token: other,
+                kind: ParseErrorKind::UnexpectedToken {
+                    expected_types: vec![
+                        ErrorTokenKind::Newline,
+                        ErrorTokenKind::LabelIdentifier,
+                        ErrorTokenKind::RegisterLiteral,
+                        ErrorTokenKind::NumberLiteral,
+                        ErrorTokenKind::OpenParen,
+                    ]
+                },
+                help: None,
+            })
         }
+    }
 
-        Ok(Node::Instruction {
-            kind,
-            arguments: args,
-        })
+    /// Parses the rest of an expression in parentheses, after the opening parenthesis.
+    fn parse_parenthesized(&mut self) -> Result<Node<'a>, ParseError<'a>> {
+        let expression = self.parse_expression(0)?;
+        match self.input_tokens.next() {
+            Some(Token { kind: TokenKind::CloseParen, .. }) => Ok(expression),
+            other => Err(ParseError {
+                token: other,
+                kind: ParseErrorKind::UnexpectedToken {
+                    expected_types: vec![ErrorTokenKind::Operator, ErrorTokenKind::CloseParen]
+                },
+                help: None,
+            })
+        }
+    }
+}
+
+/// Gives local and anonymous labels, and the references to them, the scope that tells them apart from others with the
+/// same text. This has to see the whole program at once, with included files in place, and after conditionals and
+/// macros have been resolved, so that labels in branches that aren't assembled and in macro bodies aren't counted.
+///
+/// A local label like `.loop` belongs to the closest global label before it, so every global label can have its own
+/// `.loop`. Each anonymous label like `1:` is a label of its own, and `1b` and `1f` refer to the closest `1:` before
+/// and after them. References to anonymous labels that don't exist are left as they are, to be reported as undefined.
+///
+/// Labels defined by a macro only see the other labels of the same expansion, and the labels around an expansion
+/// don't see into it, so a global label in a macro doesn't start a new scope for the local labels around the call.
+pub fn scope_labels(program: &mut [Node]) {
+    let mut globals: HashMap<Option<u32>, u32> = HashMap::new();
+    let mut anonymous = 0;
+    let mut definitions = AnonymousLabels::new();
+
+    for (i, node) in program.iter_mut().enumerate() {
+        let Node::Label { name, .. } = node else {
+            continue;
+        };
+
+        if name.is_anonymous() {
+            anonymous += 1;
+            name.scope = Some(anonymous);
+            definitions.entry((name.text, name.expansion)).or_default().push((i, anonymous));
+        } else if name.is_local() {
+            name.scope = Some(globals.get(&name.expansion).copied().unwrap_or_default());
+        } else {
+            *globals.entry(name.expansion).or_default() += 1;
+        }
+    }
+
+    let mut globals = HashMap::new();
+    for (i, node) in program.iter_mut().enumerate() {
+        match node {
+            Node::Label { name, .. } if !name.is_local() && !name.is_anonymous() => {
+                *globals.entry(name.expansion).or_default() += 1
+            }
+            Node::Instruction { arguments, .. }
+            | Node::PseudoInstruction { arguments, .. }
+            | Node::Directive { arguments, .. }
+            | Node::MacroCall { arguments, .. } => {
+                for argument in arguments {
+                    scope_references(argument, i, &globals, &definitions);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Where each anonymous label is, as the index of its node and its scope, by its text and expansion.
+type AnonymousLabels<'a> = HashMap<(&'a str, Option<u32>), Vec<(usize, u32)>>;
+
+fn scope_references<'a>(
+    node: &mut Node<'a>,
+    index: usize,
+    globals: &HashMap<Option<u32>, u32>,
+    definitions: &AnonymousLabels,
+) {
+    match node {
+        Node::LabelReference { name, .. } if name.is_local() => {
+            name.scope = Some(globals.get(&name.expansion).copied().unwrap_or_default())
+        }
+        Node::LabelReference { name, .. } => {
+            if !name.is_anonymous_reference() {
+                return;
+            }
+            let (text, direction) = name.text.split_at(name.text.len() - 1);
+
+            let candidates = definitions.get(&(text, name.expansion)).map(Vec::as_slice).unwrap_or_default();
+            let found = match direction {
+                "b" => candidates.iter().rev().find(|(definition, _)| *definition < index),
+                _ => candidates.iter().find(|(definition, _)| *definition > index),
+            };
+            if let Some((_, scope)) = found {
+                *name = Name { text, scope: Some(*scope), ..*name };
+            }
+        }
+        Node::Negation { operand, .. } => scope_references(operand, index, globals, definitions),
+        Node::BinaryOperation { left, right, .. } => {
+            scope_references(left, index, globals, definitions);
+            scope_references(right, index, globals, definitions);
+        }
+        Node::FunctionCall { argument, .. } => scope_references(argument, index, globals, definitions),
+        _ => {}
     }
 }
 
-fn parse_number_literal(kind: NumberLiteralKind, text: &str) -> Result<u8, ParseIntError> {
+fn parse_number_literal(kind: NumberLiteralKind, text: &str) -> Result<u16, ParseIntError> {
     match kind {
-        NumberLiteralKind::Decimal => u8::from_str_radix(text, 10),
-        NumberLiteralKind::Hex => u8::from_str_radix(&text[2..], 16),
-        NumberLiteralKind::Binary => u8::from_str_radix(&text[2..], 2)
+        NumberLiteralKind::Decimal => text.parse(),
+        NumberLiteralKind::Hex => u16::from_str_radix(&text[2..], 16),
+        NumberLiteralKind::Binary => u16::from_str_radix(&text[2..], 2),
+        // The lexer only lets through well-formed character literals.
+        NumberLiteralKind::Character => match text.as_bytes() {
+            [b'\'', b'\\', escaped, b'\''] => Ok(unescape(*escaped as char).unwrap_or_default() as u16),
+            [b'\'', c, b'\''] => Ok(*c as u16),
+            _ => unreachable!("Character literals are a single character in quotes"),
+        },
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    fn parse(program: &str) -> Result<Vec<Node<'_>>, Vec<ParseError<'_>>> {
+        let mut lexer = Lexer::new(program, 0);
+        let mut parser = Parser::new(lexer.iter());
+        parser.parse()
+    }
+
+    #[test]
+    fn reports_one_error_per_bad_line() {
+        let errors = parse("ldi x (1 2 3) 4\nmov ) z : 5\nnop\nldi x (1\nnop\n").err().unwrap();
+        let lines: Vec<_> = errors.iter().map(|error| error.token.as_ref().unwrap().location.line).collect();
+        assert_eq!(lines, [1, 2, 4]);
+    }
+
+    #[test]
+    fn carries_on_after_an_error() {
+        let errors = parse("5 nop\nlabel: ldi x 0b2\n").err().unwrap();
+        assert!(matches!(
+            errors.as_slice(),
+            [
+                ParseError { kind: ParseErrorKind::UnexpectedToken { .. }, .. },
+                ParseError { kind: ParseErrorKind::InvalidNumberLiteral { .. }, .. },
+            ]
+        ));
+    }
+
+    #[test]
+    fn names_defined_by_directives_stand_alone() {
+        let program = parse(".equ K -1\n.equ L (1 + 2)\n.var V, 2\n.equ M N - 1\n").ok().unwrap();
+        let arguments: Vec<_> = program
+            .iter()
+            .map(|node| match node {
+                Node::Directive { arguments, .. } => arguments.as_slice(),
+                _ => panic!("Expected a directive"),
+            })
+            .collect();
+        assert!(matches!(arguments[0], [Node::LabelReference { .. }, Node::Negation { .. }]));
+        assert!(matches!(arguments[1], [Node::LabelReference { .. }, Node::BinaryOperation { .. }]));
+        assert!(matches!(arguments[2], [Node::LabelReference { .. }, Node::NumberLiteral { value: 2, .. }]));
+        assert!(matches!(
+            arguments[3],
+            [Node::LabelReference { name: Name { text: "M", .. }, .. }, Node::BinaryOperation { .. }]
+        ));
+    }
+
+    #[test]
+    fn rejects_defining_anonymous_references() {
+        let errors = parse("1b: nop
+0b:
+1: brn 1b
+").err().unwrap();
+        let texts: Vec<_> = errors.iter().map(|error| error.token.as_ref().unwrap().text).collect();
+        assert_eq!(texts, ["1b", "0b"]);
+        assert!(errors.iter().all(|error| matches!(error.kind, ParseErrorKind::ReservedLabelName)));
+    }
+}
@@ -0,0 +1,502 @@
+use crate::error::{AssemblyError, AssemblyErrorKind};
+use crate::flow;
+use crate::layout::{lay_out, JumpForm, Layout};
+use crate::lexer::{DirectiveKind, InstructionKind};
+use crate::location::Location;
+use crate::parser::{Name, Node};
+use crate::symbols::SymbolTable;
+use common::architecture::{NUM_PAGES, PAGE_SIZE};
+use std::ops::Range;
+
+/// A subroutine declared between `.sub name` and `.endsub`.
+struct Routine<'a> {
+    name: Name<'a>,
+    /// Where the `.sub` directive is.
+    location: Location,
+    /// The indices of the nodes between `.sub` and `.endsub`.
+    body: Range<usize>,
+}
+
+impl Routine<'_> {
+    /// The most instructions a copy of the subroutine can take up, assuming every far jump in it needs its longest
+    /// form.
+    fn max_size(&self, program: &[Node]) -> usize {
+        program[self.body.clone()]
+            .iter()
+            .map(|node| match node {
+                Node::Instruction { .. } => 1,
+                Node::PseudoInstruction { .. } => JumpForm { load_page: true, set_flag: true }.len(),
+                _ => 0,
+            })
+            .sum()
+    }
+}
+
+/// A program with its subroutines copied onto the pages they are called from.
+struct Expanded<'a> {
+    program: Vec<Node<'a>>,
+    /// The subroutine each node is a copy of a part of, if any.
+    owners: Vec<Option<usize>>,
+}
+
+/// A subroutine call found in a laid out program.
+struct Call {
+    /// The index of the `BRN` node making the call.
+    node: usize,
+    routine: usize,
+    page: usize,
+}
+
+/// Copies every subroutine onto each page it is called from, so that every subroutine call stays within its page.
+///
+/// A subroutine jump ignores the page buffer, so the code calling a subroutine and the subroutine itself have to share
+/// a page. Subroutines are declared with `.sub name` and `.endsub` and are taken out of the normal flow of the
+/// program. Each page that calls a subroutine gets its own copy of it at the end of the page, and labels inside a
+/// subroutine always refer to the copy on the same page. Subroutines that are never called are left out.
+///
+/// A copy can only be reached from its own page, so every page with a call needs a copy, and all the calls on a page
+/// share it. Code isn't moved between pages to save copies, since it is placed in the order it is written. Making room
+/// for a copy can push calls onto other pages, so this repeats until every call has a copy on its page. Copies left
+/// without any calls are taken away, unless they turn out to be needed again, so this always finishes.
+///
+/// # Arguments
+///
+/// * `program`: The nodes produced by the parser.
+pub fn place<'a>(program: &[Node<'a>]) -> Result<Vec<Node<'a>>, Vec<AssemblyError<'a>>> {
+    let routines = find_routines(program)?;
+    if routines.is_empty() {
+        return Ok(program.to_vec());
+    }
+
+    // Start out by finding the calls without any subroutines in the way.
+    let mut expanded = expand(program, &routines, &[], true);
+    let (layout, symbols) = lay_out(&expanded.program)?;
+    let mut calls = find_calls(&expanded, &routines, &layout, &symbols);
+
+    let mut pages: Vec<Vec<usize>> = vec![vec![]; NUM_PAGES];
+    // The copies taken away for having no calls left, as their page and subroutine. One that is needed again after
+    // being taken away is kept from then on, so copies can't keep coming and going.
+    let mut removed: Vec<(usize, usize)> = vec![];
+    let mut pinned: Vec<(usize, usize)> = vec![];
+    loop {
+        let mut changed = false;
+        for call in &calls {
+            if !pages[call.page].contains(&call.routine) {
+                pages[call.page].push(call.routine);
+                if removed.contains(&(call.page, call.routine)) {
+                    pinned.push((call.page, call.routine));
+                }
+                changed = true;
+            }
+        }
+        if !changed {
+            // Making room for a copy can push the calls that needed an earlier copy onto the next page, leaving that
+            // copy with nothing calling it.
+            for (page, on_page) in pages.iter_mut().enumerate() {
+                on_page.retain(|&routine| {
+                    let called = calls.iter().any(|call| call.page == page && call.routine == routine);
+                    if called || pinned.contains(&(page, routine)) {
+                        return true;
+                    }
+                    removed.push((page, routine));
+                    changed = true;
+                    false
+                });
+            }
+        }
+        let copies = arrange(program, &routines, &pages, &expanded, &calls)?;
+        if !changed {
+            return Ok(expand(program, &routines, &copies, false).program);
+        }
+
+        expanded = expand(program, &routines, &copies, true);
+        let (layout, symbols) = lay_out(&expanded.program)?;
+        calls = find_calls(&expanded, &routines, &layout, &symbols);
+        check_nesting(&expanded, &routines, &calls)?;
+    }
+}
+
+/// Works out where every copy goes, packing the copies on each page back to back at the end of it. Returns the
+/// subroutine and index into program memory of every copy.
+fn arrange<'a>(
+    program: &[Node<'a>],
+    routines: &[Routine<'a>],
+    pages: &[Vec<usize>],
+    expanded: &Expanded<'a>,
+    calls: &[Call],
+) -> Result<Vec<(usize, usize)>, Vec<AssemblyError<'a>>> {
+    let mut errors = vec![];
+    for (page, on_page) in pages.iter().enumerate() {
+        let total: usize = on_page.iter().map(|&routine| routines[routine].max_size(program)).sum();
+        if total <= PAGE_SIZE {
+            continue;
+        }
+
+        // Blame the call that needed the copy which no longer fits.
+        let mut used = 0;
+        for &routine in on_page {
+            used += routines[routine].max_size(program);
+            if used <= PAGE_SIZE {
+                continue;
+            }
+
+            let call = calls.iter().find(|call| call.page == page && call.routine == routine);
+            let location = call.map_or(routines[routine].location, |call| expanded.program[call.node].location());
+            let chain = call.map_or(vec![routines[routine].name], |call| chain(expanded, routines, calls, call));
+            errors.push(AssemblyError {
+                location,
+                kind: AssemblyErrorKind::SubroutineDoesNotFit {
+                    name: routines[routine].name,
+                    page,
+                    chain: chain.into_boxed_slice(),
+                },
+                help: Some(format!(
+                    "Every page that calls a subroutine needs its own copy of it, and the copies needed on page {page} \
+                    take up {total} instructions. Make the subroutines smaller or call them from fewer pages"
+                )),
+            });
+            break;
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut copies = vec![];
+    for (page, on_page) in pages.iter().enumerate() {
+        let mut end = (page + 1) * PAGE_SIZE;
+        for &routine in on_page {
+            end -= routines[routine].max_size(program);
+            copies.push((routine, end));
+        }
+    }
+    Ok(copies)
+}
+
+/// Finds every `.sub` block in a program.
+fn find_routines<'a>(program: &[Node<'a>]) -> Result<Vec<Routine<'a>>, Vec<AssemblyError<'a>>> {
+    let mut routines = vec![];
+    let mut errors = vec![];
+    let mut open: Option<(Name<'a>, Location, usize)> = None;
+
+    for (i, node) in program.iter().enumerate() {
+        let Node::Directive { kind, arguments, location } = node else {
+            continue;
+        };
+
+        match (kind, open) {
+            (DirectiveKind::Sub, Some((_, outer, _))) => {
+                errors.push(AssemblyError {
+                    location: outer,
+                    kind: AssemblyErrorKind::UnmatchedDirective { directive: DirectiveKind::Sub },
+                    help: Some("Subroutines can't be nested. Close this one with .endsub first".to_owned()),
+                });
+                open = None;
+            }
+            (DirectiveKind::Sub, None) => match arguments.as_slice() {
+                [Node::LabelReference { name, .. }] => open = Some((*name, *location, i)),
+                _ => errors.push(AssemblyError {
+                    location: *location,
+                    kind: AssemblyErrorKind::InvalidDirectiveArguments { directive: *kind },
+                    help: Some(format!("{} takes the name of the subroutine", kind.name())),
+                }),
+            },
+            (DirectiveKind::EndSub, Some((name, location, start))) => {
+                routines.push(Routine { name, location, body: start + 1..i });
+                open = None;
+            }
+            (DirectiveKind::EndSub, None) => errors.push(AssemblyError {
+                location: *location,
+                kind: AssemblyErrorKind::UnmatchedDirective { directive: *kind },
+                help: None,
+            }),
+            (DirectiveKind::Page | DirectiveKind::Org | DirectiveKind::Align, Some(_)) => errors.push(AssemblyError {
+                location: *location,
+                kind: AssemblyErrorKind::InvalidDirectiveArguments { directive: *kind },
+                help: Some("Subroutines are placed by the assembler, so code inside them can't be placed".to_owned()),
+            }),
+            (DirectiveKind::Byte | DirectiveKind::Fill, Some(_)) => errors.push(AssemblyError {
+                location: *location,
+                kind: AssemblyErrorKind::InvalidDirectiveArguments { directive: *kind },
+                help: Some("Subroutines are copied around by the assembler, so they can only hold code".to_owned()),
+            }),
+            (DirectiveKind::Page | DirectiveKind::Org | DirectiveKind::Align, None)
+            | (DirectiveKind::Byte | DirectiveKind::Fill, None)
+            | (DirectiveKind::Equ, _) => {}
+            (
+                DirectiveKind::Macro
+                | DirectiveKind::EndMacro
+                | DirectiveKind::Include
+                | DirectiveKind::Print
+                | DirectiveKind::Printer
+                | DirectiveKind::Var
+                | DirectiveKind::If
+                | DirectiveKind::IfDef
+                | DirectiveKind::Elif
+                | DirectiveKind::Else
+                | DirectiveKind::EndIf,
+                _,
+            ) => unreachable!("Conditions, includes, macros, strings and variables are handled before subroutines"),
+        }
+
+        if let (DirectiveKind::EndSub, [argument, ..]) = (kind, arguments.as_slice()) {
+            errors.push(AssemblyError {
+                location: argument.location(),
+                kind: AssemblyErrorKind::InvalidDirectiveArguments { directive: *kind },
+                help: Some(format!("{} takes no arguments", kind.name())),
+            });
+        }
+    }
+
+    if let Some((_, location, _)) = open {
+        errors.push(AssemblyError {
+            location,
+            kind: AssemblyErrorKind::UnmatchedDirective { directive: DirectiveKind::Sub },
+            help: None,
+        });
+    }
+
+    if errors.is_empty() {
+        Ok(routines)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Takes every subroutine out of the program and adds copies of them to the end of it.
+///
+/// # Arguments
+///
+/// * `program`: The nodes produced by the parser.
+/// * `routines`: The subroutines declared in `program`.
+/// * `copies`: The subroutine and index into program memory of every copy.
+/// * `placeholders`: Whether to still define the names of subroutines without a copy, so calls to them can be found.
+fn expand<'a>(
+    program: &[Node<'a>],
+    routines: &[Routine<'a>],
+    copies: &[(usize, usize)],
+    placeholders: bool,
+) -> Expanded<'a> {
+    let mut expanded = Expanded { program: vec![], owners: vec![] };
+
+    let mut i = 0;
+    while i < program.len() {
+        match routines.iter().find(|routine| routine.body.start == i + 1) {
+            // Skip the whole block, including the `.endsub`.
+            Some(routine) => i = routine.body.end + 1,
+            None => {
+                expanded.program.push(program[i].clone());
+                expanded.owners.push(None);
+                i += 1;
+            }
+        }
+    }
+
+    let placeholders = (0..routines.len())
+        .filter(|r| placeholders && copies.iter().all(|(copy, _)| copy != r))
+        .map(|r| (r, None));
+    let copies = copies.iter().map(|&(r, start)| (r, Some(start)));
+
+    for (r, start) in placeholders.chain(copies) {
+        let routine = &routines[r];
+        let placement = Node::Directive {
+            kind: DirectiveKind::Org,
+            arguments: vec![Node::NumberLiteral { value: start.unwrap_or(0) as u16, location: routine.location }],
+            location: routine.location,
+        };
+        let label = Node::Label { name: routine.name, location: routine.location };
+        let body = match start {
+            Some(_) => &program[routine.body.clone()],
+            None => &[],
+        };
+
+        for node in [placement, label].into_iter().chain(body.iter().cloned()) {
+            expanded.program.push(node);
+            expanded.owners.push(Some(r));
+        }
+    }
+
+    expanded
+}
+
+/// Finds every subroutine call in a laid out program. A call is a `BRN` to a subroutine that may be made with the
+/// subroutine jump flag set.
+fn find_calls(expanded: &Expanded, routines: &[Routine], layout: &Layout, symbols: &SymbolTable) -> Vec<Call> {
+    let flows = flow::node_flows(&expanded.program, layout, symbols);
+    let states = flow::known_states(&flows, layout);
+
+    expanded
+        .program
+        .iter()
+        .enumerate()
+        .filter_map(|(i, node)| match node {
+            Node::Instruction { kind: InstructionKind::BRN, arguments, .. } => match arguments.as_slice() {
+                [Node::LabelReference { name, .. }] if states[i].subroutine_jump != Some(false) => routines
+                    .iter()
+                    .position(|routine| routine.name == *name)
+                    .map(|routine| Call { node: i, routine, page: layout.page(i) }),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+/// Makes sure no subroutine calls another one. There is only one return address, so the inner call would make the
+/// outer subroutine forget where to return to.
+fn check_nesting<'a>(
+    expanded: &Expanded<'a>,
+    routines: &[Routine<'a>],
+    calls: &[Call],
+) -> Result<(), Vec<AssemblyError<'a>>> {
+    let errors: Vec<_> = calls
+        .iter()
+        .filter(|call| expanded.owners[call.node].is_some())
+        .map(|call| AssemblyError {
+            location: expanded.program[call.node].location(),
+            kind: AssemblyErrorKind::NestedCall { chain: chain(expanded, routines, calls, call) },
+            help: Some(
+                "Only one subroutine can run at a time, since there is only one return address. Reset the subroutine \
+                jump flag with rsj before jumping to another subroutine"
+                    .to_owned(),
+            ),
+        })
+        .collect();
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Lists the calls leading up to `call`, starting with the closest label in front of the outermost call.
+fn chain<'a>(expanded: &Expanded<'a>, routines: &[Routine<'a>], calls: &[Call], call: &Call) -> Vec<Name<'a>> {
+    let mut chain = vec![routines[call.routine].name];
+    let mut node = call.node;
+
+    // Each subroutine is only counted once, so recursive calls can't loop forever.
+    while let Some(owner) = expanded.owners[node] {
+        if chain.contains(&routines[owner].name) {
+            chain.insert(0, routines[owner].name);
+            return chain;
+        }
+        chain.insert(0, routines[owner].name);
+        match calls.iter().find(|outer| outer.routine == owner) {
+            Some(outer) => node = outer.node,
+            None => return chain,
+        }
+    }
+
+    let label = expanded.program[..node].iter().rev().find_map(|node| match node {
+        Node::Label { name, .. } => Some(*name),
+        _ => None,
+    });
+    if let Some(label) = label {
+        chain.insert(0, label);
+    }
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    /// Places the subroutines of a program and returns the page of every copy of each subroutine, in order.
+    fn copies(program: &str) -> Result<Vec<(&str, usize)>, Vec<AssemblyErrorKind<'_>>> {
+        let mut lexer = Lexer::new(program, 0);
+        let mut parser = Parser::new(lexer.iter());
+        let nodes = parser.parse().ok().unwrap();
+        let placed = place(&nodes).map_err(|errors| errors.into_iter().map(|e| e.kind).collect::<Vec<_>>())?;
+        let (layout, _) = lay_out(&placed).map_err(|errors| errors.into_iter().map(|e| e.kind).collect::<Vec<_>>())?;
+
+        let mut copies = vec![];
+        for (i, node) in placed.iter().enumerate() {
+            let next = placed.get(i + 1);
+            if let (Node::Directive { kind: DirectiveKind::Org, .. }, Some(Node::Label { name, .. })) = (node, next) {
+                copies.push((name.text, layout.page(i + 1)));
+            }
+        }
+        Ok(copies)
+    }
+
+    #[test]
+    fn copies_subroutine_onto_calling_page() {
+        let program = ".page 3\nssj\nssf\nbrn write\nrsj\nret\n.sub write\nout 0\nret\n.endsub\n";
+        assert_eq!(copies(program).ok().unwrap(), vec![("write", 3)]);
+    }
+
+    #[test]
+    fn duplicates_subroutine_for_every_calling_page() {
+        let program = "ssj\nssf\nbrn write\nrsj\nret\n.page 2\nssj\nssf\nbrn write\nret\n\
+            .sub write\nout 0\nret\n.endsub\n";
+        assert_eq!(copies(program).ok().unwrap(), vec![("write", 0), ("write", 2)]);
+    }
+
+    #[test]
+    fn copies_without_calls_are_taken_away() {
+        // The copies on page 0 push the call to `short` onto page 1, so it doesn't need a copy on page 0 after all.
+        let nops = "nop\n".repeat(52);
+        let program = format!("ssj\nssf\nbrn long\nrsj\n{nops}lpb 0\nssj\nssf\nbrn short\nrsj\nret\n\
+            .sub long\n{}ret\n.endsub\n.sub short\nret\n.endsub\n", "nop\n".repeat(8));
+        assert_eq!(copies(&program).ok().unwrap(), vec![("long", 0), ("short", 1)]);
+    }
+
+    #[test]
+    fn uncalled_subroutine_is_left_out() {
+        assert_eq!(copies("nop\n.sub unused\nret\n.endsub\n").ok().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn labels_inside_subroutine_refer_to_same_page() {
+        let program = "ssj\nssf\nbrn wait\nret\n.page 5\nssj\nssf\nbrn wait\nret\n\
+            .sub wait\nrsj\nloop:\ndec x\ncmp y\njmp_if loop\nret\n.endsub\n";
+        let mut lexer = Lexer::new(program, 0);
+        let mut parser = Parser::new(lexer.iter());
+        let nodes = parser.parse().ok().unwrap();
+        let placed = place(&nodes).ok().unwrap();
+        let (layout, symbols) = lay_out(&placed).ok().unwrap();
+
+        for page in [0, 5] {
+            assert_eq!(symbols.resolve(Name::new("loop"), page).unwrap().address.page as usize, page);
+        }
+        let jumps = placed.iter().enumerate().filter(|(_, node)| matches!(node, Node::PseudoInstruction { .. }));
+        assert!(jumps.map(|(i, _)| layout.page(i)).eq([0, 5]));
+    }
+
+    #[test]
+    fn subroutines_that_do_not_fit_are_reported() {
+        let body = "nop\n".repeat(40);
+        let program = format!("call:\nssj\nssf\nbrn big\nbrn huge\nret\n.sub big\n{body}ret\n.endsub\n.sub huge\n{body}ret\n.endsub\n");
+        let errors = copies(&program).err().unwrap();
+        assert!(matches!(
+            errors.as_slice(),
+            [AssemblyErrorKind::SubroutineDoesNotFit { name, page: 0, chain }]
+                if name.text == "huge" && **chain == [Name::new("call"), Name::new("huge")]
+        ));
+    }
+
+    #[test]
+    fn nested_calls_are_reported() {
+        let program = "main:\nssj\nssf\nbrn outer\nret\n.sub outer\nbrn inner\nret\n.endsub\n\
+            .sub inner\nret\n.endsub\n";
+        let errors = copies(program).err().unwrap();
+        assert!(matches!(
+            errors.as_slice(),
+            [AssemblyErrorKind::NestedCall { chain }]
+                if chain == &[Name::new("main"), Name::new("outer"), Name::new("inner")]
+        ));
+    }
+
+    #[test]
+    fn unmatched_directives() {
+        let errors = copies(".endsub\n.sub open\nnop\n").err().unwrap();
+        assert!(matches!(errors.as_slice(), [
+            AssemblyErrorKind::UnmatchedDirective { directive: DirectiveKind::EndSub },
+            AssemblyErrorKind::UnmatchedDirective { directive: DirectiveKind::Sub },
+        ]));
+    }
+}
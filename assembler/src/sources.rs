@@ -0,0 +1,399 @@
+use crate::conditionals::Resolver;
+use crate::error::{AssemblyError, AssemblyErrorKind};
+use crate::lexer::{DirectiveKind, Lexer};
+use crate::location::Location;
+use crate::parser::{Node, ParseError, Parser};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A source file making up part of a program.
+pub struct SourceFile {
+    /// The path the file was found at, as it should be shown to the user.
+    pub path: PathBuf,
+    /// The path used to tell whether two includes refer to the same file.
+    canonical: PathBuf,
+    pub text: String,
+    /// The file pulled in by each `.include` in this file that is assembled, keyed by the index of the directive, or
+    /// None if the file can't be included.
+    includes: HashMap<u32, Option<u32>>,
+}
+
+/// Every source file making up a program. The first one is the file given to the assembler, and the rest are pulled
+/// in with `.include`. `Location::file` indexes into it.
+pub struct Sources {
+    files: Vec<SourceFile>,
+    /// The file holding the constants defined on the command line, if there are any.
+    definitions: Option<u32>,
+}
+
+impl Sources {
+    /// Creates the sources of a program from its main file, without following any includes yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: The path of the main file.
+    /// * `text`: The contents of the main file.
+    pub fn new(path: PathBuf, text: String) -> Self {
+        let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        Sources { files: vec![SourceFile { path, canonical, text, includes: HashMap::new() }], definitions: None }
+    }
+
+    /// Defines a constant as if `.equ name, value` was written at the very start of the program. The definitions are
+    /// kept in a file of their own, so errors in them point at the command line rather than the main file.
+    pub fn define(&mut self, name: &str, value: &str) {
+        let file = match self.definitions {
+            Some(file) => file,
+            None => {
+                let path = PathBuf::from("<command line>");
+                let canonical = PathBuf::new();
+                self.files.push(SourceFile { path, canonical, text: String::new(), includes: HashMap::new() });
+                self.files.len() as u32 - 1
+            }
+        };
+        self.definitions = Some(file);
+        self.files[file as usize].text.push_str(&format!(".equ {name}, {value}\n"));
+    }
+
+    pub fn files(&self) -> &[SourceFile] {
+        &self.files
+    }
+
+    pub fn file(&self, file: u32) -> &SourceFile {
+        &self.files[file as usize]
+    }
+
+    /// Formats a location as `path:line:col`.
+    pub fn position(&self, location: Location) -> String {
+        format!("{}:{}:{}", self.file(location.file).path.display(), location.line, location.col)
+    }
+
+    /// Reads every file included by the main file, directly or indirectly.
+    ///
+    /// An included file is looked for next to the file including it first, and then in each of `include_paths` in
+    /// order. Each file is only included once, so including it again does nothing. A file including itself, directly
+    /// or through other files, is an error. So is a character that isn't ASCII in any of the files, including the main
+    /// file and the constants defined on the command line.
+    ///
+    /// Only includes that are assembled are followed, so a file included in a `.if` block whose condition doesn't hold
+    /// is never looked for, and doesn't count as included. Since conditions can use constants from the files included
+    /// before them, the program is read again after each file is loaded, until every include that is assembled has its
+    /// file.
+    ///
+    /// # Arguments
+    ///
+    /// * `include_paths`: The extra directories to look for included files in.
+    pub fn load_includes(&mut self, include_paths: &[PathBuf]) -> Result<(), Vec<AssemblyError<'static>>> {
+        let errors: Vec<_> =
+            self.files.iter().enumerate().filter_map(|(i, file)| non_ascii(&file.text, i as u32)).collect();
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let mut load_errors = vec![];
+        let mut errors = loop {
+            let Walk { pending, errors, .. } = self.walk();
+            let Some((file, location, name)) = pending else {
+                break errors;
+            };
+            let included = self.load(file, &name, location, include_paths, &mut load_errors);
+            self.files[file as usize].includes.insert(location.index, included);
+        };
+        errors.append(&mut load_errors);
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Finds and reads the file named by an `.include`, unless it has been read already. Returns the index of the file,
+    /// or None if it can't be included.
+    fn load(
+        &mut self,
+        file: u32,
+        name: &str,
+        location: Location,
+        include_paths: &[PathBuf],
+        errors: &mut Vec<AssemblyError<'static>>,
+    ) -> Option<u32> {
+        let directory = self.file(file).path.parent().map(Path::to_path_buf).unwrap_or_default();
+        let directories: Vec<_> = [directory].into_iter().chain(include_paths.iter().cloned()).collect();
+
+        let Some(path) = directories.iter().map(|directory| directory.join(name)).find(|path| path.is_file()) else {
+            errors.push(AssemblyError {
+                location,
+                kind: AssemblyErrorKind::IncludeNotFound {
+                    path: name.to_owned(),
+                    searched: directories.iter().map(|directory| directory.display().to_string()).collect(),
+                },
+                help: Some("Add the directory containing the file with -I".to_owned()),
+            });
+            return None;
+        };
+
+        let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if let Some(included) = self.files.iter().position(|source| source.canonical == canonical) {
+            // A file that isn't ASCII has been reported already, and can't be read.
+            return self.files[included].text.is_ascii().then_some(included as u32);
+        }
+
+        match fs::read_to_string(&path) {
+            Ok(text) => {
+                let included = self.files.len() as u32;
+                // The file is still added, so the error can show where in it the character is, but nothing in it is
+                // read.
+                let error = non_ascii(&text, included);
+                self.files.push(SourceFile { path, canonical, text, includes: HashMap::new() });
+                match error {
+                    Some(error) => {
+                        errors.push(error);
+                        None
+                    }
+                    None => Some(included),
+                }
+            }
+            Err(err) => {
+                errors.push(AssemblyError {
+                    location,
+                    kind: AssemblyErrorKind::UnreadableInclude {
+                        path: path.display().to_string(),
+                        cause: err.to_string(),
+                    },
+                    help: None,
+                });
+                None
+            }
+        }
+    }
+
+    /// Parses the main file, with the contents of every included file in place of the `.include` pulling it in, after
+    /// the constants defined on the command line. Local and anonymous labels aren't scoped yet, since that has to wait
+    /// for conditionals and macros.
+    pub fn parse(&self) -> Result<Vec<Node<'_>>, Vec<ParseError<'_>>> {
+        let Walk { program, parse_errors, .. } = self.walk();
+        if parse_errors.is_empty() {
+            Ok(program)
+        } else {
+            Err(parse_errors)
+        }
+    }
+
+    /// Reads the whole program, following the includes that have been loaded, up to the first include that is
+    /// assembled but hasn't been loaded yet.
+    fn walk(&self) -> Walk<'_> {
+        let mut walk = Walk {
+            program: vec![],
+            parse_errors: vec![],
+            errors: vec![],
+            conditionals: Resolver::default(),
+            stack: vec![],
+            spliced: vec![],
+            pending: None,
+        };
+        if let Some(definitions) = self.definitions {
+            self.splice(definitions, &mut walk);
+        }
+        if walk.pending.is_none() {
+            self.splice(0, &mut walk);
+        }
+        walk
+    }
+
+    /// Adds the nodes of a file to the program being read, and the nodes of the files it includes in place of the
+    /// `.include`s pulling them in.
+    fn splice<'a>(&'a self, file: u32, walk: &mut Walk<'a>) {
+        let source = self.file(file);
+        let mut lexer = Lexer::new(&source.text, file);
+        let nodes = match Parser::new(lexer.iter()).parse() {
+            Ok(nodes) => nodes,
+            Err(mut errors) => return walk.parse_errors.append(&mut errors),
+        };
+
+        walk.stack.push(file);
+        for node in nodes {
+            let Node::Directive { kind: DirectiveKind::Include, arguments, location } = &node else {
+                walk.conditionals.push(&node);
+                walk.program.push(node);
+                continue;
+            };
+            if !walk.conditionals.active() {
+                continue;
+            }
+
+            let [Node::StringLiteral { value, .. }] = arguments.as_slice() else {
+                walk.errors.push(AssemblyError {
+                    location: *location,
+                    kind: AssemblyErrorKind::InvalidDirectiveArguments { directive: DirectiveKind::Include },
+                    help: Some(format!("{} takes the path of a file in double quotes", DirectiveKind::Include.name())),
+                });
+                continue;
+            };
+            let Some(&included) = source.includes.get(&location.index) else {
+                walk.pending = Some((file, *location, value.to_string()));
+                break;
+            };
+            let Some(included) = included else {
+                continue;
+            };
+
+            if let Some(start) = walk.stack.iter().position(|&f| f == included) {
+                let chain = walk.stack[start..].iter().copied().chain([included]);
+                walk.errors.push(AssemblyError {
+                    location: *location,
+                    kind: AssemblyErrorKind::IncludeCycle {
+                        chain: chain.map(|f| self.file(f).path.display().to_string()).collect(),
+                    },
+                    help: None,
+                });
+            } else if !walk.spliced.contains(&included) {
+                walk.spliced.push(included);
+                self.splice(included, walk);
+                if walk.pending.is_some() {
+                    break;
+                }
+            }
+        }
+        walk.stack.pop();
+    }
+}
+
+/// The program read so far, along with what is needed to follow its includes.
+struct Walk<'a> {
+    /// The nodes of the program, with conditionals still in place.
+    program: Vec<Node<'a>>,
+    parse_errors: Vec<ParseError<'a>>,
+    /// The includes that can't be followed.
+    errors: Vec<AssemblyError<'static>>,
+    /// Tells whether each `.include` is assembled.
+    conditionals: Resolver<'a>,
+    /// The files being read, each included by the one before it.
+    stack: Vec<u32>,
+    /// The files that have been included already.
+    spliced: Vec<u32>,
+    /// The first `.include` that is assembled but hasn't been loaded, as the file it is in, where it is and the path
+    /// it names. Nothing after it has been read.
+    pending: Option<(u32, Location, String)>,
+}
+
+/// Points out the first character of a file that isn't ASCII, if there is one, since the lexer can only read ASCII.
+fn non_ascii(text: &str, file: u32) -> Option<AssemblyError<'static>> {
+    let index = text.find(|c: char| !c.is_ascii())?;
+    let line_start = text[..index].rfind('\n').map_or(0, |i| i + 1);
+    let location = Location {
+        file,
+        index: index as u32,
+        // Carets and editor ranges count characters, and this is a single one.
+        length: 1,
+        line: text[..index].matches('\n').count() as u32 + 1,
+        col: text[line_start..index].chars().count() as u32 + 1,
+        expansion: None,
+    };
+    Some(AssemblyError {
+        location,
+        kind: AssemblyErrorKind::NonAscii { character: text[index..].chars().next().unwrap() },
+        help: Some("Only ASCII characters can be used in programs, including in comments".to_owned()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes each file into a fresh directory and loads the first one.
+    fn load(name: &str, files: &[(&str, &str)]) -> (Sources, Result<(), Vec<AssemblyError<'static>>>) {
+        let directory = std::env::temp_dir().join(format!("assembler-sources-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        for (path, text) in files {
+            let path = directory.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
+        }
+
+        let main = directory.join(files[0].0);
+        let mut sources = Sources::new(main.clone(), fs::read_to_string(main).unwrap());
+        let result = sources.load_includes(&[directory.join("lib")]);
+        (sources, result)
+    }
+
+    #[test]
+    fn includes_are_spliced_in_once() {
+        let files = [
+            ("main.asm", "nop\n.include \"a.asm\"\n.include \"b.asm\"\nret\n"),
+            ("a.asm", ".include \"b.asm\"\ninc x\n"),
+            ("lib/b.asm", "inc y\n"),
+        ];
+        let (sources, result) = load("once", &files);
+        assert!(result.is_ok());
+
+        let program = sources.parse().ok().unwrap();
+        let files: Vec<_> = program.iter().map(|node| node.location().file).collect();
+        assert_eq!(files, [0, 2, 1, 0]);
+        assert!(sources.file(2).path.ends_with("lib/b.asm"));
+    }
+
+    #[test]
+    fn include_cycles_are_reported() {
+        let files = [("main.asm", ".include \"a.asm\"\n"), ("a.asm", ".include \"main.asm\"\n")];
+        let (_, result) = load("cycle", &files);
+        let errors = result.err().unwrap();
+        assert!(matches!(
+            errors.as_slice(),
+            [AssemblyError { kind: AssemblyErrorKind::IncludeCycle { chain }, location, .. }]
+                if chain.len() == 3 && chain[0].ends_with("main.asm") && location.file == 1
+        ));
+    }
+
+    #[test]
+    fn missing_includes_are_reported() {
+        let (_, result) = load("missing", &[("main.asm", ".include \"nowhere.asm\"\n.include 5\n")]);
+        let errors = result.err().unwrap();
+        assert!(matches!(
+            errors.as_slice(),
+            [
+                AssemblyError { kind: AssemblyErrorKind::InvalidDirectiveArguments { .. }, .. },
+                AssemblyError { kind: AssemblyErrorKind::IncludeNotFound { searched, .. }, .. },
+            ] if searched.len() == 2
+        ));
+    }
+
+    #[test]
+    fn includes_that_are_not_assembled_are_not_looked_for() {
+        let (_, result) = load("dead-missing", &[("main.asm", ".if 0\n.include \"nowhere.asm\"\n.endif\nnop\n")]);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn includes_that_are_not_assembled_do_not_use_up_the_file() {
+        let files = [
+            ("main.asm", ".if 0\n.include \"a.asm\"\n.endif\n.include \"config.asm\"\n"),
+            ("config.asm", ".equ FAST 1\n.ifdef FAST\n.include \"a.asm\"\n.endif\n"),
+            ("a.asm", "inc x\n"),
+        ];
+        let (sources, result) = load("dead-once", &files);
+        assert!(result.is_ok());
+
+        let program = sources.parse().ok().unwrap();
+        let files: Vec<_> = program.iter().map(|node| node.location().file).collect();
+        assert_eq!(files, [0, 0, 1, 1, 2, 1]);
+    }
+
+    #[test]
+    fn non_ascii_characters_are_reported() {
+        let (_, result) = load("ascii-main", &[("main.asm", "nop ; caf\u{e9}\n")]);
+        assert!(matches!(
+            result.err().unwrap().as_slice(),
+            [AssemblyError { kind: AssemblyErrorKind::NonAscii { character: '\u{e9}' }, location, .. }]
+                if location.file == 0 && location.line == 1 && location.col == 10
+        ));
+
+        let files = [("main.asm", ".include \"a.asm\"\n"), ("a.asm", "inc x\n; \u{2192} y\n")];
+        let (_, result) = load("ascii-include", &files);
+        assert!(matches!(
+            result.err().unwrap().as_slice(),
+            [AssemblyError { kind: AssemblyErrorKind::NonAscii { character: '\u{2192}' }, location, .. }]
+                if location.file == 1 && location.line == 2
+        ));
+    }
+}
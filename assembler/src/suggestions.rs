@@ -0,0 +1,62 @@
+/// Finds the candidate `name` is most likely a typo of, ignoring case. Nothing is suggested unless a candidate is close
+/// enough for that to be likely.
+///
+/// # Arguments
+///
+/// * `name`: The name that could not be found.
+/// * `candidates`: Every name that would have been accepted.
+pub fn closest<'c>(name: &str, candidates: impl IntoIterator<Item = &'c str>) -> Option<&'c str> {
+    // Roughly one typo for every three characters, so short names don't match just about anything.
+    let max_distance = (name.chars().count() / 3).max(1);
+    candidates
+        .into_iter()
+        .filter(|&candidate| candidate != name)
+        .map(|candidate| (distance(&name.to_lowercase(), &candidate.to_lowercase()), candidate))
+        .filter(|&(distance, _)| distance <= max_distance)
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// The number of characters that have to be inserted, removed, replaced or swapped with their neighbour to turn `a`
+/// into `b`.
+fn distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    // distances[i][j] is the distance between the first i characters of a and the first j characters of b.
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, distance) in distances[0].iter_mut().enumerate() {
+        *distance = j;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = (a[i - 1] != b[j - 1]) as usize;
+            let mut best = (distances[i - 1][j] + 1).min(distances[i][j - 1] + 1).min(distances[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                best = best.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = best;
+        }
+    }
+
+    distances[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suggests_near_misses() {
+        let mnemonics = ["ldi", "lod", "inc", "brn"];
+        assert_eq!(closest("ldx", mnemonics), Some("ldi"));
+        assert_eq!(closest("LDI", mnemonics), Some("ldi"));
+        assert_eq!(closest("bnr", mnemonics), Some("brn"));
+        assert_eq!(closest("write_chr", ["write_char", "start"]), Some("write_char"));
+        assert_eq!(closest("foo", mnemonics), None);
+    }
+}
@@ -0,0 +1,232 @@
+use crate::error::{AssemblyError, AssemblyErrorKind};
+use crate::expression::Constants;
+use crate::layout::Layout;
+use crate::lexer::DirectiveKind;
+use crate::location::Location;
+use crate::parser::{Name, Node};
+use crate::suggestions;
+use common::architecture::{NUM_PAGES, PAGE_SIZE, PROGRAM_MEMORY_SIZE};
+use std::collections::HashMap;
+
+/// A location in program memory, split the same way the computer splits it into the page address and program
+/// counter.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Address {
+    pub page: u8,
+    pub offset: u8,
+}
+
+impl Address {
+    /// Creates an address from an index into program memory. The index must be less than `PROGRAM_MEMORY_SIZE`.
+    pub fn from_index(index: usize) -> Address {
+        assert!(index < PROGRAM_MEMORY_SIZE);
+
+        Address {
+            page: (index / PAGE_SIZE) as u8,
+            offset: (index % PAGE_SIZE) as u8,
+        }
+    }
+}
+
+pub struct Symbol {
+    pub address: Address,
+    pub location: Location,
+    /// The index of the label node that defines this symbol.
+    pub node: usize,
+}
+
+pub struct SymbolTable<'a> {
+    /// Every definition of each label. Labels inside subroutines are defined once for every page the subroutine is
+    /// copied onto, and all other labels are defined exactly once.
+    symbols: HashMap<Name<'a>, Vec<Symbol>>,
+    constants: Constants<'a>,
+}
+
+impl<'a> SymbolTable<'a> {
+    /// Builds the symbol table for a program by giving every label the address of the instruction following it.
+    /// All labels are collected before any references are checked, so labels can be used before they are defined.
+    ///
+    /// # Arguments
+    ///
+    /// * `program`: The nodes produced by the parser.
+    /// * `layout`: Where each node of `program` is placed in program memory.
+    /// * `constants`: The constants defined in `program`, which share their names with labels.
+    pub fn build(
+        program: &[Node<'a>],
+        layout: &Layout,
+        constants: Constants<'a>,
+    ) -> Result<SymbolTable<'a>, Vec<AssemblyError<'a>>> {
+        let mut symbols: HashMap<Name<'a>, Vec<Symbol>> = HashMap::new();
+        let mut errors = vec![];
+
+        for (i, node) in program.iter().enumerate() {
+            let Node::Label { name, location } = node else {
+                continue;
+            };
+
+            let index = layout.start(i);
+            let definitions = symbols.entry(*name).or_default();
+            // Copies of a subroutine share their source location, but each copy lives on its own page.
+            let previous = definitions
+                .iter()
+                .find(|previous| previous.location != *location || previous.address.page as usize == index / PAGE_SIZE)
+                .map(|previous| previous.location)
+                .or(constants.get(*name).map(|constant| constant.location));
+
+            if let Some(previous) = previous {
+                errors.push(AssemblyError {
+                    location: *location,
+                    kind: AssemblyErrorKind::DuplicateLabel { name: *name, previous },
+                    help: None,
+                });
+            } else if index >= PROGRAM_MEMORY_SIZE {
+                errors.push(AssemblyError {
+                    location: *location,
+                    kind: AssemblyErrorKind::LabelOutOfRange { name: *name },
+                    help: Some(format!("Program memory only holds {NUM_PAGES} pages of {PAGE_SIZE} instructions")),
+                });
+            } else {
+                definitions.push(Symbol { address: Address::from_index(index), location: *location, node: i });
+            }
+        }
+
+        let table = SymbolTable { symbols, constants };
+        for node in program {
+            let expressions = match node {
+                Node::Instruction { arguments, .. } | Node::PseudoInstruction { arguments, .. } => arguments.as_slice(),
+                // The first argument is the name being defined.
+                Node::Directive { kind: DirectiveKind::Equ, arguments, .. } => arguments.get(1..).unwrap_or_default(),
+                _ => continue,
+            };
+
+            for expression in expressions {
+                table.check_names(expression, &mut errors);
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(table)
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn constants(&self) -> &Constants<'a> {
+        &self.constants
+    }
+
+    /// Reports every name used in `node` that is neither a label nor a constant.
+    fn check_names(&self, node: &Node<'a>, errors: &mut Vec<AssemblyError<'a>>) {
+        match node {
+            Node::LabelReference { name, location } => {
+                if self.get(*name).is_none() && self.constants.get(*name).is_none() {
+                    let labels = self.labels().map(|(name, _)| name.text);
+                    let candidates = labels.chain(self.constants.iter().map(|(name, _)| name.text));
+                    let suggestion = suggestions::closest(name.text, candidates);
+                    errors.push(AssemblyError {
+                        location: *location,
+                        kind: AssemblyErrorKind::UndefinedLabel { name: *name },
+                        help: suggestion.map(|suggestion| format!("Did you mean `{suggestion}`?")),
+                    });
+                }
+            }
+            Node::Negation { operand, .. } => self.check_names(operand, errors),
+            Node::BinaryOperation { left, right, .. } => {
+                self.check_names(left, errors);
+                self.check_names(right, errors);
+            }
+            Node::FunctionCall { argument, .. } => self.check_names(argument, errors),
+            _ => {}
+        }
+    }
+
+    /// Gets every definition of every label.
+    pub fn labels(&self) -> impl Iterator<Item = (Name<'a>, &Symbol)> {
+        self.symbols.iter().flat_map(|(name, definitions)| definitions.iter().map(|symbol| (*name, symbol)))
+    }
+
+    /// Gets the first definition of a label.
+    pub fn get(&self, name: Name<'a>) -> Option<&Symbol> {
+        self.symbols.get(&name).and_then(|definitions| definitions.first())
+    }
+
+    /// Gets the definition of a label as seen from code on `page`, preferring the copy on that page when the label
+    /// is defined more than once.
+    pub fn resolve(&self, name: Name<'a>, page: usize) -> Option<&Symbol> {
+        let definitions = self.symbols.get(&name)?;
+        definitions
+            .iter()
+            .find(|symbol| symbol.address.page as usize == page)
+            .or(definitions.first())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::lay_out;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn build(program: &str) -> Result<Vec<(&str, Address)>, Vec<AssemblyErrorKind<'_>>> {
+        let mut lexer = Lexer::new(program, 0);
+        let mut parser = Parser::new(lexer.iter());
+        let nodes = parser.parse().ok().unwrap();
+        let table = lay_out(&nodes).map(|(_, symbols)| symbols).map_err(|errors| errors.into_iter().map(|e| e.kind).collect::<Vec<_>>())?;
+        let mut symbols: Vec<_> = table
+            .symbols
+            .into_iter()
+            .flat_map(|(name, definitions)| definitions.into_iter().map(move |symbol| (name.text, symbol.address)))
+            .collect();
+        symbols.sort_by_key(|(_, address)| (address.page, address.offset));
+        Ok(symbols)
+    }
+
+    #[test]
+    fn address_from_index() {
+        assert_eq!(Address::from_index(0), Address { page: 0, offset: 0 });
+        assert_eq!(Address::from_index(63), Address { page: 0, offset: 63 });
+        assert_eq!(Address::from_index(64), Address { page: 1, offset: 0 });
+        assert_eq!(Address::from_index(1023), Address { page: 15, offset: 63 });
+    }
+
+    #[test]
+    fn labels_get_page_and_offset() {
+        let symbols = build("start:\nnop\n.org 70\nmiddle:\nnop\nend:\nbrn start\n").ok().unwrap();
+        assert_eq!(symbols, vec![
+            ("start", Address { page: 0, offset: 0 }),
+            ("middle", Address { page: 1, offset: 6 }),
+            ("end", Address { page: 1, offset: 7 }),
+        ]);
+    }
+
+    #[test]
+    fn forward_references_resolve() {
+        assert!(build("brn end\nend:\nnop\n").is_ok());
+    }
+
+    #[test]
+    fn far_jumps_take_up_space() {
+        let symbols = build("jmp end\nend:\nnop\n").ok().unwrap();
+        assert_eq!(symbols, vec![("end", Address { page: 0, offset: 2 })]);
+    }
+
+    #[test]
+    fn undefined_label() {
+        let errors = build("brn nowhere\n").err().unwrap();
+        assert!(matches!(errors.as_slice(), [AssemblyErrorKind::UndefinedLabel { name: Name { text: "nowhere", .. } }]));
+    }
+
+    #[test]
+    fn duplicate_label() {
+        let errors = build("loop:\nnop\nloop:\nnop\n").err().unwrap();
+        assert!(matches!(errors.as_slice(), [AssemblyErrorKind::DuplicateLabel { name: Name { text: "loop", .. }, .. }]));
+    }
+
+    #[test]
+    fn label_out_of_range() {
+        let program = format!("{}end:\n", "nop\n".repeat(PROGRAM_MEMORY_SIZE));
+        let errors = build(&program).err().unwrap();
+        assert!(matches!(errors.as_slice(), [AssemblyErrorKind::LabelOutOfRange { name: Name { text: "end", .. } }]));
+    }
+}
@@ -0,0 +1,149 @@
+use crate::error::{AssemblyError, AssemblyErrorKind};
+use crate::expression::{self, Constants};
+use crate::lexer::{DirectiveKind, InstructionKind, PseudoInstructionKind, Register};
+use crate::parser::{Function, Node};
+use common::architecture::WORKING_MEMORY_SIZE;
+
+/// Gives every variable declared with `.var name` or `.var name, size` its own space in working memory, and expands
+/// `ldxy` into the instructions loading an address into X and Y.
+///
+/// Variables are placed one after another in the order they are declared, starting at address 0. Sizes are counted in
+/// nibbles and default to one. Each declaration turns into a constant holding the variable's address, so variables
+/// can be used anywhere a constant can, like `ldxy buffer + 2`.
+///
+/// # Arguments
+///
+/// * `program`: The nodes left after macros are expanded.
+pub fn allocate<'a>(program: &[Node<'a>]) -> Result<Vec<Node<'a>>, Vec<AssemblyError<'a>>> {
+    let constants = Constants::collect(program)?;
+    let mut allocated = vec![];
+    let mut errors = vec![];
+    let mut next = 0;
+
+    for node in program {
+        match node {
+            Node::Directive { kind: kind @ DirectiveKind::Var, arguments, location } => {
+                let invalid = AssemblyError {
+                    location: *location,
+                    kind: AssemblyErrorKind::InvalidDirectiveArguments { directive: *kind },
+                    help: Some(format!(
+                        "{} takes a name, optionally followed by a size from 1 to {WORKING_MEMORY_SIZE}",
+                        kind.name()
+                    )),
+                };
+                // The size has to be known before anything is placed, like the argument of `.page`.
+                let (reference, name, size) = match arguments.as_slice() {
+                    [reference @ Node::LabelReference { name, .. }] => (reference, name, 1),
+                    [reference @ Node::LabelReference { name, .. }, size] if size.is_expression() => {
+                        match expression::evaluate_constant(size, &constants) {
+                            Ok(size) if (1..=WORKING_MEMORY_SIZE as i64).contains(&size) => {
+                                (reference, name, size as usize)
+                            }
+                            Ok(_) => {
+                                errors.push(invalid);
+                                continue;
+                            }
+                            Err(err) => {
+                                errors.push(err);
+                                continue;
+                            }
+                        }
+                    }
+                    _ => {
+                        errors.push(invalid);
+                        continue;
+                    }
+                };
+
+                if next + size > WORKING_MEMORY_SIZE {
+                    errors.push(AssemblyError {
+                        location: *location,
+                        kind: AssemblyErrorKind::OutOfWorkingMemory {
+                            name: *name,
+                            size,
+                            free: WORKING_MEMORY_SIZE - next,
+                        },
+                        help: Some(format!("Working memory holds {WORKING_MEMORY_SIZE} nibbles")),
+                    });
+                    continue;
+                }
+
+                let address = Node::NumberLiteral { value: next as u16, location: *location };
+                let arguments = vec![reference.clone(), address];
+                allocated.push(Node::Directive { kind: DirectiveKind::Equ, arguments, location: *location });
+                next += size;
+            }
+            Node::PseudoInstruction { kind: kind @ PseudoInstructionKind::Ldxy, arguments, location } => {
+                let [address] = arguments.as_slice() else {
+                    errors.push(AssemblyError {
+                        location: *location,
+                        kind: AssemblyErrorKind::InvalidPseudoArguments { instruction: *kind },
+                        help: Some(format!("{} takes an address in working memory, like a variable", kind.name())),
+                    });
+                    continue;
+                };
+
+                for (register, function) in [(Register::X, Function::Hi), (Register::Y, Function::Lo)] {
+                    let nibble = Node::FunctionCall {
+                        function,
+                        argument: Box::new(address.clone()),
+                        location: address.location(),
+                    };
+                    let arguments = vec![Node::RegisterLiteral { register, location: *location }, nibble];
+                    allocated.push(Node::Instruction { kind: InstructionKind::LDI, arguments, location: *location });
+                }
+            }
+            _ => allocated.push(node.clone()),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(allocated)
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::generate;
+    use crate::layout::lay_out;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn kinds(errors: Vec<AssemblyError<'_>>) -> Vec<AssemblyErrorKind<'_>> {
+        errors.into_iter().map(|error| error.kind).collect()
+    }
+
+    fn assemble(program: &str) -> Result<Vec<u8>, Vec<AssemblyErrorKind<'_>>> {
+        let mut lexer = Lexer::new(program, 0);
+        let mut parser = Parser::new(lexer.iter());
+        let nodes = parser.parse().ok().unwrap();
+        let nodes = allocate(&nodes).map_err(kinds)?;
+        let (layout, symbols) = lay_out(&nodes).map_err(kinds)?;
+        Ok(generate(&nodes, &layout, &symbols).map_err(kinds)?.to_vec())
+    }
+
+    #[test]
+    fn places_variables_one_after_another() {
+        let image = assemble(".var count\n.var buffer, SIZE\n.var last\n.equ SIZE 16\nldxy last\nldxy buffer + 1\n")
+            .ok()
+            .unwrap();
+        // last is at 17: LDI X 1, LDI Y 1, then buffer + 1 is at 2: LDI X 0, LDI Y 2
+        assert_eq!(image[0..4], [0b11010001, 0b11100001, 0b11010000, 0b11100010]);
+    }
+
+    #[test]
+    fn rejects_variables_that_do_not_fit() {
+        let errors = assemble(".var one, 200\n.var two, 57\n.var three, 0\nldxy\n").err().unwrap();
+        assert!(matches!(
+            errors.as_slice(),
+            [
+                AssemblyErrorKind::OutOfWorkingMemory { size: 57, free: 56, .. },
+                AssemblyErrorKind::InvalidDirectiveArguments { directive: DirectiveKind::Var },
+                AssemblyErrorKind::InvalidPseudoArguments { instruction: PseudoInstructionKind::Ldxy },
+            ]
+        ));
+    }
+}
@@ -0,0 +1,91 @@
+use crate::error::{AssemblyError, AssemblyErrorKind};
+use crate::lexer::DirectiveKind;
+use crate::parser::Node;
+use std::collections::HashSet;
+
+/// Looks for things in a program that are allowed but probably a mistake, like a macro that is never used.
+///
+/// Only definitions in the main file are checked, since included files are expected to define more than any single
+/// program uses.
+///
+/// # Arguments
+///
+/// * `program`: The nodes produced by the parser, before macros are expanded.
+pub fn check<'a>(program: &[Node<'a>]) -> Vec<AssemblyError<'a>> {
+    let mut used_names = HashSet::new();
+    let mut used_macros = HashSet::new();
+    for node in program {
+        match node {
+            Node::MacroCall { name, arguments, .. } => {
+                used_macros.insert(*name);
+                collect_names(arguments, &mut used_names);
+            }
+            Node::Directive { kind: DirectiveKind::Macro, .. } => {}
+            // The first argument is the name being defined.
+            Node::Directive { kind: DirectiveKind::Equ, arguments, .. } => {
+                collect_names(arguments.get(1..).unwrap_or_default(), &mut used_names)
+            }
+            Node::Instruction { arguments, .. }
+            | Node::PseudoInstruction { arguments, .. }
+            | Node::Directive { arguments, .. } => collect_names(arguments, &mut used_names),
+            _ => {}
+        }
+    }
+
+    let mut warnings = vec![];
+    for node in program {
+        let Node::Directive { kind, arguments, location } = node else {
+            continue;
+        };
+        let Some(Node::LabelReference { name, location }) = arguments.first().filter(|_| location.file == 0) else {
+            continue;
+        };
+        let kind = match kind {
+            DirectiveKind::Macro if !used_macros.contains(name.text) => AssemblyErrorKind::UnusedMacro { name: name.text },
+            DirectiveKind::Equ if !used_names.contains(name.text) => AssemblyErrorKind::UnusedConstant { name: *name },
+            _ => continue,
+        };
+        warnings.push(AssemblyError { location: *location, kind, help: None });
+    }
+
+    warnings
+}
+
+/// Collects every name referred to in `nodes`.
+fn collect_names<'a>(nodes: &[Node<'a>], names: &mut HashSet<&'a str>) {
+    for node in nodes {
+        match node {
+            Node::LabelReference { name, .. } => {
+                names.insert(name.text);
+            }
+            Node::Negation { operand, .. } => collect_names(std::slice::from_ref(operand), names),
+            Node::BinaryOperation { left, right, .. } => {
+                collect_names(std::slice::from_ref(left), names);
+                collect_names(std::slice::from_ref(right), names);
+            }
+            Node::FunctionCall { argument, .. } => collect_names(std::slice::from_ref(argument), names),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn warnings(program: &str) -> Vec<String> {
+        let mut lexer = Lexer::new(program, 0);
+        let mut parser = Parser::new(lexer.iter());
+        let nodes = parser.parse().ok().unwrap();
+        check(&nodes).into_iter().map(|warning| warning.kind.code().to_owned()).collect()
+    }
+
+    #[test]
+    fn warns_about_unused_definitions() {
+        assert_eq!(warnings(".equ A 1\n.equ B A\n.macro m\n.endm\nldi x B\n"), ["W001"]);
+        assert_eq!(warnings(".equ A 1\n.macro m x\nldi x hi(A)\n.endm\nm 2\n"), Vec::<String>::new());
+        assert_eq!(warnings(".equ A 1\n"), ["W002"]);
+    }
+}
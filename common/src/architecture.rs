@@ -12,5 +12,7 @@ pub const REGISTER_INDEX_BITS: usize = NUM_REGISTERS.ilog2() as usize;
 pub const PORT_INDEX_BITS: usize = NUM_PORTS.ilog2() as usize;
 pub const PIN_INDEX_BITS: usize = NUM_PINS.ilog2() as usize;
 
-pub const PROGRAM_MEMORY_SIZE: usize = 2usize.pow(PC_BITS as u32) * 2usize.pow(PA_BITS as u32);
-pub const WORKING_MEMORY_SIZE: usize = 2usize.pow(2 * WORKING_BITS as u32);  // two registers used to index
\ No newline at end of file
+pub const PAGE_SIZE: usize = 2usize.pow(PC_BITS as u32);
+pub const NUM_PAGES: usize = 2usize.pow(PA_BITS as u32);
+pub const PROGRAM_MEMORY_SIZE: usize = PAGE_SIZE * NUM_PAGES;
+pub const WORKING_MEMORY_SIZE: usize = 2usize.pow(2 * WORKING_BITS as u32);  // two registers used to index
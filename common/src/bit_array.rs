@@ -258,6 +258,7 @@ fn change_bits_true<const FROM: usize, const TO: usize>(
     out
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
 
@@ -0,0 +1,173 @@
+use crate::architecture::{PAGE_SIZE, PROGRAM_MEMORY_SIZE};
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+
+/// A position in one of the source files a program was assembled from.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SourcePosition {
+    /// Indexes into `DebugInfo::files`.
+    pub file: usize,
+    pub line: u32,
+    pub col: u32,
+}
+
+/// What the assembler knows about a ROM image that the image itself doesn't hold: where each instruction came from,
+/// and the labels and constants of the program. The assembler writes it next to the image, and the emulator reads it
+/// to show addresses the way they were written.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DebugInfo {
+    pub files: Vec<String>,
+    /// The source position of each index into program memory, if anything was assembled there.
+    pub positions: Vec<Option<SourcePosition>>,
+    /// Every label, with the index into program memory it points to.
+    pub labels: Vec<(String, usize)>,
+    /// Every constant defined with `.equ`, with its value.
+    pub constants: Vec<(String, i64)>,
+}
+
+impl Default for DebugInfo {
+    fn default() -> Self {
+        DebugInfo {
+            files: vec![],
+            positions: vec![None; PROGRAM_MEMORY_SIZE],
+            labels: vec![],
+            constants: vec![],
+        }
+    }
+}
+
+impl DebugInfo {
+    /// Reads debug information back from the text written by its `Display` implementation.
+    pub fn parse(text: &str) -> Result<DebugInfo, String> {
+        let mut info = DebugInfo::default();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+
+            let error = || format!("Invalid debug information on line {}: '{line}'", number + 1);
+            let (kind, rest) = line.split_once(' ').ok_or_else(error)?;
+            match kind {
+                "file" => info.files.push(rest.to_owned()),
+                "label" => {
+                    let (name, address) = rest.split_once(' ').ok_or_else(error)?;
+                    info.labels.push((name.to_owned(), parse_address(address).ok_or_else(error)?));
+                }
+                "equ" => {
+                    let (name, value) = rest.split_once(' ').ok_or_else(error)?;
+                    info.constants.push((name.to_owned(), value.parse().map_err(|_| error())?));
+                }
+                "at" => {
+                    let parts: Vec<_> = rest.split(' ').collect();
+                    let [address, file, line, col] = parts.as_slice() else {
+                        return Err(error());
+                    };
+                    let index = parse_address(address).ok_or_else(error)?;
+                    let position = SourcePosition {
+                        file: file.parse().map_err(|_| error())?,
+                        line: line.parse().map_err(|_| error())?,
+                        col: col.parse().map_err(|_| error())?,
+                    };
+                    if position.file >= info.files.len() {
+                        return Err(error());
+                    }
+                    info.positions[index] = Some(position);
+                }
+                _ => return Err(error()),
+            }
+        }
+
+        Ok(info)
+    }
+
+    /// Describes an index into program memory by the closest label before it on the same page and where it was
+    /// written, like `write_char+3 (hello_world.asm:14)`. Anything that isn't known is left out, falling back to the
+    /// page and offset.
+    pub fn describe(&self, index: usize) -> String {
+        let page_start = index / PAGE_SIZE * PAGE_SIZE;
+        let label = self
+            .labels
+            .iter()
+            .filter(|(_, address)| (page_start..=index).contains(address))
+            .max_by_key(|(_, address)| *address);
+
+        let mut description = match label {
+            Some((name, address)) if *address == index => name.clone(),
+            Some((name, address)) => format!("{name}+{}", index - address),
+            None => format_address(index),
+        };
+
+        if let Some(position) = self.positions.get(index).copied().flatten() {
+            let path = Path::new(&self.files[position.file]);
+            let file = path.file_name().map_or(path.as_os_str(), |name| name);
+            description.push_str(&format!(" ({}:{})", file.to_string_lossy(), position.line));
+        }
+
+        description
+    }
+}
+
+impl Display for DebugInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for file in &self.files {
+            writeln!(f, "file {file}")?;
+        }
+        for (name, index) in &self.labels {
+            writeln!(f, "label {name} {}", format_address(*index))?;
+        }
+        for (name, value) in &self.constants {
+            writeln!(f, "equ {name} {value}")?;
+        }
+        for (index, position) in self.positions.iter().enumerate() {
+            if let Some(SourcePosition { file, line, col }) = position {
+                writeln!(f, "at {} {file} {line} {col}", format_address(index))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Formats an index into program memory as `page:offset`.
+fn format_address(index: usize) -> String {
+    format!("{}:{:02}", index / PAGE_SIZE, index % PAGE_SIZE)
+}
+
+fn parse_address(address: &str) -> Option<usize> {
+    let (page, offset) = address.split_once(':')?;
+    let (page, offset): (usize, usize) = (page.parse().ok()?, offset.parse().ok()?);
+    let index = page * PAGE_SIZE + offset;
+    (offset < PAGE_SIZE && index < PROGRAM_MEMORY_SIZE).then_some(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example() -> DebugInfo {
+        let mut info = DebugInfo {
+            files: vec!["programs/hello_world.asm".to_owned()],
+            labels: vec![("start".to_owned(), 0), ("write_char".to_owned(), 57)],
+            constants: vec![("CHAR".to_owned(), -3)],
+            ..DebugInfo::default()
+        };
+        info.positions[60] = Some(SourcePosition { file: 0, line: 14, col: 5 });
+        info
+    }
+
+    #[test]
+    fn describes_addresses_by_label() {
+        let info = example();
+        assert_eq!(info.describe(60), "write_char+3 (hello_world.asm:14)");
+        assert_eq!(info.describe(0), "start");
+        assert_eq!(info.describe(70), "1:06");
+    }
+
+    #[test]
+    fn reads_back_what_it_writes() {
+        let info = example();
+        assert_eq!(DebugInfo::parse(&info.to_string()), Ok(info));
+        assert!(DebugInfo::parse("at 0:64 0 1 1\n").is_err());
+    }
+}
@@ -5,3 +5,4 @@ pub mod instruction;
 pub mod un;
 pub mod architecture;
 pub mod bit_array;
+pub mod debug_info;
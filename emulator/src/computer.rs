@@ -10,6 +10,7 @@ use crate::device::connectable::device_pin::DevicePin;
 use crate::device::connectable::device_port::DevicePort;
 use crate::device::Device;
 use common::architecture::*;
+use common::debug_info::DebugInfo;
 use common::instruction::{decode_instruction, Instruction};
 use common::un::U;
 use std::borrow::BorrowMut;
@@ -34,11 +35,23 @@ pub struct Computer {
 
     program_memory: ReadOnlyMemory<INSTRUCTION_BITS, PROGRAM_MEMORY_SIZE>,
     working_memory: ReadWriteMemory<WORKING_BITS, WORKING_MEMORY_SIZE>,
+
+    /// Used to show where in the program the computer is, if the assembler wrote it out.
+    debug_info: DebugInfo,
+    /// Whether to print every instruction as it is executed.
+    trace: bool,
+    /// The index into program memory of the instruction being executed.
+    current_address: usize,
 }
 
 impl Device for Computer {
     fn tick(&mut self, tick: u32) {
+        self.current_address = self.address();
         let inst_bits = self.fetch();
+        if self.trace {
+            let bits: u8 = inst_bits.into();
+            eprintln!("{}: {:08b}", self.debug_info.describe(self.current_address), bits);
+        }
         self.program_counter.increment();
         let inst = self.decode(inst_bits);
         self.execute(inst);
@@ -80,9 +93,26 @@ impl Computer {
             ],
             program_memory: ReadOnlyMemory::with_values(program),
             working_memory: ReadWriteMemory::new(),
+            debug_info: DebugInfo::default(),
+            trace: false,
+            current_address: 0,
         }
     }
 
+    pub fn with_debug_info(self, debug_info: DebugInfo) -> Self {
+        Computer { debug_info, ..self }
+    }
+
+    pub fn with_trace(self, trace: bool) -> Self {
+        Computer { trace, ..self }
+    }
+
+    fn address(&self) -> usize {
+        let pc: u8 = self.program_counter.load().into();
+        let pa: u8 = self.page_address.load().into();
+        pa as usize * PAGE_SIZE + pc as usize
+    }
+
     fn fetch(&self) -> U<INSTRUCTION_BITS> {
         let pc = self.program_counter.load();
         let pa = self.page_address.load();
@@ -178,7 +208,10 @@ impl Computer {
                     return;
                 }
 
-                if !self.subroutine_jump_flag {
+                if self.subroutine_jump_flag {
+                    // The program counter already points at the next instruction, which is where RET comes back to.
+                    self.subroutine_ret_addr = self.program_counter.load();
+                } else {
                     self.page_address.store(self.page_buffer);
                 }
                 self.program_counter.store(immediate)
@@ -189,7 +222,11 @@ impl Computer {
             Instruction::SSJ => self.subroutine_jump_flag = true,
             Instruction::RSJ => self.subroutine_jump_flag = false,
             Instruction::RET => self.program_counter.store(self.subroutine_ret_addr),
-            _ => panic!("Instruction not implemented."),
+            instruction => panic!(
+                "Instruction {:?} at {} not implemented.",
+                instruction,
+                self.debug_info.describe(self.current_address)
+            ),
         }
     }
 
@@ -222,3 +259,37 @@ impl Computer {
         x_shifted | y
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn computer(instructions: &[u8]) -> Computer {
+        let mut program = [0u8; PROGRAM_MEMORY_SIZE];
+        program[..instructions.len()].copy_from_slice(instructions);
+        Computer::with_program(program.map(|byte| byte.into()))
+    }
+
+    #[test]
+    fn subroutine_jump_returns_after_the_branch() {
+        // ssj, ssf, brn 5, nop, nop, ret
+        let mut computer = computer(&[0b00000001, 0b00000011, 0b10000101, 0, 0, 0b00001000]);
+        for tick in 0..3 {
+            computer.tick(tick);
+        }
+        assert_eq!(computer.address(), 5);
+
+        computer.tick(3);
+        assert_eq!(computer.address(), 3);
+    }
+
+    #[test]
+    fn plain_jump_uses_the_page_buffer() {
+        // lpb 2, ssf, brn 5
+        let mut computer = computer(&[0b00010010, 0b00000011, 0b10000101]);
+        for tick in 0..3 {
+            computer.tick(tick);
+        }
+        assert_eq!(computer.address(), 2 * PAGE_SIZE + 5);
+    }
+}
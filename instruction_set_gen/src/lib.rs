@@ -18,11 +18,15 @@ struct InstrDef<'a> {
     fields: Vec<Field<'a>>,
 }
 
+fn read_defs() -> String {
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    fs::read_to_string(manifest_dir.join("instructions.txt"))
+        .expect("instruction definitions missing")
+}
+
 #[proc_macro]
 pub fn make_instructions(_item: TokenStream) -> TokenStream {
-    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
-    let defs = fs::read_to_string(manifest_dir.join("instructions.txt"))
-        .expect("instruction definitions missing");
+    let defs = read_defs();
     let parsed = parse_defs(defs.as_str());
     let enum_def = gen_enum(&parsed);
     let encode = gen_encode(&parsed);
@@ -32,6 +36,169 @@ pub fn make_instructions(_item: TokenStream) -> TokenStream {
     return output.parse().unwrap();
 }
 
+/// Generates the assembler's `InstructionKind` enum, with the lowercase mnemonic of each instruction, its bit pattern,
+/// the fields it takes and a way to build the matching `Instruction` from their values. `Instruction` must be in scope.
+#[proc_macro]
+pub fn make_mnemonics(_item: TokenStream) -> TokenStream {
+    let defs = read_defs();
+    let parsed = parse_defs(defs.as_str());
+    let enum_def = gen_kind_enum(&parsed);
+    let all = gen_all(&parsed);
+    let from_str = gen_from_str(&parsed);
+    let mnemonic = gen_mnemonic(&parsed);
+    let pattern = gen_pattern(&parsed);
+    let fields = gen_fields(&parsed);
+    let build = gen_build(&parsed);
+    let split = gen_split(&parsed);
+
+    let output = format!(
+        "{}\nimpl InstructionKind {{\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n}}",
+        enum_def, all, from_str, mnemonic, pattern, fields, build, split
+    );
+    output.parse().unwrap()
+}
+
+fn gen_kind_enum(parsed: &[InstrDef]) -> String {
+    let mut enum_str = "#[allow(clippy::upper_case_acronyms)]".to_string();
+    enum_str.push_str("#[derive(Eq, PartialEq, Copy, Clone, Debug)] pub enum InstructionKind {");
+    for def in parsed {
+        enum_str.push_str(def.name);
+        enum_str.push(',');
+    }
+    enum_str.push('}');
+    enum_str
+}
+
+fn gen_all(parsed: &[InstrDef]) -> String {
+    let mut all_str = "pub const ALL: &'static [InstructionKind] = &[".to_string();
+    for def in parsed {
+        all_str.push_str("InstructionKind::");
+        all_str.push_str(def.name);
+        all_str.push(',');
+    }
+    all_str.push_str("];");
+    all_str
+}
+
+fn gen_mnemonic(parsed: &[InstrDef]) -> String {
+    let mut mnemonic_str = "pub fn mnemonic(self) -> &'static str {".to_string();
+    mnemonic_str.push_str("match self {");
+    for def in parsed {
+        mnemonic_str.push_str("InstructionKind::");
+        mnemonic_str.push_str(def.name);
+        mnemonic_str.push_str(" => \"");
+        mnemonic_str.push_str(def.name.to_lowercase().as_str());
+        mnemonic_str.push_str("\",");
+    }
+    mnemonic_str.push('}');
+    mnemonic_str.push('}');
+    mnemonic_str
+}
+
+fn gen_pattern(parsed: &[InstrDef]) -> String {
+    let mut pattern_str =
+        "/// The bits of the instruction, with a letter for each bit of a field, like `11rrxxxx`.\n".to_string();
+    pattern_str.push_str("pub fn pattern(self) -> &'static str {");
+    pattern_str.push_str("match self {");
+    for def in parsed {
+        pattern_str.push_str(format!("InstructionKind::{} => {:?},", def.name, def.pattern).as_str());
+    }
+    pattern_str.push('}');
+    pattern_str.push('}');
+    pattern_str
+}
+
+fn gen_from_str(parsed: &[InstrDef]) -> String {
+    let mut from_str = "pub fn from_str(s: &str) -> Option<Self> {".to_string();
+    from_str.push_str("match s {");
+    for def in parsed {
+        from_str.push('"');
+        from_str.push_str(def.name.to_lowercase().as_str());
+        from_str.push_str("\" => Some(InstructionKind::");
+        from_str.push_str(def.name);
+        from_str.push_str("),");
+    }
+    from_str.push_str("_ => None,");
+    from_str.push('}');
+    from_str.push('}');
+    from_str
+}
+
+fn gen_fields(parsed: &[InstrDef]) -> String {
+    let mut fields_str =
+        "/// The name and width in bits of each field of the instruction, in the order they are defined in.\n".to_string();
+    fields_str.push_str("pub fn fields(self) -> &'static [(&'static str, usize)] {");
+    fields_str.push_str("match self {");
+    for def in parsed {
+        fields_str.push_str("InstructionKind::");
+        fields_str.push_str(def.name);
+        fields_str.push_str(" => &[");
+        for field in &def.fields {
+            fields_str.push_str(format!("(\"{}\", {}),", field.name, field.bits).as_str());
+        }
+        fields_str.push_str("],");
+    }
+    fields_str.push('}');
+    fields_str.push('}');
+    fields_str
+}
+
+fn gen_build(parsed: &[InstrDef]) -> String {
+    let mut build_str =
+        "/// Builds the instruction from the value of each field, in the order they are defined in.\n".to_string();
+    build_str.push_str("pub fn instruction(self, fields: &[u8]) -> Instruction {");
+    build_str.push_str("match self {");
+    for def in parsed {
+        build_str.push_str("InstructionKind::");
+        build_str.push_str(def.name);
+        build_str.push_str(" => Instruction::");
+        build_str.push_str(def.name);
+
+        if !def.fields.is_empty() {
+            build_str.push_str(" { ");
+            for (i, field) in def.fields.iter().enumerate() {
+                build_str.push_str(format!("{}: fields[{}].into(),", field.name, i).as_str());
+            }
+            build_str.push('}');
+        }
+        build_str.push(',');
+    }
+    build_str.push('}');
+    build_str.push('}');
+    build_str
+}
+
+fn gen_split(parsed: &[InstrDef]) -> String {
+    let mut split_str =
+        "/// Splits an instruction into its kind and the value of each field, in the order they are defined in.\n"
+            .to_string();
+    split_str.push_str("pub fn split(instruction: &Instruction) -> (InstructionKind, Vec<u8>) {");
+    split_str.push_str("match instruction {");
+    for def in parsed {
+        split_str.push_str("Instruction::");
+        split_str.push_str(def.name);
+
+        if !def.fields.is_empty() {
+            split_str.push_str(" { ");
+            for field in &def.fields {
+                split_str.push_str(field.name);
+                split_str.push(',');
+            }
+            split_str.push('}');
+        }
+        split_str.push_str(" => (InstructionKind::");
+        split_str.push_str(def.name);
+        split_str.push_str(", vec![");
+        for field in &def.fields {
+            split_str.push_str(format!("(*{}).into(),", field.name).as_str());
+        }
+        split_str.push_str("]),");
+    }
+    split_str.push('}');
+    split_str.push('}');
+    split_str
+}
+
 fn gen_enum(parsed: &Vec<InstrDef>) -> String {
     let mut enum_str = "#[derive(PartialEq, Debug)] pub enum Instruction {".to_string();
     for def in parsed {
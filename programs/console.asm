@@ -0,0 +1,22 @@
+; Console output, shared between programs.
+
+; Writes the character in x (high nibble) and y (low nibble) to the console.
+    .sub    write_char
+    mov     z x
+    out     0
+    mov     z y
+    out     1
+    sep     0                           ; in the simulation delay doesn't really matter, but in real life we would want something here
+    rsp     0
+    ret
+    .endsub
+
+; .print writes its text with write_char.
+    .printer write_char
+
+; Writes a character to the console. Must be used with the subroutine jump flag set.
+    .macro  put_char char
+    ldi     x hi(char)
+    ldi     y lo(char)
+    brn     write_char
+    .endm